@@ -0,0 +1,11 @@
+CREATE TABLE mail_queue (
+  uuid            TEXT     NOT NULL PRIMARY KEY,
+  created_at      DATETIME NOT NULL,
+  recipient       TEXT     NOT NULL,
+  subject         TEXT     NOT NULL,
+  body            TEXT     NOT NULL,
+  html            BOOLEAN  NOT NULL,
+  attempts        INTEGER  NOT NULL,
+  next_attempt_at DATETIME NOT NULL,
+  last_error      TEXT
+);
@@ -0,0 +1,10 @@
+ALTER TABLE users
+    ADD COLUMN
+    verified_at DATETIME;
+
+ALTER TABLE users
+    ADD COLUMN
+    last_verifying_at DATETIME;
+
+-- Existing accounts are considered verified
+UPDATE users SET verified_at = created_at;
@@ -0,0 +1,14 @@
+ALTER TABLE devices
+    ADD COLUMN
+    refresh_token_issued_at DATETIME;
+
+ALTER TABLE devices
+    ADD COLUMN
+    refresh_token_used_at DATETIME;
+
+ALTER TABLE devices
+    ADD COLUMN
+    previous_refresh_token TEXT;
+
+-- Existing sessions start counting from their last update
+UPDATE devices SET refresh_token_issued_at = updated_at, refresh_token_used_at = updated_at;
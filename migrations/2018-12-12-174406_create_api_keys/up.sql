@@ -0,0 +1,10 @@
+CREATE TABLE api_keys (
+  uuid          TEXT     NOT NULL PRIMARY KEY,
+  created_at    DATETIME NOT NULL,
+  updated_at    DATETIME NOT NULL,
+  user_uuid     TEXT     REFERENCES users (uuid),
+  org_uuid      TEXT     REFERENCES organizations (uuid),
+  client_id     TEXT     NOT NULL UNIQUE,
+  secret_hash   BLOB     NOT NULL,
+  secret_salt   BLOB     NOT NULL
+);
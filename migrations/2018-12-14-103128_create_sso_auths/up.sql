@@ -0,0 +1,11 @@
+CREATE TABLE sso_auths (
+  state          TEXT     NOT NULL PRIMARY KEY,
+  created_at     DATETIME NOT NULL,
+  nonce          TEXT     NOT NULL,
+  verifier       TEXT     NOT NULL,
+  redirect_uri   TEXT     NOT NULL,
+  client_state   TEXT,
+  code_challenge TEXT     NOT NULL,
+  auth_code      TEXT     UNIQUE,
+  user_uuid      TEXT     REFERENCES users (uuid)
+);
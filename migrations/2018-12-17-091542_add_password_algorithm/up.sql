@@ -0,0 +1,4 @@
+-- 0 = PBKDF2-SHA256, 1 = Argon2id
+ALTER TABLE users
+    ADD COLUMN
+    password_algorithm INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,8 @@
+-- Only used by the Argon2id client KDF, memory is in MiB
+ALTER TABLE users
+    ADD COLUMN
+    client_kdf_memory INTEGER;
+
+ALTER TABLE users
+    ADD COLUMN
+    client_kdf_parallelism INTEGER;
@@ -0,0 +1,9 @@
+CREATE TABLE invitations_old (
+  email TEXT NOT NULL PRIMARY KEY
+);
+
+INSERT INTO invitations_old (email) SELECT email FROM invitations;
+
+DROP TABLE invitations;
+
+ALTER TABLE invitations_old RENAME TO invitations;
@@ -0,0 +1,15 @@
+CREATE TABLE invitations_new (
+  email           TEXT     NOT NULL PRIMARY KEY,
+  org_uuid        TEXT     REFERENCES organizations (uuid),
+  invited_by_uuid TEXT     REFERENCES users (uuid),
+  created_at      DATETIME NOT NULL,
+  expires_at      DATETIME NOT NULL
+);
+
+-- The existing invitations get the default expiration of 5 days from now
+INSERT INTO invitations_new (email, created_at, expires_at)
+SELECT email, datetime('now'), datetime('now', '+5 days') FROM invitations;
+
+DROP TABLE invitations;
+
+ALTER TABLE invitations_new RENAME TO invitations;
@@ -0,0 +1,4 @@
+-- Ciphers in the trash, they are purged after TRASH_AUTO_DELETE_DAYS
+ALTER TABLE ciphers
+    ADD COLUMN
+    deleted_at DATETIME;
@@ -0,0 +1,16 @@
+CREATE TABLE cipher_revisions (
+  uuid          TEXT     NOT NULL PRIMARY KEY,
+  cipher_uuid   TEXT     NOT NULL REFERENCES ciphers (uuid),
+  author_uuid   TEXT,
+  revision_date DATETIME NOT NULL,
+  created_at    DATETIME NOT NULL,
+  name          TEXT     NOT NULL,
+  notes         TEXT,
+  fields        TEXT,
+  data          TEXT     NOT NULL
+);
+
+-- When NULL, the organization uses REVISION_RETENTION_DAYS
+ALTER TABLE organizations
+    ADD COLUMN
+    revision_retention_days INTEGER;
@@ -0,0 +1,11 @@
+ALTER TABLE organizations
+    ADD COLUMN
+    private_key TEXT;
+
+ALTER TABLE organizations
+    ADD COLUMN
+    public_key TEXT;
+
+ALTER TABLE users_organizations
+    ADD COLUMN
+    reset_password_key TEXT;
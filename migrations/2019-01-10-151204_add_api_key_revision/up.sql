@@ -0,0 +1,3 @@
+ALTER TABLE api_keys
+    ADD COLUMN
+    revision INTEGER NOT NULL DEFAULT 0;
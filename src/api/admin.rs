@@ -0,0 +1,67 @@
+use rocket::Route;
+use rocket_contrib::json::Json;
+
+use serde_json::Value;
+
+use crate::api::JsonResult;
+use crate::auth::Headers;
+use crate::db::models::User;
+use crate::db::DbConn;
+use crate::CONFIG;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_config,
+        reload_config,
+        weak_kdf_report,
+    ]
+}
+
+#[get("/config")]
+fn get_config(headers: Headers) -> JsonResult {
+    if !headers.user.is_server_admin() {
+        err!("Only the server admin can view the configuration")
+    }
+
+    Ok(Json(CONFIG.runtime_json()))
+}
+
+#[post("/config/reload")]
+fn reload_config(headers: Headers) -> JsonResult {
+    if !headers.user.is_server_admin() {
+        err!("Only the server admin can reload the configuration")
+    }
+
+    if let Err(errors) = CONFIG.reload() {
+        err!(format!("The configuration was not reloaded: {}", errors.join("; ")))
+    }
+
+    info!("Configuration reloaded by {}", headers.user.email);
+    Ok(Json(CONFIG.runtime_json()))
+}
+
+/// Lists the accounts whose client KDF iterations are below CLIENT_KDF_MIN_ITERATIONS
+#[get("/reports/weak-kdf")]
+fn weak_kdf_report(headers: Headers, conn: DbConn) -> JsonResult {
+    if !headers.user.is_server_admin() {
+        err!("Only the server admin can view the reports")
+    }
+
+    let users: Vec<Value> = User::find_weak_client_kdf(&conn)
+        .iter()
+        .map(|user| {
+            json!({
+                "Id": user.uuid,
+                "Email": user.email,
+                "Kdf": user.client_kdf_type,
+                "KdfIterations": user.client_kdf_iter,
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "MinIterations": CONFIG.client_kdf_min_iterations,
+        "Data": users,
+        "Object": "list",
+    })))
+}
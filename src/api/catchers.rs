@@ -0,0 +1,84 @@
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::Catcher;
+use rocket_contrib::json::Json;
+
+use crate::auth::{self, AuthError};
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![bad_request, unauthorized, forbidden]
+}
+
+#[catch(400)]
+fn bad_request(req: &Request) -> ErrorResponse {
+    ErrorResponse::new(Status::BadRequest, req)
+}
+
+#[catch(401)]
+fn unauthorized(req: &Request) -> ErrorResponse {
+    ErrorResponse::new(Status::Unauthorized, req)
+}
+
+#[catch(403)]
+fn forbidden(req: &Request) -> ErrorResponse {
+    ErrorResponse::new(Status::Forbidden, req)
+}
+
+/// Error response of a failed request guard, with the WWW-Authenticate header when the authentication failed
+struct ErrorResponse {
+    status: Status,
+    error: Option<AuthError>,
+    role_error: Option<&'static str>,
+}
+
+impl ErrorResponse {
+    fn new(status: Status, req: &Request) -> Self {
+        let error = match auth::request_auth_error(req) {
+            Some(error) if error.status() == status => Some(error),
+            // Without a token, the client is told which authentication scheme is expected
+            _ if status == Status::Unauthorized && req.headers().get_one("Authorization").is_none() => {
+                Some(AuthError::Missing)
+            }
+            _ => None,
+        };
+
+        let role_error = if status == Status::Forbidden && error.is_none() {
+            auth::request_role_error(req)
+        } else {
+            None
+        };
+
+        ErrorResponse { status, error, role_error }
+    }
+}
+
+impl<'r> Responder<'r> for ErrorResponse {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let message = match (self.error, self.role_error) {
+            (Some(error), _) => error.description(),
+            (None, Some(role_error)) => role_error,
+            (None, None) => self.status.reason,
+        };
+
+        let mut res = Json(json!({
+            "Message": message,
+            "error": "",
+            "error_description": message,
+            "ValidationErrors": {"": [ message ]},
+            "ErrorModel": {
+                "Message": message,
+                "Object": "error"
+            },
+            "Object": "error"
+        }))
+        .respond_to(req)?;
+
+        res.set_status(self.status);
+        if let Some(error) = self.error {
+            res.set_raw_header("WWW-Authenticate", error.www_authenticate());
+        }
+
+        Ok(res)
+    }
+}
@@ -3,14 +3,19 @@ use rocket_contrib::json::Json;
 use crate::db::models::*;
 use crate::db::DbConn;
 
-use crate::api::{EmptyResult, JsonResult, JsonUpcase, NumberOrString, PasswordData, UpdateType, WebSocketUsers};
-use crate::auth::{Headers, decode_invite_jwt, InviteJWTClaims};
+use crate::api::{EmptyResult, JsonResult, JsonUpcase, NumberOrString, PasswordData, WebSocketUsers, notify_account_change};
+use crate::auth::{Headers, encode_jwt, decode_invite_jwt, decode_verify_email_jwt, InviteJWTClaims, VerifyEmailJWTClaims, JWT_VERIFYEMAIL_ISSUER};
 use crate::mail;
 
 use crate::CONFIG;
 
 use rocket::{Route, State};
 
+use diesel::Connection;
+use std::collections::HashSet;
+
+use chrono::{Duration, Utc};
+
 pub fn routes() -> Vec<Route> {
     routes![
         register,
@@ -30,6 +35,10 @@ pub fn routes() -> Vec<Route> {
         revision_date,
         password_hint,
         prelogin,
+        post_verify_email,
+        post_verify_email_token,
+        api_key,
+        rotate_api_key,
     ]
 }
 
@@ -39,6 +48,8 @@ struct RegisterData {
     Email: String,
     Kdf: Option<i32>,
     KdfIterations: Option<i32>,
+    KdfMemory: Option<i32>,
+    KdfParallelism: Option<i32>,
     Key: String,
     Keys: Option<KeysData>,
     MasterPasswordHash: String,
@@ -59,10 +70,13 @@ struct KeysData {
 fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
     let data: RegisterData = data.into_inner().data;
 
+    // Only users registering without an invitation need to verify their email
+    let mut verify_email = false;
+
     let mut user = match User::find_by_mail(&data.Email, &conn) {
         Some(user) => {
             if Invitation::find_by_mail(&data.Email, &conn).is_some() {
-                if CONFIG.mail.is_none() {
+                if !CONFIG.mail_enabled() {
                     for mut user_org in UserOrganization::find_invited_by_user(&user.uuid, &conn).iter_mut() {
                         user_org.status = UserOrgStatus::Accepted as i32;
                         if user_org.save(&conn).is_err() {
@@ -88,14 +102,19 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
                         err!("Registration email does not match invite email")
                     }
                 }
-            } else if CONFIG.signups_allowed {
+            } else if user.password_hash.is_empty() {
+                err!("The invitation has expired, ask for a new one")
+            } else if CONFIG.signups_allowed() {
                     err!("Account with this email already exists")
             } else {
                 err!("Registration not allowed")
             }
         }
         None => {
-            if CONFIG.signups_allowed || (CONFIG.mail.is_none() && Invitation::take(&data.Email, &conn)) {
+            if CONFIG.is_signup_allowed(&data.Email) {
+                verify_email = CONFIG.signups_verify();
+                User::new(data.Email)
+            } else if !CONFIG.mail_enabled() && Invitation::take(&data.Email, &conn) {
                 User::new(data.Email)
             } else {
                 err!("Registration not allowed")
@@ -103,12 +122,13 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
         }
     };
 
-    if let Some(client_kdf_iter) = data.KdfIterations {
-        user.client_kdf_iter = client_kdf_iter;
+    if !verify_email {
+        user.verified_at = Some(Utc::now().naive_utc());
     }
 
-    if let Some(client_kdf_type) = data.Kdf {
-        user.client_kdf_type = client_kdf_type;
+    let client_kdf_type = data.Kdf.unwrap_or(User::CLIENT_KDF_TYPE_DEFAULT);
+    if let Err(e) = user.set_client_kdf(client_kdf_type, data.KdfIterations, data.KdfMemory, data.KdfParallelism) {
+        err!(e)
     }
 
     user.set_password(&data.MasterPasswordHash);
@@ -128,10 +148,109 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
         user.public_key = Some(keys.PublicKey);
     }
 
-    match user.save(&conn) {
-        Ok(()) => Ok(()),
-        Err(_) => err!("Failed to save user"),
+    if user.save(&conn).is_err() {
+        err!("Failed to save user")
+    }
+
+    if verify_email {
+        if let Err(e) = send_verification_email(&mut user, &conn) {
+            error!("Error sending verification email: {}", e);
+        }
     }
+
+    Ok(())
+}
+
+/// Sends the link to verify the email of a new account, and records when it was sent
+pub fn send_verification_email(user: &mut User, conn: &DbConn) -> Result<(), String> {
+    let time_now = Utc::now().naive_utc();
+    let claims = VerifyEmailJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::days(5)).timestamp(),
+        iss: JWT_VERIFYEMAIL_ISSUER.to_string(),
+        sub: user.uuid.clone(),
+        email: user.email.clone(),
+    };
+
+    mail::send_verify_email(&user.email, &user.uuid, &encode_jwt(&claims), conn)?;
+
+    user.last_verifying_at = Some(time_now);
+    user.save(conn).map_err(|e| format!("{:?}", e))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct VerifyEmailData {
+    Email: String,
+}
+
+/// Sends the verification link again. Unverified users can't log in, so the request isn't authenticated,
+/// and the response is the same whether the account exists or not
+#[post("/accounts/verify-email", data = "<data>")]
+fn post_verify_email(data: JsonUpcase<VerifyEmailData>, conn: DbConn) -> EmptyResult {
+    let data: VerifyEmailData = data.into_inner().data;
+
+    // Placeholder accounts of invited users are verified when the invitation is accepted
+    let mut user = match User::find_by_mail(&data.Email, &conn) {
+        Some(user) => {
+            if user.verified_at.is_some() || user.password_hash.is_empty() {
+                return Ok(());
+            }
+            user
+        }
+        None => return Ok(()),
+    };
+
+    // Anyone can call this, so a user receives at most one link every few minutes
+    if let Some(last) = user.last_verifying_at {
+        if Utc::now().naive_utc() - last < Duration::minutes(5) {
+            return Ok(());
+        }
+    }
+
+    if let Err(e) = send_verification_email(&mut user, &conn) {
+        error!("Error sending verification email: {}", e);
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct VerifyEmailTokenData {
+    UserId: String,
+    Token: String,
+}
+
+#[post("/accounts/verify-email-token", data = "<data>")]
+fn post_verify_email_token(data: JsonUpcase<VerifyEmailTokenData>, conn: DbConn) -> EmptyResult {
+    let data: VerifyEmailTokenData = data.into_inner().data;
+
+    let claims = match decode_verify_email_jwt(&data.Token) {
+        Ok(claims) => claims,
+        Err(_) => err!("Invalid or expired verification token"),
+    };
+
+    // The same error for an unknown user, so the endpoint doesn't tell which accounts exist
+    let mut user = match User::find_by_uuid(&data.UserId, &conn) {
+        Some(user) => user,
+        None => err!("Invalid verification token"),
+    };
+
+    if claims.sub != user.uuid || claims.email != user.email {
+        err!("Invalid verification token")
+    }
+
+    if user.verified_at.is_none() {
+        user.verified_at = Some(Utc::now().naive_utc());
+        user.last_verifying_at = None;
+
+        if user.save(&conn).is_err() {
+            err!("Failed to save user")
+        }
+    }
+
+    Ok(())
 }
 
 #[get("/accounts/profile")]
@@ -219,7 +338,10 @@ fn post_password(data: JsonUpcase<ChangePassData>, headers: Headers, conn: DbCon
     user.set_password(&data.NewMasterPasswordHash);
     user.key = data.Key;
     match user.save(&conn) {
-        Ok(()) => Ok(()),
+        Ok(()) => {
+            notify_account_change(&user.email, "Your master password was changed.", &conn);
+            Ok(())
+        }
         Err(_) => err!("Failed to save password"),
     }
 }
@@ -229,6 +351,8 @@ fn post_password(data: JsonUpcase<ChangePassData>, headers: Headers, conn: DbCon
 struct ChangeKdfData {
     Kdf: i32,
     KdfIterations: i32,
+    KdfMemory: Option<i32>,
+    KdfParallelism: Option<i32>,
 
     MasterPasswordHash: String,
     NewMasterPasswordHash: String,
@@ -244,12 +368,17 @@ fn post_kdf(data: JsonUpcase<ChangeKdfData>, headers: Headers, conn: DbConn) ->
         err!("Invalid password")
     }
 
-    user.client_kdf_iter = data.KdfIterations;
-    user.client_kdf_type = data.Kdf;
+    if let Err(e) = user.set_client_kdf(data.Kdf, Some(data.KdfIterations), data.KdfMemory, data.KdfParallelism) {
+        err!(e)
+    }
+
     user.set_password(&data.NewMasterPasswordHash);
     user.key = data.Key;
     match user.save(&conn) {
-        Ok(()) => Ok(()),
+        Ok(()) => {
+            notify_account_change(&user.email, "Your encryption key settings (KDF) were changed.", &conn);
+            Ok(())
+        }
         Err(_) => err!("Failed to save password settings"),
     }
 }
@@ -262,12 +391,15 @@ struct UpdateFolderData {
 }
 
 use super::ciphers::CipherData;
+use super::sends::SendData;
 
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct KeyData {
     Ciphers: Vec<CipherData>,
     Folders: Vec<UpdateFolderData>,
+    #[serde(default)]
+    Sends: Vec<SendData>,
     Key: String,
     PrivateKey: String,
     MasterPasswordHash: String,
@@ -281,49 +413,143 @@ fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, conn: DbConn, ws:
         err!("Invalid password")
     }
 
+    // Everything is re-encrypted in one go, a partial rotation would leave items that the new key can't decrypt
+    let mut rotation = Ok(());
+    let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+        rotation = _rotate_key(data, &headers, &conn, &ws);
+        if rotation.is_ok() {
+            Ok(())
+        } else {
+            Err(diesel::result::Error::RollbackTransaction)
+        }
+    });
+
+    rotation?;
+    if let Err(e) = result {
+        err!("Failed to rotate the key", e)
+    }
+
+    // The other devices still hold the old key, so they need to log in again
+    for device in Device::find_by_user(&headers.user.uuid, &conn) {
+        if device.uuid != headers.device.uuid {
+            ws.send_device_logout(&headers.user, &device.uuid);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the ids sent by the client are exactly the ids of the items owned by the user
+fn _check_complete_set(kind: &str, sent: &[Option<String>], owned: HashSet<String>) -> EmptyResult {
+    let mut seen = HashSet::new();
+    for id in sent {
+        match id {
+            Some(id) if !owned.contains(id) => err!(format!("The {} {} is not owned by the user", kind, id)),
+            Some(id) if !seen.insert(id.as_str()) => err!(format!("The {} {} was sent more than once", kind, id)),
+            Some(_) => (),
+            None => err!(format!("A {} without an id was sent", kind)),
+        }
+    }
+
+    if seen.len() != owned.len() {
+        err!(format!("All the {}s of the user must be re-encrypted, {} are missing", kind, owned.len() - seen.len()))
+    }
+
+    Ok(())
+}
+
+fn _rotate_key(data: KeyData, headers: &Headers, conn: &DbConn, ws: &State<WebSocketUsers>) -> EmptyResult {
     let user_uuid = &headers.user.uuid;
 
+    let folder_ids: Vec<_> = data.Folders.iter().map(|f| Some(f.Id.clone())).collect();
+    let owned_folders = Folder::find_by_user(user_uuid, conn).into_iter().map(|f| f.uuid).collect();
+    _check_complete_set("folder", &folder_ids, owned_folders)?;
+
+    let cipher_ids: Vec<_> = data.Ciphers.iter().map(|c| c.Id.clone()).collect();
+    let owned_ciphers = Cipher::find_owned_by_user(user_uuid, conn).into_iter().map(|c| c.uuid).collect();
+    _check_complete_set("cipher", &cipher_ids, owned_ciphers)?;
+
+    let send_ids: Vec<_> = data.Sends.iter().map(|s| s.Id.clone()).collect();
+    let owned_sends = Send::find_by_user(user_uuid, conn).into_iter().map(|s| s.uuid).collect();
+    _check_complete_set("send", &send_ids, owned_sends)?;
+
     // Update folder data
     for folder_data in data.Folders {
-        let mut saved_folder = match Folder::find_by_uuid(&folder_data.Id, &conn) {
+        let mut saved_folder = match Folder::find_by_uuid(&folder_data.Id, conn) {
             Some(folder) => folder,
             None => err!("Folder doesn't exist"),
         };
 
-        if &saved_folder.user_uuid != user_uuid {
-            err!("The folder is not owned by the user")
-        }
-
         saved_folder.name = folder_data.Name;
-        if saved_folder.save(&conn).is_err() {
+        if saved_folder.save(conn).is_err() {
             err!("Failed to save folder")
         }
     }
 
-    // Update cipher data
+    // Update cipher data, the other devices are logged out instead of notified of each change
     use super::ciphers::update_cipher_from_data;
 
     for cipher_data in data.Ciphers {
-        let mut saved_cipher = match Cipher::find_by_uuid(cipher_data.Id.as_ref().unwrap(), &conn) {
+        let mut saved_cipher = match cipher_data.Id.as_ref().and_then(|id| Cipher::find_by_uuid(id, conn)) {
             Some(cipher) => cipher,
             None => err!("Cipher doesn't exist"),
         };
 
-        if saved_cipher.user_uuid.as_ref().unwrap() != user_uuid {
-            err!("The cipher is not owned by the user")
+        // The previous versions are encrypted with the old key
+        if CipherRevision::delete_all_by_cipher(&saved_cipher.uuid, conn).is_err() {
+            err!("Failed to remove the cipher history")
         }
 
-        update_cipher_from_data(&mut saved_cipher, cipher_data, &headers, false, &conn, &ws, UpdateType::SyncCipherUpdate)?
+        update_cipher_from_data(&mut saved_cipher, cipher_data, headers, false, conn, ws, None)?
+    }
+
+    // Update send data, the send keys are encrypted with the user key
+    use super::sends::update_send_from_data;
+
+    for send_data in data.Sends {
+        let mut saved_send = match send_data.Id.as_ref().and_then(|id| Send::find_by_uuid(id, conn)) {
+            Some(send) => send,
+            None => err!("Send doesn't exist"),
+        };
+
+        update_send_from_data(&mut saved_send, send_data)?;
+        if saved_send.save(conn).is_err() {
+            err!("Failed to save send")
+        }
     }
 
     // Update user data
-    let mut user = headers.user;
+    let mut user = match User::find_by_uuid(user_uuid, conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    // The account recovery keys are encrypted with the old key, so the user has to enroll again
+    for mut user_org in UserOrganization::find_any_state_by_user(user_uuid, conn) {
+        if user_org.reset_password_key.is_some() {
+            user_org.reset_password_key = None;
+            if user_org.save(conn).is_err() {
+                err!("Failed to remove the account recovery enrollment")
+            }
+        }
+    }
+
+    // The emergency contacts hold the old key as well, the grantor has to confirm them again
+    for mut emer in EmergencyAccess::find_by_grantor(user_uuid, conn) {
+        if emer.key_encrypted.is_some() {
+            emer.key_encrypted = None;
+            emer.status = EmergencyAccessStatus::Accepted as i32;
+            if emer.save(conn).is_err() {
+                err!("Failed to reset the emergency access")
+            }
+        }
+    }
 
     user.key = data.Key;
     user.private_key = Some(data.PrivateKey);
     user.reset_security_stamp();
 
-    if user.save(&conn).is_err() {
+    if user.save(conn).is_err() {
         err!("Failed modify user key");
     }
 
@@ -346,6 +572,39 @@ fn post_sstamp(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -
     }
 }
 
+#[post("/accounts/api-key", data = "<data>")]
+fn api_key(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _api_key(data, false, headers, conn)
+}
+
+#[post("/accounts/rotate-api-key", data = "<data>")]
+fn rotate_api_key(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _api_key(data, true, headers, conn)
+}
+
+fn _api_key(data: JsonUpcase<PasswordData>, rotate: bool, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    // The secret is stored hashed, so it can only be shown when it's generated
+    let (mut api_key, rotate) = match ApiKey::find_by_user(&user.uuid, &conn) {
+        Some(api_key) => (api_key, rotate),
+        None => (ApiKey::new_for_user(user.uuid.clone()), true),
+    };
+
+    let secret = if rotate { Some(api_key.rotate_secret()) } else { None };
+
+    if api_key.save(&conn).is_err() {
+        err!("Failed to save API key")
+    }
+
+    Ok(Json(api_key.to_json(secret.as_ref().map(String::as_str))))
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct EmailTokenData {
@@ -393,13 +652,18 @@ fn post_email(data: JsonUpcase<ChangeEmailData>, headers: Headers, conn: DbConn)
         err!("Email already in use");
     }
 
+    let old_email = user.email.clone();
     user.email = data.NewEmail;
 
     user.set_password(&data.NewMasterPasswordHash);
     user.key = data.Key;
 
     match user.save(&conn) {
-        Ok(()) => Ok(()),
+        Ok(()) => {
+            // Notify the previous address, in case the account was taken over
+            notify_account_change(&old_email, &format!("Your email address was changed to {}.", user.email), &conn);
+            Ok(())
+        }
         Err(_) => err!("Failed to save email address"),
     }
 }
@@ -445,11 +709,11 @@ fn password_hint(data: JsonUpcase<PasswordHintData>, conn: DbConn) -> EmptyResul
         None => return Ok(()),
     };
 
-    if let Some(ref mail_config) = CONFIG.mail {
-        if let Err(e) = mail::send_password_hint(&data.Email, hint, mail_config) {
+    if CONFIG.mail_enabled() {
+        if let Err(e) = mail::send_password_hint(&data.Email, hint, &conn) {
             err!(format!("There have been a problem sending the email: {}", e));
         }
-    } else if CONFIG.show_password_hint {
+    } else if CONFIG.show_password_hint() {
         if let Some(hint) = hint {
             err!(format!("Your password hint is: {}", &hint));
         } else {
@@ -470,13 +734,15 @@ struct PreloginData {
 fn prelogin(data: JsonUpcase<PreloginData>, conn: DbConn) -> JsonResult {
     let data: PreloginData = data.into_inner().data;
 
-    let (kdf_type, kdf_iter) = match User::find_by_mail(&data.Email, &conn) {
-        Some(user) => (user.client_kdf_type, user.client_kdf_iter),
-        None => (User::CLIENT_KDF_TYPE_DEFAULT, User::CLIENT_KDF_ITER_DEFAULT),
+    let (kdf_type, kdf_iter, kdf_memory, kdf_parallelism) = match User::find_by_mail(&data.Email, &conn) {
+        Some(user) => (user.client_kdf_type, user.client_kdf_iter, user.client_kdf_memory, user.client_kdf_parallelism),
+        None => (User::CLIENT_KDF_TYPE_DEFAULT, User::CLIENT_KDF_ITER_DEFAULT, None, None),
     };
 
     Ok(Json(json!({
         "Kdf": kdf_type,
-        "KdfIterations": kdf_iter
+        "KdfIterations": kdf_iter,
+        "KdfMemory": kdf_memory,
+        "KdfParallelism": kdf_parallelism
     })))
 }
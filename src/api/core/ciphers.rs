@@ -1,10 +1,15 @@
-use std::collections::{HashSet, HashMap};
-use std::path::Path;
+use std::collections::{BTreeMap, HashSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::str;
 
 use rocket::http::ContentType;
+use rocket::response::status::BadRequest;
 use rocket::{request::Form, Data, Route, State};
 
 use rocket_contrib::json::Json;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 
 use multipart::server::save::SavedData;
@@ -12,10 +17,15 @@ use multipart::server::{Multipart, SaveResult};
 
 use data_encoding::HEXLOWER;
 
+use chrono::{Duration, Utc};
+
+use diesel::Connection;
+
 use crate::db::models::*;
-use crate::db::DbConn;
+use crate::db::{DbConn, Pool};
 
 use crate::crypto;
+use crate::util::UpCase;
 
 use crate::api::{self, EmptyResult, JsonResult, JsonUpcase, PasswordData, UpdateType, WebSocketUsers};
 use crate::auth::Headers;
@@ -34,6 +44,7 @@ pub fn routes() -> Vec<Route> {
         post_ciphers_admin,
         post_ciphers_create,
         post_ciphers_import,
+        post_ciphers_import_stream,
         post_attachment,
         post_attachment_admin,
         post_attachment_share,
@@ -53,6 +64,16 @@ pub fn routes() -> Vec<Route> {
         delete_cipher_admin,
         delete_cipher_selected,
         delete_cipher_selected_post,
+        delete_cipher_put,
+        delete_cipher_put_admin,
+        delete_cipher_selected_put,
+        delete_cipher_selected_put_admin,
+        restore_cipher_put,
+        restore_cipher_put_admin,
+        restore_cipher_selected,
+        restore_cipher_selected_admin,
+        get_cipher_revisions,
+        restore_cipher_revision,
         delete_all,
         move_cipher_selected,
         move_cipher_selected_put,
@@ -82,6 +103,9 @@ fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> JsonResult {
     let ciphers = Cipher::find_by_user(&headers.user.uuid, &conn);
     let ciphers_json: Vec<Value> = ciphers.iter().map(|c| c.to_json(&headers.host, &headers.user.uuid, &conn)).collect();
 
+    let sends = Send::find_by_user(&headers.user.uuid, &conn);
+    let sends_json: Vec<Value> = sends.iter().map(Send::to_json).collect();
+
     let domains_json = if data.exclude_domains { Value::Null } else { api::core::get_eq_domains(headers).unwrap().into_inner() };
 
     Ok(Json(json!({
@@ -89,6 +113,7 @@ fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> JsonResult {
         "Folders": folders_json,
         "Collections": collections_json,
         "Ciphers": ciphers_json,
+        "Sends": sends_json,
         "Domains": domains_json,
         "Object": "sync"
     })))
@@ -200,12 +225,14 @@ fn post_ciphers(data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn, ws
     let data: CipherData = data.into_inner().data;
 
     let mut cipher = Cipher::new(data.Type, data.Name.clone());
-    update_cipher_from_data(&mut cipher, data, &headers, false, &conn, &ws, UpdateType::SyncCipherCreate)?;
+    update_cipher_from_data(&mut cipher, data, &headers, false, &conn, &ws, Some(UpdateType::SyncCipherCreate))?;
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
 }
 
-pub fn update_cipher_from_data(cipher: &mut Cipher, data: CipherData, headers: &Headers, shared_to_collection: bool, conn: &DbConn, ws: &State<WebSocketUsers>, ut: UpdateType) -> EmptyResult {
+pub fn update_cipher_from_data(cipher: &mut Cipher, data: CipherData, headers: &Headers, shared_to_collection: bool, conn: &DbConn, ws: &State<WebSocketUsers>, ut: Option<UpdateType>) -> EmptyResult {
+    let previous_org_uuid = cipher.organization_uuid.clone();
+
     if let Some(org_id) = data.OrganizationId {
         match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, &conn) {
             None => err!("You don't have permission to add item to organization"),
@@ -277,6 +304,17 @@ pub fn update_cipher_from_data(cipher: &mut Cipher, data: CipherData, headers: &
     type_data["PasswordHistory"] = data.PasswordHistory.clone().unwrap_or(Value::Null);
     // TODO: ******* Backwards compat end **********
 
+    // Keep the previous version, unless it's encrypted with a key the new owner doesn't have
+    if cipher.organization_uuid != previous_org_uuid {
+        if CipherRevision::delete_all_by_cipher(&cipher.uuid, &conn).is_err() {
+            err!("Failed to remove the cipher history")
+        }
+    } else if let Some(UpdateType::SyncCipherUpdate) = ut {
+        if CipherRevision::archive(cipher, &headers.user.uuid, &conn).is_err() {
+            err!("Failed to save the cipher history")
+        }
+    }
+
     cipher.favorite = data.Favorite.unwrap_or(false);
     cipher.name = data.Name;
     cipher.notes = data.Notes;
@@ -288,7 +326,10 @@ pub fn update_cipher_from_data(cipher: &mut Cipher, data: CipherData, headers: &
         Ok(()) => (),
         Err(_) => err!("Failed to save cipher")
     };
-    ws.send_cipher_update(ut, &cipher, &cipher.update_users_revision(&conn));
+    let user_uuids = cipher.update_users_revision(&conn);
+    if let Some(ut) = ut {
+        ws.send_cipher_update(ut, &cipher, &user_uuids);
+    }
 
     if cipher.move_to_folder(data.FolderId, &headers.user.uuid, &conn).is_err() {
         err!("Error saving the folder information")
@@ -317,47 +358,316 @@ struct RelationsData {
 }
 
 
+/// Most errors listed in the response of a failed import, the others are only counted
+const IMPORT_ERRORS_LIMIT: usize = 100;
+
+/// Problems found with the items of an import, keyed by the position of the item in the request.
+/// They are reported together, so the user can fix the export file in one go
+#[derive(Default)]
+pub struct ImportErrors {
+    errors: BTreeMap<String, Vec<String>>,
+    count: usize,
+}
+
+impl ImportErrors {
+    pub fn add(&mut self, key: String, msg: String) {
+        self.count += 1;
+        if self.count <= IMPORT_ERRORS_LIMIT {
+            self.errors.entry(key).or_insert_with(Vec::new).push(msg);
+        }
+    }
+
+    /// Records the message of an error returned by the API helpers
+    pub fn add_api_error(&mut self, key: String, error: BadRequest<Json<Value>>) {
+        let msg = error.0
+            .and_then(|json| {
+                let value = json.into_inner();
+                value["ErrorModel"]["Message"].as_str().map(String::from)
+            })
+            .unwrap_or_else(|| "Unknown error".into());
+
+        self.add(key, msg);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn into_result(self) -> EmptyResult {
+        let msg = if self.count > IMPORT_ERRORS_LIMIT {
+            format!(
+                "The import failed and nothing was imported, check the errors of each item ({} errors, only the first {} are listed)",
+                self.count, IMPORT_ERRORS_LIMIT
+            )
+        } else {
+            "The import failed and nothing was imported, check the errors of each item".to_string()
+        };
+        error!("{}: {:#?}", msg, self.errors);
+
+        err_json!(json!({
+            "Message": msg,
+            "error": "",
+            "error_description": "",
+            "ValidationErrors": self.errors,
+            "ErrorModel": {
+                "Message": msg,
+                "Object": "error"
+            },
+            "Object": "error"
+        }))
+    }
+}
+
 #[post("/ciphers/import", data = "<data>")]
 fn post_ciphers_import(data: JsonUpcase<ImportData>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
     let data: ImportData = data.into_inner().data;
+    let mut errors = ImportErrors::default();
 
-    // Read and create the folders
-    let mut folders: Vec<_> = Vec::new();
-    for folder in data.Folders.into_iter() {
-        let mut new_folder = Folder::new(headers.user.uuid.clone(), folder.Name);
-        if new_folder.save(&conn).is_err() {
-            err!("Failed importing folders")
-        } else {
+    // Check the relations between folders and ciphers before touching the database
+    let mut relations_map = HashMap::new();
+    for (index, relation) in data.FolderRelationships.iter().enumerate() {
+        let key = format!("FolderRelationships[{}]", index);
+        if relation.Key >= data.Ciphers.len() {
+            errors.add(key.clone(), format!("Cipher index {} is out of range", relation.Key));
+        }
+        if relation.Value >= data.Folders.len() {
+            errors.add(key.clone(), format!("Folder index {} is out of range", relation.Value));
+        }
+        if relations_map.insert(relation.Key, relation.Value).is_some() {
+            errors.add(key, format!("Cipher {} is already in another folder", relation.Key));
+        }
+    }
+
+    let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+        // Read and create the folders
+        let mut folders = Vec::new();
+        for (index, folder) in data.Folders.into_iter().enumerate() {
+            let mut new_folder = Folder::new(headers.user.uuid.clone(), folder.Name);
+            if let Err(e) = new_folder.save(&conn) {
+                errors.add(format!("Folders[{}]", index), format!("Failed to save folder: {}", e));
+            }
             folders.push(new_folder);
         }
+
+        // Read and create the ciphers
+        for (index, cipher_data) in data.Ciphers.into_iter().enumerate() {
+            let key = format!("Ciphers[{}]", index);
+            let folder_uuid = relations_map.get(&index)
+                .and_then(|i| folders.get(*i))
+                .map(|folder| folder.uuid.clone());
+
+            let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
+            if let Err(e) = update_cipher_from_data(&mut cipher, cipher_data, &headers, false, &conn, &ws, None) {
+                errors.add_api_error(key, e);
+                continue;
+            }
+
+            if let Err(e) = cipher.move_to_folder(folder_uuid, &headers.user.uuid, &conn) {
+                errors.add(key, e.to_string());
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(diesel::result::Error::RollbackTransaction)
+        }
+    });
+
+    if !errors.is_empty() {
+        return errors.into_result();
+    }
+    if let Err(e) = result {
+        err!("Failed to import the vault, nothing was imported", e)
+    }
+
+    let mut user = headers.user;
+    match user.update_revision(&conn) {
+        Ok(()) => {
+            ws.send_user_update(UpdateType::SyncVault, &user);
+            Ok(())
+        }
+        Err(_) => err!("Failed to update the revision, please log out and log back in to finish import.")
     }
+}
 
-    // Read the relations between folders and ciphers
-    let mut relations_map = HashMap::new();
 
-    for relation in data.FolderRelationships {
-        relations_map.insert(relation.Key, relation.Value);
+/// Longest line accepted by the streaming imports, one item with a long password history fits easily
+const IMPORT_LINE_LIMIT: u64 = 1024 * 1024;
+
+/// Body of a streaming import saved in TMP_FOLDER. The whole body is received before the import
+/// transaction starts, so a slow upload doesn't keep the database locked. The file is removed when dropped
+pub struct ImportSpool {
+    path: PathBuf,
+}
+
+impl ImportSpool {
+    pub fn new(data: Data) -> Result<Self, String> {
+        fs::create_dir_all(&CONFIG.tmp_folder).map_err(|e| format!("Error creating the temporary folder: {}", e))?;
+
+        let file_name = format!("import-{}", HEXLOWER.encode(&crypto::get_random(vec![0; 10])));
+        let spool = ImportSpool { path: Path::new(&CONFIG.tmp_folder).join(file_name) };
+
+        // One byte past the limit is read to tell a body of exactly the limit from a larger one
+        let limit = CONFIG.import_stream_limit;
+        let received = File::create(&spool.path)
+            .and_then(|mut file| io::copy(&mut data.open().take(limit.saturating_add(1)), &mut file))
+            .map_err(|e| format!("Error receiving the import: {}", e))?;
+
+        if received > limit {
+            return Err(format!("The import is larger than the limit of {} MiB", limit / (1024 * 1024)));
+        }
+        Ok(spool)
+    }
+
+    /// Reads the newline delimited JSON one line at a time, so the memory used doesn't grow with the size of the vault.
+    /// `process` receives the line number and the parsed item, or the reason the line couldn't be parsed
+    pub fn read_lines<T, F>(&self, process: F) -> Result<(), String>
+        where T: DeserializeOwned,
+              F: FnMut(usize, Result<T, String>)
+    {
+        let file = File::open(&self.path).map_err(|e| format!("Error reading the import: {}", e))?;
+        read_import_lines(file, process)
+    }
+}
+
+impl Drop for ImportSpool {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("Error removing the import file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn read_import_lines<R, T, F>(input: R, mut process: F) -> Result<(), String>
+    where R: Read,
+          T: DeserializeOwned,
+          F: FnMut(usize, Result<T, String>)
+{
+    let mut reader = BufReader::new(input);
+    let mut buffer = Vec::new();
+    let mut line_number = 0;
+
+    loop {
+        buffer.clear();
+        let read = (&mut reader)
+            .take(IMPORT_LINE_LIMIT + 1)
+            .read_until(b'\n', &mut buffer)
+            .map_err(|e| format!("Error reading the import: {}", e))?;
+
+        if read == 0 {
+            return Ok(());
+        }
+        line_number += 1;
+
+        if buffer.len() as u64 > IMPORT_LINE_LIMIT {
+            return Err(format!("Line {} is longer than {} bytes", line_number, IMPORT_LINE_LIMIT));
+        }
+
+        let line = match str::from_utf8(&buffer) {
+            Ok(line) => line.trim(),
+            Err(_) => {
+                process(line_number, Err("The line is not valid UTF-8".into()));
+                continue;
+            }
+        };
+        if line.is_empty() {
+            continue;
+        }
+
+        let item = serde_json::from_str::<UpCase<T>>(line)
+            .map(|item| item.data)
+            .map_err(|e| format!("Invalid item: {}", e));
+        process(line_number, item);
     }
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ImportStreamItem {
+    Folder: Option<FolderData>,
+    Cipher: Option<CipherData>,
+    // Position of the folder among the folders of the import
+    FolderIndex: Option<usize>,
+}
+
+#[post("/ciphers/import-stream", data = "<data>")]
+fn post_ciphers_import_stream(data: Data, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    let spool = match ImportSpool::new(data) {
+        Ok(spool) => spool,
+        Err(e) => err!(e),
+    };
+    let mut errors = ImportErrors::default();
+
+    let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+        // Only the ids of the folders are kept, the items are saved as soon as they are read
+        let mut folders: Vec<String> = Vec::new();
+
+        let read = spool.read_lines(|line, item: Result<ImportStreamItem, String>| {
+            let key = format!("Line {}", line);
+            let item = match item {
+                Ok(item) => item,
+                Err(e) => return errors.add(key, e),
+            };
+
+            match (item.Folder, item.Cipher) {
+                (Some(folder), None) => {
+                    let mut new_folder = Folder::new(headers.user.uuid.clone(), folder.Name);
+                    if let Err(e) = new_folder.save(&conn) {
+                        errors.add(key, format!("Failed to save folder: {}", e));
+                    }
+                    folders.push(new_folder.uuid);
+                }
+                (None, Some(cipher_data)) => {
+                    let folder_uuid = match item.FolderIndex {
+                        Some(index) => match folders.get(index) {
+                            Some(uuid) => Some(uuid.clone()),
+                            None => return errors.add(key, format!("Folder index {} is out of range, folders must come before their ciphers", index)),
+                        },
+                        None => None,
+                    };
+
+                    let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
+                    if let Err(e) = update_cipher_from_data(&mut cipher, cipher_data, &headers, false, &conn, &ws, None) {
+                        return errors.add_api_error(key, e);
+                    }
 
-    // Read and create the ciphers
-    for (index, cipher_data) in data.Ciphers.into_iter().enumerate() {
-        let folder_uuid = relations_map.get(&index)
-            .map(|i| folders[*i].uuid.clone());
+                    if let Err(e) = cipher.move_to_folder(folder_uuid, &headers.user.uuid, &conn) {
+                        errors.add(key, e.to_string());
+                    }
+                }
+                _ => errors.add(key, "Each line must contain either a Folder or a Cipher".into()),
+            }
+        });
 
-        let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
-        update_cipher_from_data(&mut cipher, cipher_data, &headers, false, &conn, &ws, UpdateType::SyncCipherCreate)?;
+        if let Err(e) = read {
+            errors.add("Body".into(), e);
+        }
 
-        cipher.move_to_folder(folder_uuid, &headers.user.uuid.clone(), &conn).ok();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(diesel::result::Error::RollbackTransaction)
+        }
+    });
+
+    if !errors.is_empty() {
+        return errors.into_result();
+    }
+    if let Err(e) = result {
+        err!("Failed to import the vault, nothing was imported", e)
     }
 
     let mut user = headers.user;
     match user.update_revision(&conn) {
-        Ok(()) => Ok(()),
+        Ok(()) => {
+            ws.send_user_update(UpdateType::SyncVault, &user);
+            Ok(())
+        }
         Err(_) => err!("Failed to update the revision, please log out and log back in to finish import.")
     }
 }
 
-
 #[put("/ciphers/<uuid>/admin", data = "<data>")]
 fn put_cipher_admin(uuid: String, data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> JsonResult {
     put_cipher(uuid, data, headers, conn, ws)
@@ -386,7 +696,7 @@ fn put_cipher(uuid: String, data: JsonUpcase<CipherData>, headers: Headers, conn
         err!("Cipher is not write accessible")
     }
 
-    update_cipher_from_data(&mut cipher, data, &headers, false, &conn, &ws, UpdateType::SyncCipherUpdate)?;
+    update_cipher_from_data(&mut cipher, data, &headers, false, &conn, &ws, Some(UpdateType::SyncCipherUpdate))?;
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
 }
@@ -551,7 +861,7 @@ fn share_cipher_by_uuid(uuid: &str, data: ShareCipherData, headers: &Headers, co
                     }
                 }
             }
-            update_cipher_from_data(&mut cipher, data.Cipher, &headers, shared_to_collection, &conn, &ws, UpdateType::SyncCipherUpdate)?;
+            update_cipher_from_data(&mut cipher, data.Cipher, &headers, shared_to_collection, &conn, &ws, Some(UpdateType::SyncCipherUpdate))?;
 
             Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
         }
@@ -657,50 +967,134 @@ fn delete_attachment_admin(uuid: String, attachment_id: String, headers: Headers
     _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, &conn, &ws)
 }
 
+#[get("/ciphers/<uuid>/revisions")]
+fn get_cipher_revisions(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist")
+    };
+
+    if !cipher.is_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not owned by user")
+    }
+
+    // The retention may have been shortened since the last change
+    let days = CipherRevision::retention_days(&cipher, &conn);
+    if let Err(e) = CipherRevision::delete_expired(&cipher.uuid, days, &conn) {
+        error!("Error removing the expired revisions of {}: {:#?}", cipher.uuid, e);
+    }
+
+    let revisions_json: Vec<Value> = CipherRevision::find_by_cipher(&cipher.uuid, &conn).iter().map(CipherRevision::to_json).collect();
+
+    Ok(Json(json!({
+      "Data": revisions_json,
+      "Object": "list",
+      "ContinuationToken": null
+    })))
+}
+
+#[post("/ciphers/<uuid>/revisions/<revision_id>/restore")]
+fn restore_cipher_revision(uuid: String, revision_id: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> JsonResult {
+    let mut cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist")
+    };
+
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not write accessible")
+    }
+
+    let revision = match CipherRevision::find_by_uuid(&revision_id, &conn) {
+        Some(revision) => revision,
+        None => err!("Revision doesn't exist")
+    };
+
+    if revision.cipher_uuid != cipher.uuid {
+        err!("Revision from other cipher")
+    }
+
+    // The current version is archived as well, so the restore can be undone
+    if CipherRevision::archive(&cipher, &headers.user.uuid, &conn).is_err() {
+        err!("Failed to save the cipher history")
+    }
+
+    revision.apply_to(&mut cipher);
+    if cipher.save(&conn).is_err() {
+        err!("Failed to save cipher")
+    }
+    ws.send_cipher_update(UpdateType::SyncCipherUpdate, &cipher, &cipher.update_users_revision(&conn));
+
+    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
+}
+
 #[post("/ciphers/<uuid>/delete")]
 fn delete_cipher_post(uuid: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
-    _delete_cipher_by_uuid(&uuid, &headers, &conn, &ws)
+    _delete_cipher_by_uuid(&uuid, &headers, &conn, false, &ws)
 }
 
 #[post("/ciphers/<uuid>/delete-admin")]
 fn delete_cipher_post_admin(uuid: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
-    _delete_cipher_by_uuid(&uuid, &headers, &conn, &ws)
+    _delete_cipher_by_uuid(&uuid, &headers, &conn, false, &ws)
+}
+
+#[put("/ciphers/<uuid>/delete")]
+fn delete_cipher_put(uuid: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    _delete_cipher_by_uuid(&uuid, &headers, &conn, true, &ws)
+}
+
+#[put("/ciphers/<uuid>/delete-admin")]
+fn delete_cipher_put_admin(uuid: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    _delete_cipher_by_uuid(&uuid, &headers, &conn, true, &ws)
 }
 
 #[delete("/ciphers/<uuid>")]
 fn delete_cipher(uuid: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
-    _delete_cipher_by_uuid(&uuid, &headers, &conn, &ws)
+    _delete_cipher_by_uuid(&uuid, &headers, &conn, false, &ws)
 }
 
 #[delete("/ciphers/<uuid>/admin")]
 fn delete_cipher_admin(uuid: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
-    _delete_cipher_by_uuid(&uuid, &headers, &conn, &ws)
+    _delete_cipher_by_uuid(&uuid, &headers, &conn, false, &ws)
 }
 
 #[delete("/ciphers", data = "<data>")]
 fn delete_cipher_selected(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
-    let data: Value = data.into_inner().data;
+    _delete_multiple_ciphers(data, headers, conn, false, ws)
+}
 
-    let uuids = match data.get("Ids") {
-        Some(ids) => match ids.as_array() {
-            Some(ids) => ids.iter().filter_map(Value::as_str),
-            None => err!("Posted ids field is not an array")
-        },
-        None => err!("Request missing ids field")
-    };
+#[post("/ciphers/delete", data = "<data>")]
+fn delete_cipher_selected_post(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    _delete_multiple_ciphers(data, headers, conn, false, ws)
+}
 
-    for uuid in uuids {
-        if let error @ Err(_) = _delete_cipher_by_uuid(uuid, &headers, &conn, &ws) {
-            return error;
-        };
-    }
+#[put("/ciphers/delete", data = "<data>")]
+fn delete_cipher_selected_put(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    _delete_multiple_ciphers(data, headers, conn, true, ws)
+}
 
-    Ok(())
+#[put("/ciphers/delete-admin", data = "<data>")]
+fn delete_cipher_selected_put_admin(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    _delete_multiple_ciphers(data, headers, conn, true, ws)
 }
 
-#[post("/ciphers/delete", data = "<data>")]
-fn delete_cipher_selected_post(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
-    delete_cipher_selected(data, headers, conn, ws)
+#[put("/ciphers/<uuid>/restore")]
+fn restore_cipher_put(uuid: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> JsonResult {
+    _restore_cipher_by_uuid(&uuid, &headers, &conn, &ws)
+}
+
+#[put("/ciphers/<uuid>/restore-admin")]
+fn restore_cipher_put_admin(uuid: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> JsonResult {
+    _restore_cipher_by_uuid(&uuid, &headers, &conn, &ws)
+}
+
+#[put("/ciphers/restore", data = "<data>")]
+fn restore_cipher_selected(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> JsonResult {
+    _restore_multiple_ciphers(data, headers, conn, ws)
+}
+
+#[put("/ciphers/restore-admin", data = "<data>")]
+fn restore_cipher_selected_admin(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> JsonResult {
+    _restore_multiple_ciphers(data, headers, conn, ws)
 }
 
 #[post("/ciphers/move", data = "<data>")]
@@ -797,8 +1191,8 @@ fn delete_all(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn, ws
     Ok(())
 }
 
-fn _delete_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, ws: &State<WebSocketUsers>) -> EmptyResult {
-    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+fn _delete_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, soft_delete: bool, ws: &State<WebSocketUsers>) -> EmptyResult {
+    let mut cipher = match Cipher::find_by_uuid(&uuid, &conn) {
         Some(cipher) => cipher,
         None => err!("Cipher doesn't exist"),
     };
@@ -807,12 +1201,119 @@ fn _delete_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, ws: &Sta
         err!("Cipher can't be deleted by user")
     }
 
-    match cipher.delete(&conn) {
-        Ok(()) => {
-            ws.send_cipher_update(UpdateType::SyncCipherDelete, &cipher, &cipher.update_users_revision(&conn));
-            Ok(())
+    if soft_delete {
+        cipher.deleted_at = Some(Utc::now().naive_utc());
+        match cipher.save(&conn) {
+            Ok(()) => {
+                ws.send_cipher_update(UpdateType::SyncCipherUpdate, &cipher, &cipher.update_users_revision(&conn));
+                Ok(())
+            }
+            Err(_) => err!("Failed moving cipher to the trash")
+        }
+    } else {
+        match cipher.delete(&conn) {
+            Ok(()) => {
+                ws.send_cipher_update(UpdateType::SyncCipherDelete, &cipher, &cipher.update_users_revision(&conn));
+                Ok(())
+            }
+            Err(_) => err!("Failed deleting cipher")
+        }
+    }
+}
+
+fn _selected_ids(data: &Value) -> api::ApiResult<Vec<&str>> {
+    match data.get("Ids") {
+        Some(ids) => match ids.as_array() {
+            Some(ids) => Ok(ids.iter().filter_map(Value::as_str).collect()),
+            None => err!("Posted ids field is not an array")
+        },
+        None => err!("Request missing ids field")
+    }
+}
+
+fn _delete_multiple_ciphers(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, soft_delete: bool, ws: State<WebSocketUsers>) -> EmptyResult {
+    let data: Value = data.into_inner().data;
+
+    for uuid in _selected_ids(&data)? {
+        if let error @ Err(_) = _delete_cipher_by_uuid(uuid, &headers, &conn, soft_delete, &ws) {
+            return error;
+        };
+    }
+
+    Ok(())
+}
+
+fn _restore_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, ws: &State<WebSocketUsers>) -> JsonResult {
+    let mut cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher can't be restored by user")
+    }
+
+    cipher.deleted_at = None;
+    if cipher.save(&conn).is_err() {
+        err!("Failed restoring cipher")
+    }
+    ws.send_cipher_update(UpdateType::SyncCipherUpdate, &cipher, &cipher.update_users_revision(&conn));
+
+    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
+}
+
+fn _restore_multiple_ciphers(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> JsonResult {
+    let data: Value = data.into_inner().data;
+
+    let mut ciphers_json = Vec::new();
+    for uuid in _selected_ids(&data)? {
+        ciphers_json.push(_restore_cipher_by_uuid(uuid, &headers, &conn, &ws)?.into_inner());
+    }
+
+    Ok(Json(json!({
+      "Data": ciphers_json,
+      "Object": "list",
+      "ContinuationToken": null
+    })))
+}
+
+/// Permanently deletes the ciphers that have been in the trash for longer than TRASH_AUTO_DELETE_DAYS, checked every hour
+pub fn start_trash_purge(pool: Pool, ws: WebSocketUsers) {
+    let days = match CONFIG.trash_auto_delete_days {
+        Some(days) => days,
+        None => return,
+    };
+
+    crate::util::spawn_hourly_job("trash-purge", pool, move |conn| purge_trash(days, conn, &ws));
+}
+
+/// Removes the cipher revisions past the retention of their organization or of the server, checked every hour
+pub fn start_revision_purge(pool: Pool) {
+    crate::util::spawn_hourly_job("revision-purge", pool, |conn| {
+        if let Err(e) = CipherRevision::purge_expired(conn) {
+            error!("Error purging the expired cipher revisions: {:#?}", e);
+        }
+    });
+}
+
+fn purge_trash(days: i64, conn: &DbConn, ws: &WebSocketUsers) {
+    let limit = Utc::now().naive_utc() - Duration::days(days);
+
+    let ciphers = match Cipher::find_deleted_before(&limit, conn) {
+        Ok(ciphers) => ciphers,
+        Err(e) => {
+            error!("Error loading the ciphers to purge from the trash: {:#?}", e);
+            return;
+        }
+    };
+
+    for cipher in ciphers {
+        // The members with access through a collection are found before the cipher leaves it
+        let user_uuids = cipher.update_users_revision(conn);
+        match cipher.delete(conn) {
+            Ok(()) => ws.send_cipher_update(UpdateType::SyncCipherDelete, &cipher, &user_uuids),
+            Err(e) => error!("Error purging cipher {} from the trash: {:#?}", cipher.uuid, e),
         }
-        Err(_) => err!("Failed deleting cipher")
     }
 }
 
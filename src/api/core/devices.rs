@@ -0,0 +1,136 @@
+use rocket::{Route, State};
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::db::DbConn;
+use crate::db::models::*;
+
+use crate::api::{EmptyResult, JsonResult, JsonUpcase, WebSocketUsers};
+use crate::auth::Headers;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_devices,
+        get_device,
+        put_device,
+        post_device,
+        delete_device,
+        delete_device_post,
+        forget_twofactor_remember,
+        clear_device_token,
+        put_device_token,
+    ]
+}
+
+#[get("/devices")]
+fn get_devices(headers: Headers, conn: DbConn) -> JsonResult {
+    let devices = Device::find_by_user(&headers.user.uuid, &conn);
+    let devices_json: Vec<Value> = devices.iter().map(Device::to_json).collect();
+
+    Ok(Json(json!({
+      "Data": devices_json,
+      "Object": "list",
+      "ContinuationToken": null,
+    })))
+}
+
+#[get("/devices/<uuid>")]
+fn get_device(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    match Device::find_by_uuid_and_user(&uuid, &headers.user.uuid, &conn) {
+        Some(device) => Ok(Json(device.to_json())),
+        None => err!("Device doesn't exist"),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct DeviceData {
+    Name: String,
+}
+
+#[put("/devices/<uuid>", data = "<data>")]
+fn put_device(uuid: String, data: JsonUpcase<DeviceData>, headers: Headers, conn: DbConn) -> JsonResult {
+    post_device(uuid, data, headers, conn)
+}
+
+#[post("/devices/<uuid>", data = "<data>")]
+fn post_device(uuid: String, data: JsonUpcase<DeviceData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: DeviceData = data.into_inner().data;
+
+    let mut device = match Device::find_by_uuid_and_user(&uuid, &headers.user.uuid, &conn) {
+        Some(device) => device,
+        None => err!("Device doesn't exist"),
+    };
+
+    if data.Name.trim().is_empty() {
+        err!("The device name can't be empty")
+    }
+
+    device.name = data.Name;
+    match device.save(&conn) {
+        Ok(()) => Ok(Json(device.to_json())),
+        Err(_) => err!("Failed to save device"),
+    }
+}
+
+#[post("/devices/<uuid>/delete")]
+fn delete_device_post(uuid: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    delete_device(uuid, headers, conn, ws)
+}
+
+#[delete("/devices/<uuid>")]
+fn delete_device(uuid: String, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    let device = match Device::find_by_uuid_and_user(&uuid, &headers.user.uuid, &conn) {
+        Some(device) => device,
+        None => err!("Device doesn't exist"),
+    };
+
+    // Removing the device invalidates its refresh token, and its access tokens are rejected
+    // because the device can't be found anymore
+    let device_uuid = device.uuid.clone();
+    if device.delete(&conn).is_err() {
+        err!("Failed to delete device")
+    }
+
+    ws.send_device_logout(&headers.user, &device_uuid);
+    Ok(())
+}
+
+#[post("/devices/forget-twofactor-remember")]
+fn forget_twofactor_remember(headers: Headers, conn: DbConn) -> EmptyResult {
+    match Device::clear_twofactor_remember_by_user(&headers.user.uuid, &conn) {
+        Ok(()) => Ok(()),
+        Err(_) => err!("Failed to forget the two-step login devices"),
+    }
+}
+
+#[put("/devices/identifier/<uuid>/clear-token")]
+fn clear_device_token(uuid: String) -> EmptyResult {
+    // This endpoint doesn't have auth header
+
+    let _ = uuid;
+    // uuid is not related to deviceId
+
+    // This only clears push token
+    // https://github.com/bitwarden/core/blob/master/src/Api/Controllers/DevicesController.cs#L109
+    // https://github.com/bitwarden/core/blob/master/src/Core/Services/Implementations/DeviceService.cs#L37
+    Ok(())
+}
+
+#[put("/devices/identifier/<uuid>/token", data = "<data>")]
+fn put_device_token(uuid: String, data: JsonUpcase<Value>, headers: Headers) -> JsonResult {
+    let _data: Value = data.into_inner().data;
+    // Data has a single string value "PushToken"
+    let _ = uuid;
+    // uuid is not related to deviceId
+
+    // TODO: This should save the push token, but we don't have push functionality
+
+    Ok(Json(json!({
+        "Id": headers.device.uuid,
+        "Name": headers.device.name,
+        "Type": headers.device.type_,
+        "Identifier": headers.device.uuid,
+        "CreationDate": crate::util::format_date(&headers.device.created_at),
+    })))
+}
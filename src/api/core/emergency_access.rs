@@ -0,0 +1,600 @@
+use chrono::{Duration, Utc};
+use rocket::{Route, State};
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::api::{notify_account_change, revoke_sessions, ApiResult, EmptyResult, JsonResult, JsonUpcase, NumberOrString, WebSocketUsers};
+use crate::auth::{decode_emergency_access_invite_jwt, encode_jwt, EmergencyAccessInviteJWTClaims, Headers, JWT_ISSUER};
+use crate::db::models::*;
+use crate::db::{DbConn, Pool};
+use crate::mail;
+use crate::CONFIG;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_contacts,
+        get_grantees,
+        get_emergency_access,
+        put_emergency_access,
+        post_emergency_access,
+        delete_emergency_access,
+        post_delete_emergency_access,
+        send_invite,
+        resend_invite,
+        accept_invite,
+        confirm_emergency_access,
+        initiate_emergency_access,
+        approve_emergency_access,
+        reject_emergency_access,
+        view_emergency_access,
+        takeover_emergency_access,
+        password_emergency_access,
+    ]
+}
+
+/// The grantor can't make the grantee wait for longer than this
+const MAX_WAIT_TIME_DAYS: i32 = 90;
+
+fn _emergency_access_allowed() -> EmptyResult {
+    if !CONFIG.emergency_access_allowed {
+        err!("Emergency access is disabled on this server")
+    }
+    Ok(())
+}
+
+fn _parse_type(type_: NumberOrString) -> ApiResult<EmergencyAccessType> {
+    match type_.into_i32().and_then(EmergencyAccessType::from_i32) {
+        Some(type_) => Ok(type_),
+        None => err!("Invalid emergency access type"),
+    }
+}
+
+fn _check_wait_time(wait_time_days: i32) -> EmptyResult {
+    if wait_time_days < 1 || wait_time_days > MAX_WAIT_TIME_DAYS {
+        err!(format!("The wait time must be between 1 and {} days", MAX_WAIT_TIME_DAYS))
+    }
+    Ok(())
+}
+
+fn _type_name(emer: &EmergencyAccess) -> &'static str {
+    if emer.has_type(EmergencyAccessType::Takeover) {
+        "takeover"
+    } else {
+        "view"
+    }
+}
+
+/// The notifications are not needed for the access to work, so failing to queue them only gets logged
+fn _queue_mail(result: Result<(), String>) {
+    if let Err(e) = result {
+        error!("Error sending emergency access notification: {}", e);
+    }
+}
+
+fn _find_as_grantor(emer_id: &str, headers: &Headers, conn: &DbConn) -> ApiResult<EmergencyAccess> {
+    let emer = match EmergencyAccess::find_by_uuid(emer_id, conn) {
+        Some(emer) => emer,
+        None => err!("Emergency access not valid."),
+    };
+
+    if emer.grantor_uuid != headers.user.uuid {
+        err!("Emergency access not valid.")
+    }
+    Ok(emer)
+}
+
+fn _find_as_grantee(emer_id: &str, headers: &Headers, conn: &DbConn) -> ApiResult<EmergencyAccess> {
+    let emer = match EmergencyAccess::find_by_uuid(emer_id, conn) {
+        Some(emer) => emer,
+        None => err!("Emergency access not valid."),
+    };
+
+    if emer.grantee_uuid.as_ref() != Some(&headers.user.uuid) {
+        err!("Emergency access not valid.")
+    }
+    Ok(emer)
+}
+
+/// The grantee can only use the access once the grantor approved the recovery or the wait time passed
+fn _find_approved(emer_id: &str, type_: EmergencyAccessType, headers: &Headers, conn: &DbConn) -> ApiResult<(EmergencyAccess, User)> {
+    _emergency_access_allowed()?;
+    let emer = _find_as_grantee(emer_id, headers, conn)?;
+
+    if !emer.has_status(EmergencyAccessStatus::RecoveryApproved) || !emer.has_type(type_) {
+        err!("Emergency access not valid.")
+    }
+
+    match User::find_by_uuid(&emer.grantor_uuid, conn) {
+        Some(grantor) => Ok((emer, grantor)),
+        None => err!("Grantor user not found."),
+    }
+}
+
+/// Creates the token that lets the grantee accept the invitation, it expires like the organization invitations
+fn _invite_token(emer: &EmergencyAccess, email: &str, grantor_name: &str) -> String {
+    let time_now = Utc::now().naive_utc();
+    let claims = EmergencyAccessInviteJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::hours(CONFIG.invitation_expiration_hours)).timestamp(),
+        iss: JWT_ISSUER.to_string(),
+        sub: emer.uuid.clone(),
+        email: email.to_string(),
+        grantor_name: grantor_name.to_string(),
+    };
+
+    encode_jwt(&claims)
+}
+
+///
+/// Grantor endpoints
+///
+
+#[get("/emergency-access/trusted")]
+fn get_contacts(headers: Headers, conn: DbConn) -> JsonResult {
+    let emers_json: Vec<Value> = EmergencyAccess::find_by_grantor(&headers.user.uuid, &conn)
+        .iter()
+        .map(|e| e.to_json_grantee_details(&conn))
+        .collect();
+
+    Ok(Json(json!({
+      "Data": emers_json,
+      "Object": "list",
+      "ContinuationToken": null
+    })))
+}
+
+#[get("/emergency-access/<emer_id>")]
+fn get_emergency_access(emer_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let emer = _find_as_grantor(&emer_id, &headers, &conn)?;
+    Ok(Json(emer.to_json_grantee_details(&conn)))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct EmergencyAccessUpdateData {
+    Type: NumberOrString,
+    WaitTimeDays: i32,
+}
+
+#[put("/emergency-access/<emer_id>", data = "<data>")]
+fn put_emergency_access(emer_id: String, data: JsonUpcase<EmergencyAccessUpdateData>, headers: Headers, conn: DbConn) -> JsonResult {
+    post_emergency_access(emer_id, data, headers, conn)
+}
+
+#[post("/emergency-access/<emer_id>", data = "<data>")]
+fn post_emergency_access(emer_id: String, data: JsonUpcase<EmergencyAccessUpdateData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _emergency_access_allowed()?;
+    let data: EmergencyAccessUpdateData = data.into_inner().data;
+
+    let mut emer = _find_as_grantor(&emer_id, &headers, &conn)?;
+    let type_ = _parse_type(data.Type)?;
+    _check_wait_time(data.WaitTimeDays)?;
+
+    emer.type_ = type_ as i32;
+    emer.wait_time_days = data.WaitTimeDays;
+
+    match emer.save(&conn) {
+        Ok(()) => Ok(Json(emer.to_json())),
+        Err(_) => err!("Failed to update emergency access"),
+    }
+}
+
+/// Both the grantor and the grantee can remove the access
+#[delete("/emergency-access/<emer_id>")]
+fn delete_emergency_access(emer_id: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    let emer = match EmergencyAccess::find_by_uuid(&emer_id, &conn) {
+        Some(emer) => emer,
+        None => err!("Emergency access not valid."),
+    };
+
+    if emer.grantor_uuid != headers.user.uuid && emer.grantee_uuid.as_ref() != Some(&headers.user.uuid) {
+        err!("Emergency access not valid.")
+    }
+
+    match emer.delete(&conn) {
+        Ok(()) => Ok(()),
+        Err(_) => err!("Failed to delete emergency access"),
+    }
+}
+
+#[post("/emergency-access/<emer_id>/delete")]
+fn post_delete_emergency_access(emer_id: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    delete_emergency_access(emer_id, headers, conn)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct EmergencyAccessInviteData {
+    Email: String,
+    Type: NumberOrString,
+    WaitTimeDays: i32,
+}
+
+#[post("/emergency-access/invite", data = "<data>")]
+fn send_invite(data: JsonUpcase<EmergencyAccessInviteData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    _emergency_access_allowed()?;
+    let data: EmergencyAccessInviteData = data.into_inner().data;
+
+    let email = data.Email.to_lowercase();
+    let type_ = _parse_type(data.Type)?;
+    _check_wait_time(data.WaitTimeDays)?;
+
+    if email == headers.user.email {
+        err!("You can not set yourself as an emergency contact.")
+    }
+
+    // The grantee needs an account to have a public key the grantor's key can be encrypted with
+    let grantee = match User::find_by_mail(&email, &conn) {
+        Some(grantee) => grantee,
+        None => err!("Grantee user does not exist"),
+    };
+
+    if EmergencyAccess::find_by_grantor_and_email(&headers.user.uuid, &email, &conn).is_some()
+        || EmergencyAccess::find_by_grantor_and_grantee(&headers.user.uuid, &grantee.uuid, &conn).is_some()
+    {
+        err!("Grantee user already invited")
+    }
+
+    let mut emer = EmergencyAccess::new(headers.user.uuid.clone(), email.clone(), type_, data.WaitTimeDays);
+
+    // Without mail the invitation can't be sent, so it's accepted right away, like the organization invitations
+    if !CONFIG.mail_enabled() {
+        emer.grantee_uuid = Some(grantee.uuid);
+        emer.email = None;
+        emer.status = EmergencyAccessStatus::Accepted as i32;
+    }
+
+    if emer.save(&conn).is_err() {
+        err!("Failed to save emergency access")
+    }
+
+    if CONFIG.mail_enabled() {
+        let token = _invite_token(&emer, &email, &headers.user.name);
+        if let Err(e) = mail::send_emergency_access_invite(&email, &emer.uuid, &token, &headers.user.name, &conn) {
+            err!(format!("Error sending invite: {:?} ", e));
+        }
+    }
+
+    Ok(())
+}
+
+#[post("/emergency-access/<emer_id>/reinvite")]
+fn resend_invite(emer_id: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    _emergency_access_allowed()?;
+    let emer = _find_as_grantor(&emer_id, &headers, &conn)?;
+
+    if !emer.has_status(EmergencyAccessStatus::Invited) {
+        err!("The invitation was already accepted")
+    }
+
+    if !CONFIG.mail_enabled() {
+        err!("Mail is not enabled on this server")
+    }
+
+    let email = match emer.email {
+        Some(ref email) => email.clone(),
+        None => err!("Email not valid."),
+    };
+
+    let token = _invite_token(&emer, &email, &headers.user.name);
+    if let Err(e) = mail::send_emergency_access_invite(&email, &emer.uuid, &token, &headers.user.name, &conn) {
+        err!(format!("Error sending invite: {:?} ", e));
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct EmergencyAccessConfirmData {
+    Key: String,
+}
+
+/// The key is the grantor's key, encrypted by the client with the public key of the grantee
+#[post("/emergency-access/<emer_id>/confirm", data = "<data>")]
+fn confirm_emergency_access(emer_id: String, data: JsonUpcase<EmergencyAccessConfirmData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _emergency_access_allowed()?;
+    let data: EmergencyAccessConfirmData = data.into_inner().data;
+
+    let mut emer = _find_as_grantor(&emer_id, &headers, &conn)?;
+    if !emer.has_status(EmergencyAccessStatus::Accepted) {
+        err!("Emergency access not valid.")
+    }
+
+    let grantee = match emer.grantee_uuid.as_ref().and_then(|uuid| User::find_by_uuid(uuid, &conn)) {
+        Some(grantee) => grantee,
+        None => err!("Grantee user not found."),
+    };
+
+    emer.key_encrypted = Some(data.Key);
+    emer.status = EmergencyAccessStatus::Confirmed as i32;
+
+    if emer.save(&conn).is_err() {
+        err!("Failed to confirm emergency access")
+    }
+
+    if CONFIG.mail_enabled() {
+        _queue_mail(mail::send_emergency_access_invite_confirmed(&grantee.email, &headers.user.name, &conn));
+    }
+
+    Ok(Json(emer.to_json()))
+}
+
+#[post("/emergency-access/<emer_id>/approve")]
+fn approve_emergency_access(emer_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    _emergency_access_allowed()?;
+
+    let mut emer = _find_as_grantor(&emer_id, &headers, &conn)?;
+    if !emer.has_status(EmergencyAccessStatus::RecoveryInitiated) {
+        err!("Emergency access not valid.")
+    }
+
+    emer.status = EmergencyAccessStatus::RecoveryApproved as i32;
+    if emer.save(&conn).is_err() {
+        err!("Failed to approve emergency access")
+    }
+
+    if let Some(grantee) = emer.grantee_uuid.as_ref().and_then(|uuid| User::find_by_uuid(uuid, &conn)) {
+        if CONFIG.mail_enabled() {
+            _queue_mail(mail::send_emergency_access_recovery_approved(&grantee.email, &headers.user.name, &conn));
+        }
+    }
+
+    Ok(Json(emer.to_json()))
+}
+
+/// Puts the access back to confirmed, the grantee can initiate a new recovery later
+#[post("/emergency-access/<emer_id>/reject")]
+fn reject_emergency_access(emer_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    _emergency_access_allowed()?;
+
+    let mut emer = _find_as_grantor(&emer_id, &headers, &conn)?;
+    if !emer.has_status(EmergencyAccessStatus::RecoveryInitiated) && !emer.has_status(EmergencyAccessStatus::RecoveryApproved) {
+        err!("Emergency access not valid.")
+    }
+
+    emer.status = EmergencyAccessStatus::Confirmed as i32;
+    emer.recovery_initiated_at = None;
+    emer.last_notification_at = None;
+    if emer.save(&conn).is_err() {
+        err!("Failed to reject emergency access")
+    }
+
+    if let Some(grantee) = emer.grantee_uuid.as_ref().and_then(|uuid| User::find_by_uuid(uuid, &conn)) {
+        if CONFIG.mail_enabled() {
+            _queue_mail(mail::send_emergency_access_recovery_rejected(&grantee.email, &headers.user.name, &conn));
+        }
+    }
+
+    Ok(Json(emer.to_json()))
+}
+
+///
+/// Grantee endpoints
+///
+
+#[get("/emergency-access/granted")]
+fn get_grantees(headers: Headers, conn: DbConn) -> JsonResult {
+    let emers_json: Vec<Value> = EmergencyAccess::find_by_grantee(&headers.user.uuid, &conn)
+        .iter()
+        .map(|e| e.to_json_grantor_details(&conn))
+        .collect();
+
+    Ok(Json(json!({
+      "Data": emers_json,
+      "Object": "list",
+      "ContinuationToken": null
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct AcceptData {
+    Token: String,
+}
+
+#[post("/emergency-access/<emer_id>/accept", data = "<data>")]
+fn accept_invite(emer_id: String, data: JsonUpcase<AcceptData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    _emergency_access_allowed()?;
+    let data: AcceptData = data.into_inner().data;
+
+    let claims = match decode_emergency_access_invite_jwt(&data.Token) {
+        Ok(claims) => claims,
+        Err(msg) => err!("Invalid claim: {:#?}", msg),
+    };
+
+    // The invitation can only be accepted by the invited user
+    if claims.sub != emer_id || claims.email != headers.user.email {
+        err!("Claim email does not match current users email")
+    }
+
+    let mut emer = match EmergencyAccess::find_by_uuid(&emer_id, &conn) {
+        Some(emer) => emer,
+        None => err!("Invite is no longer valid."),
+    };
+
+    if !emer.has_status(EmergencyAccessStatus::Invited) || emer.email.as_ref() != Some(&claims.email) {
+        err!("Invite is no longer valid.")
+    }
+
+    emer.grantee_uuid = Some(headers.user.uuid.clone());
+    emer.email = None;
+    emer.status = EmergencyAccessStatus::Accepted as i32;
+
+    if emer.save(&conn).is_err() {
+        err!("Failed to accept emergency access")
+    }
+
+    if let Some(grantor) = User::find_by_uuid(&emer.grantor_uuid, &conn) {
+        if CONFIG.mail_enabled() {
+            _queue_mail(mail::send_emergency_access_invite_accepted(&grantor.email, &headers.user.email, &conn));
+        }
+    }
+
+    Ok(())
+}
+
+#[post("/emergency-access/<emer_id>/initiate")]
+fn initiate_emergency_access(emer_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    _emergency_access_allowed()?;
+
+    let mut emer = _find_as_grantee(&emer_id, &headers, &conn)?;
+    if !emer.has_status(EmergencyAccessStatus::Confirmed) {
+        err!("Emergency access not valid.")
+    }
+
+    let grantor = match User::find_by_uuid(&emer.grantor_uuid, &conn) {
+        Some(grantor) => grantor,
+        None => err!("Grantor user not found."),
+    };
+
+    let now = Utc::now().naive_utc();
+    emer.status = EmergencyAccessStatus::RecoveryInitiated as i32;
+    emer.recovery_initiated_at = Some(now);
+    emer.last_notification_at = Some(now);
+
+    if emer.save(&conn).is_err() {
+        err!("Failed to initiate emergency access")
+    }
+
+    if CONFIG.mail_enabled() {
+        _queue_mail(mail::send_emergency_access_recovery_initiated(
+            &grantor.email,
+            &headers.user.name,
+            _type_name(&emer),
+            emer.wait_time_days,
+            &conn,
+        ));
+    }
+
+    Ok(Json(emer.to_json()))
+}
+
+/// Returns the vault of the grantor, still encrypted, with the key the grantee can decrypt
+#[post("/emergency-access/<emer_id>/view")]
+fn view_emergency_access(emer_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let (emer, grantor) = _find_approved(&emer_id, EmergencyAccessType::View, &headers, &conn)?;
+
+    let ciphers_json: Vec<Value> = Cipher::find_owned_by_user(&grantor.uuid, &conn)
+        .iter()
+        .filter(|c| c.deleted_at.is_none())
+        .map(|c| c.to_json(&headers.host, &grantor.uuid, &conn))
+        .collect();
+
+    Ok(Json(json!({
+        "Ciphers": ciphers_json,
+        "KeyEncrypted": emer.key_encrypted,
+        "Object": "emergencyAccessView",
+    })))
+}
+
+/// Gives the grantee what is needed to derive the new master key of the grantor
+#[post("/emergency-access/<emer_id>/takeover")]
+fn takeover_emergency_access(emer_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let (emer, grantor) = _find_approved(&emer_id, EmergencyAccessType::Takeover, &headers, &conn)?;
+
+    Ok(Json(json!({
+        "Kdf": grantor.client_kdf_type,
+        "KdfIterations": grantor.client_kdf_iter,
+        "KdfMemory": grantor.client_kdf_memory,
+        "KdfParallelism": grantor.client_kdf_parallelism,
+        "KeyEncrypted": emer.key_encrypted,
+        "Object": "emergencyAccessTakeover",
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct EmergencyAccessPasswordData {
+    NewMasterPasswordHash: String,
+    Key: String,
+}
+
+/// Sets a new master password for the grantor, all the sessions of the grantor are revoked
+#[post("/emergency-access/<emer_id>/password", data = "<data>")]
+fn password_emergency_access(emer_id: String, data: JsonUpcase<EmergencyAccessPasswordData>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    let data: EmergencyAccessPasswordData = data.into_inner().data;
+    let (_, mut grantor) = _find_approved(&emer_id, EmergencyAccessType::Takeover, &headers, &conn)?;
+
+    grantor.set_password(&data.NewMasterPasswordHash);
+    grantor.key = data.Key;
+    grantor.reset_security_stamp();
+
+    if grantor.save(&conn).is_err() {
+        err!("Failed to save password")
+    }
+
+    revoke_sessions(&grantor, &conn, &ws);
+
+    notify_account_change(
+        &grantor.email,
+        &format!("Your master password was changed by your emergency contact {}.", headers.user.email),
+        &conn,
+    );
+
+    Ok(())
+}
+
+/// Starts the background job that approves the recoveries once their wait time has passed,
+/// and reminds the grantors of the ones still waiting
+pub fn start_emergency_access_job(pool: Pool) {
+    crate::util::spawn_hourly_job("emergency-access", pool, process_recoveries);
+}
+
+fn process_recoveries(conn: &DbConn) {
+    if !CONFIG.emergency_access_allowed {
+        return;
+    }
+
+    let now = Utc::now().naive_utc();
+
+    let accesses = match EmergencyAccess::find_recoveries_initiated(conn) {
+        Ok(accesses) => accesses,
+        Err(e) => {
+            error!("Error loading the initiated emergency access recoveries: {:#?}", e);
+            return;
+        }
+    };
+
+    for mut emer in accesses {
+        let deadline = match emer.recovery_deadline() {
+            Some(deadline) => deadline,
+            None => continue,
+        };
+
+        let grantor = User::find_by_uuid(&emer.grantor_uuid, conn);
+        let grantee = emer.grantee_uuid.as_ref().and_then(|uuid| User::find_by_uuid(uuid, conn));
+        let (grantor, grantee) = match (grantor, grantee) {
+            (Some(grantor), Some(grantee)) => (grantor, grantee),
+            _ => continue,
+        };
+
+        if deadline <= now {
+            emer.status = EmergencyAccessStatus::RecoveryApproved as i32;
+            if let Err(e) = emer.save(conn) {
+                error!("Error approving emergency access {}: {:#?}", emer.uuid, e);
+                continue;
+            }
+
+            if CONFIG.mail_enabled() {
+                _queue_mail(mail::send_emergency_access_recovery_approved(&grantee.email, &grantor.name, conn));
+                _queue_mail(mail::send_emergency_access_recovery_timed_out(&grantor.email, &grantee.name, _type_name(&emer), conn));
+            }
+        } else if CONFIG.mail_enabled() {
+            let reminder_due = emer.last_notification_at
+                .map_or(true, |date| date + Duration::hours(CONFIG.emergency_reminder_hours) <= now);
+            if !reminder_due {
+                continue;
+            }
+
+            // Rounded up, so the last day before the approval still shows one day left
+            let days_left = (deadline - now).num_hours() / 24 + 1;
+            _queue_mail(mail::send_emergency_access_recovery_reminder(&grantor.email, &grantee.name, _type_name(&emer), days_left, conn));
+
+            emer.last_notification_at = Some(now);
+            if let Err(e) = emer.save(conn) {
+                error!("Error saving emergency access {}: {:#?}", emer.uuid, e);
+            }
+        }
+    }
+}
@@ -1,14 +1,20 @@
-mod accounts;
+pub(crate) mod accounts;
 mod ciphers;
+mod devices;
+mod emergency_access;
 mod folders;
 mod organizations;
+mod public;
+mod sends;
 pub(crate) mod two_factor;
 
+pub use self::ciphers::{start_revision_purge, start_trash_purge};
+pub use self::emergency_access::start_emergency_access_job;
+pub use self::organizations::start_invitation_sweep;
+pub use self::sends::start_send_purge;
+
 pub fn routes() -> Vec<Route> {
     let mut mod_routes = routes![
-        clear_device_token,
-        put_device_token,
-
         get_eq_domains,
         post_eq_domains,
         put_eq_domains,
@@ -17,8 +23,12 @@ pub fn routes() -> Vec<Route> {
     let mut routes = Vec::new();
     routes.append(&mut accounts::routes());
     routes.append(&mut ciphers::routes());
+    routes.append(&mut devices::routes());
+    routes.append(&mut emergency_access::routes());
     routes.append(&mut folders::routes());
     routes.append(&mut organizations::routes());
+    routes.append(&mut public::routes());
+    routes.append(&mut sends::routes());
     routes.append(&mut two_factor::routes());
     routes.append(&mut mod_routes);
 
@@ -31,44 +41,11 @@ pub fn routes() -> Vec<Route> {
 use rocket::Route;
 
 use rocket_contrib::json::Json;
-use serde_json::Value;
-
 use crate::db::DbConn;
 
-use crate::api::{EmptyResult, JsonResult, JsonUpcase};
+use crate::api::{JsonResult, JsonUpcase};
 use crate::auth::Headers;
 
-#[put("/devices/identifier/<uuid>/clear-token")]
-fn clear_device_token(uuid: String) -> EmptyResult {
-    // This endpoint doesn't have auth header
-
-    let _ = uuid;
-    // uuid is not related to deviceId
-
-    // This only clears push token
-    // https://github.com/bitwarden/core/blob/master/src/Api/Controllers/DevicesController.cs#L109
-    // https://github.com/bitwarden/core/blob/master/src/Core/Services/Implementations/DeviceService.cs#L37
-    Ok(())
-}
-
-#[put("/devices/identifier/<uuid>/token", data = "<data>")]
-fn put_device_token(uuid: String, data: JsonUpcase<Value>, headers: Headers) -> JsonResult {
-    let _data: Value = data.into_inner().data;
-    // Data has a single string value "PushToken"
-    let _ = uuid;
-    // uuid is not related to deviceId
-
-    // TODO: This should save the push token, but we don't have push functionality
-
-    Ok(Json(json!({
-        "Id": headers.device.uuid,
-        "Name": headers.device.name,
-        "Type": headers.device.type_,
-        "Identifier": headers.device.uuid,
-        "CreationDate": crate::util::format_date(&headers.device.created_at),
-    })))
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct GlobalDomain {
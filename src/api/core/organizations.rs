@@ -1,14 +1,17 @@
-use rocket::State;
+use rocket::{Data, State};
 use rocket::request::Form;
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
+use chrono::{Duration, Utc};
+
 use crate::CONFIG;
-use crate::db::DbConn;
+use crate::db::{DbConn, Pool};
 use crate::db::models::*;
 
-use crate::api::{PasswordData, JsonResult, EmptyResult, NumberOrString, JsonUpcase, WebSocketUsers, UpdateType};
-use crate::auth::{Headers, AdminHeaders, OwnerHeaders, encode_jwt, decode_invite_jwt, InviteJWTClaims, JWT_ISSUER};
+use crate::api::{PasswordData, ApiResult, JsonResult, EmptyResult, NumberOrString, JsonUpcase, WebSocketUsers, UpdateType, notify_account_change, revoke_sessions};
+use crate::auth::{Headers, OrgHeaders, AdminHeaders, OwnerHeaders, encode_jwt, decode_invite_jwt, InviteJWTClaims, JWT_ISSUER};
+use crate::mail;
 
 use serde::{Deserialize, Deserializer};
 
@@ -27,6 +30,7 @@ pub fn routes() -> Vec<Route> {
         get_collection_users,
         put_organization,
         post_organization,
+        put_revision_retention,
         post_organization_collections,
         delete_organization_collection_user,
         post_organization_collection_delete_user,
@@ -45,7 +49,18 @@ pub fn routes() -> Vec<Route> {
         delete_user,
         post_delete_user,
         post_reinvite_user,
+        get_invites,
+        revoke_invite,
+        post_revoke_invite,
         post_org_import,
+        post_org_import_stream,
+        api_key,
+        rotate_api_key,
+        get_organization_keys,
+        post_organization_keys,
+        put_reset_password_enrollment,
+        get_reset_password_details,
+        put_reset_password,
     ]
 }
 
@@ -56,6 +71,7 @@ struct OrgData {
     BillingEmail: String,
     CollectionName: String,
     Key: String,
+    Keys: Option<OrgKeyData>,
     Name: String,
     #[serde(rename = "PlanType")]
     _PlanType: NumberOrString, // Ignored, always use the same plan
@@ -79,6 +95,10 @@ fn create_organization(headers: Headers, data: JsonUpcase<OrgData>, conn: DbConn
     let data: OrgData = data.into_inner().data;
 
     let mut org = Organization::new(data.Name, data.BillingEmail);
+    if let Some(keys) = data.Keys {
+        org.private_key = Some(keys.EncryptedPrivateKey);
+        org.public_key = Some(keys.PublicKey);
+    }
     let mut user_org = UserOrganization::new(
         headers.user.uuid.clone(), org.uuid.clone());
     let mut collection = Collection::new(
@@ -126,6 +146,43 @@ fn post_delete_organization(org_id: String, data: JsonUpcase<PasswordData>, head
     delete_organization(org_id, data, headers, conn)
 }
 
+#[post("/organizations/<org_id>/api-key", data = "<data>")]
+fn api_key(org_id: String, data: JsonUpcase<PasswordData>, headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+    _api_key(&org_id, data, false, headers, conn)
+}
+
+#[post("/organizations/<org_id>/rotate-api-key", data = "<data>")]
+fn rotate_api_key(org_id: String, data: JsonUpcase<PasswordData>, headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+    _api_key(&org_id, data, true, headers, conn)
+}
+
+fn _api_key(org_id: &str, data: JsonUpcase<PasswordData>, rotate: bool, headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    let org = match Organization::find_by_uuid(org_id, &conn) {
+        Some(org) => org,
+        None => err!("Organization not found"),
+    };
+
+    // The secret is stored hashed, so it can only be shown when it's generated
+    let (mut api_key, rotate) = match ApiKey::find_by_organization(&org.uuid, &conn) {
+        Some(api_key) => (api_key, rotate),
+        None => (ApiKey::new_for_org(org.uuid.clone()), true),
+    };
+
+    let secret = if rotate { Some(api_key.rotate_secret()) } else { None };
+
+    if api_key.save(&conn).is_err() {
+        err!("Failed to save API key")
+    }
+
+    Ok(Json(api_key.to_json(secret.as_ref().map(String::as_str))))
+}
+
 #[post("/organizations/<org_id>/leave")]
 fn leave_organization(org_id: String, headers: Headers, conn: DbConn) -> EmptyResult {
     match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, &conn) {
@@ -180,6 +237,34 @@ fn post_organization(org_id: String, _headers: OwnerHeaders, data: JsonUpcase<Or
     }
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct RevisionRetentionData {
+    Days: Option<i32>,
+}
+
+/// Sets how long the previous versions of the organization ciphers are kept, `null` uses the server default
+#[put("/organizations/<org_id>/revision-retention", data = "<data>")]
+fn put_revision_retention(org_id: String, _headers: OwnerHeaders, data: JsonUpcase<RevisionRetentionData>, conn: DbConn) -> JsonResult {
+    let data: RevisionRetentionData = data.into_inner().data;
+
+    if data.Days.map_or(false, |days| days < 0 || days > CipherRevision::MAX_RETENTION_DAYS) {
+        err!(format!("The retention must be between 0 and {} days", CipherRevision::MAX_RETENTION_DAYS))
+    }
+
+    let mut org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(organization) => organization,
+        None => err!("Can't find organization details")
+    };
+
+    org.revision_retention_days = data.Days;
+
+    match org.save(&conn) {
+        Ok(()) => Ok(Json(org.to_json())),
+        Err(_) => err!("Failed to modify organization")
+    }
+}
+
 // GET /api/collections?writeOnly=false
 #[get("/collections")]
 fn get_user_collections(headers: Headers, conn: DbConn) -> JsonResult {
@@ -424,13 +509,15 @@ fn send_invite(org_id: String, data: JsonUpcase<InviteData>, headers: AdminHeade
     }
 
     for email in data.Emails.iter() {
-        let mut user_org_status = match CONFIG.mail {
-            Some(_) => UserOrgStatus::Invited as i32,
-            None => UserOrgStatus::Accepted as i32, // Automatically mark user as accepted if no email invites
+        let mut user_org_status = if CONFIG.mail_enabled() {
+            UserOrgStatus::Invited as i32
+        } else {
+            UserOrgStatus::Accepted as i32 // Automatically mark user as accepted if no email invites
         };
         let user = match User::find_by_mail(&email, &conn) {
-            None => if CONFIG.invitations_allowed { // Invite user if that's enabled
-                let mut invitation = Invitation::new(email.clone());
+            None => if CONFIG.invitations_allowed() { // Invite user if that's enabled
+                let invitation_org = if org_id == Organization::VIRTUAL_ID { None } else { Some(org_id.clone()) };
+                let mut invitation = Invitation::new(email.clone(), invitation_org, Some(headers.user.uuid.clone()));
                 match invitation.save(&conn) {
                     Ok(()) => {
                         let mut user = User::new(email.clone());
@@ -450,6 +537,16 @@ fn send_invite(org_id: String, data: JsonUpcase<InviteData>, headers: AdminHeade
             Some(user) => if UserOrganization::find_by_user_and_org(&user.uuid, &org_id, &conn).is_some() {
                 err!(format!("User already in organization: {}", email))
             } else {
+                // A placeholder account has a single invitation for all the organizations that invited it,
+                // so every invite renews it, otherwise the oldest invite would remove the newer memberships when it expires
+                if user.password_hash.is_empty() && CONFIG.invitations_allowed() {
+                    let invitation_org = if org_id == Organization::VIRTUAL_ID { None } else { Some(org_id.clone()) };
+                    let mut invitation = Invitation::new(email.clone(), invitation_org, Some(headers.user.uuid.clone()));
+                    if invitation.save(&conn).is_err() {
+                        err!(format!("Failed to invite: {}", email))
+                    }
+                    user_org_status = UserOrgStatus::Invited as i32;
+                }
                 user
             }
 
@@ -484,29 +581,15 @@ fn send_invite(org_id: String, data: JsonUpcase<InviteData>, headers: AdminHeade
             org_user_id = Some(new_user.uuid.clone());
         }
 
-        if CONFIG.mail.is_some() {
-            use crate::mail;
-            use chrono::{Duration, Utc};
-            let time_now = Utc::now().naive_utc();
-            let claims = InviteJWTClaims {
-                nbf: time_now.timestamp(),
-                exp: (time_now + Duration::days(5)).timestamp(),
-                iss: JWT_ISSUER.to_string(),
-                sub: user.uuid.to_string(),
-                email: email.clone(),
-                org_id: org_id.clone(),
-                user_org_id: org_user_id.clone(),
-            };
+        if CONFIG.mail_enabled() {
             let org_name = match Organization::find_by_uuid(&org_id, &conn) {
                 Some(org) => org.name,
                 None => err!("Error looking up organization")
             };
-            let invite_token = encode_jwt(&claims);
-            if let Some(ref mail_config) = CONFIG.mail {
-                if let Err(e) = mail::send_invite(&email, &org_id, &org_user_id.unwrap_or(Organization::VIRTUAL_ID.to_string()), 
-                                                  &invite_token, &org_name, mail_config) {
-                    err!(format!("There has been a problem sending the email: {}", e))
-                }
+            let org_user_id = org_user_id.unwrap_or_else(|| Organization::VIRTUAL_ID.to_string());
+            let invite_token = _invite_token(&user, &org_id, &org_user_id);
+            if let Err(e) = mail::send_invite(&email, &org_id, &org_user_id, &invite_token, &org_name, &conn) {
+                err!(format!("There has been a problem sending the email: {}", e))
             }
         }
     }
@@ -514,6 +597,22 @@ fn send_invite(org_id: String, data: JsonUpcase<InviteData>, headers: AdminHeade
     Ok(())
 }
 
+/// Creates the token that lets the invited user join the organization, it expires with the invitation
+fn _invite_token(user: &User, org_id: &str, org_user_id: &str) -> String {
+    let time_now = Utc::now().naive_utc();
+    let claims = InviteJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::hours(CONFIG.invitation_expiration_hours)).timestamp(),
+        iss: JWT_ISSUER.to_string(),
+        sub: user.uuid.to_string(),
+        email: user.email.clone(),
+        org_id: org_id.to_string(),
+        user_org_id: if org_id == Organization::VIRTUAL_ID { None } else { Some(org_user_id.to_string()) },
+    };
+
+    encode_jwt(&claims)
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct AcceptData {
@@ -545,7 +644,7 @@ fn accept_invite(_org_id: String, _org_user_id: String, data: JsonUpcase<AcceptD
                     }
                 }
             } else {
-                err!("Invitation for user not found")
+                err!("Invitation for user not found or expired")
             }
         },
         None => {
@@ -731,13 +830,179 @@ fn post_delete_user(org_id: String, org_user_id: String, headers: AdminHeaders,
     delete_user(org_id, org_user_id, headers, conn)
 }
 
-#[post("/organizations/<_org_id>/users/<_org_user_id>/reinvite")]
-fn post_reinvite_user(_org_id: String, _org_user_id: String, _headers: AdminHeaders, _conn: DbConn) -> EmptyResult {
-    err!("This functionality is not implemented. The user needs to manually register before they can be accepted into the organization.")
+#[post("/organizations/<org_id>/users/<org_user_id>/reinvite")]
+fn post_reinvite_user(org_id: String, org_user_id: String, headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    let user_org = match UserOrganization::find_by_uuid_and_org(&org_user_id, &org_id, &conn) {
+        Some(user_org) => user_org,
+        None => err!("The specified user isn't a member of the organization")
+    };
+
+    if user_org.type_ != UserOrgType::User &&
+        headers.org_user_type != UserOrgType::Owner {
+        err!("Only Owners can invite Managers, Admins or Owners")
+    }
+
+    if user_org.status != UserOrgStatus::Invited as i32 {
+        err!("The user already accepted the invitation")
+    }
+
+    let user = match User::find_by_uuid(&user_org.user_uuid, &conn) {
+        Some(user) => user,
+        None => err!("User not found")
+    };
+
+    let org_name = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => org.name,
+        None => err!("Error looking up organization")
+    };
+
+    let invite_token = _invite_token(&user, &org_id, &org_user_id);
+    if let Err(e) = Invitation::renew(&user.email, &conn) {
+        err!("Error renewing the invitation", e)
+    }
+
+    // Without SMTP, the admin has to send the link to the user some other way
+    if !CONFIG.mail_enabled() {
+        return Ok(Json(json!({
+            "EmailSent": false,
+            "InviteLink": mail::invite_link(&user.email, &org_id, &org_user_id, &invite_token, &org_name),
+        })));
+    }
+
+    if let Err(e) = mail::send_invite(&user.email, &org_id, &org_user_id, &invite_token, &org_name, &conn) {
+        err!(format!("There has been a problem sending the email: {}", e))
+    }
+
+    Ok(Json(json!({
+        "EmailSent": true,
+        "InviteLink": null,
+    })))
+}
+
+/// Lists the users that haven't accepted their invitation yet
+#[get("/organizations/<org_id>/invites")]
+fn get_invites(org_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    let invites: Vec<Value> = UserOrganization::find_by_org(&org_id, &conn)
+        .iter()
+        .filter(|user_org| user_org.status == UserOrgStatus::Invited as i32)
+        .filter_map(|user_org| {
+            let user = User::find_by_uuid(&user_org.user_uuid, &conn)?;
+            let invitation = Invitation::find_by_mail(&user.email, &conn);
+
+            Some(json!({
+                "Id": user_org.uuid,
+                "UserId": user.uuid,
+                "Email": user.email,
+                "Type": user_org.type_,
+                "Registered": !user.password_hash.is_empty(),
+                "Invitation": invitation.map(|i| i.to_json()),
+                "Object": "organizationInvite",
+            }))
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": invites,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+/// Cancels a pending invitation. If the user never registered, the placeholder account is removed as well
+#[delete("/organizations/<org_id>/invites/<org_user_id>")]
+fn revoke_invite(org_id: String, org_user_id: String, headers: AdminHeaders, conn: DbConn) -> EmptyResult {
+    let user_org = match UserOrganization::find_by_uuid_and_org(&org_user_id, &org_id, &conn) {
+        Some(user_org) => user_org,
+        None => err!("The specified user isn't a member of the organization")
+    };
+
+    if user_org.type_ != UserOrgType::User &&
+        headers.org_user_type != UserOrgType::Owner {
+        err!("Only Owners can revoke invitations of Managers, Admins or Owners")
+    }
+
+    if user_org.status != UserOrgStatus::Invited as i32 {
+        err!("The user already accepted the invitation")
+    }
+
+    let user_uuid = user_org.user_uuid.clone();
+    if user_org.delete(&conn).is_err() {
+        err!("Failed to revoke the invitation")
+    }
+
+    if let Some(user) = User::find_by_uuid(&user_uuid, &conn) {
+        remove_placeholder_user(user, &conn);
+    }
+
+    Ok(())
+}
+
+#[post("/organizations/<org_id>/invites/<org_user_id>/delete")]
+fn post_revoke_invite(org_id: String, org_user_id: String, headers: AdminHeaders, conn: DbConn) -> EmptyResult {
+    revoke_invite(org_id, org_user_id, headers, conn)
+}
+
+/// Deletes the account created when inviting a user that never registered, once it isn't invited anywhere
+fn remove_placeholder_user(user: User, conn: &DbConn) {
+    if !user.password_hash.is_empty() || !UserOrganization::find_invited_by_user(&user.uuid, conn).is_empty() {
+        return;
+    }
+
+    if let Some(invitation) = Invitation::find_by_mail(&user.email, conn) {
+        if let Err(e) = invitation.delete(conn) {
+            error!("Error deleting the invitation of {}: {:#?}", user.email, e);
+        }
+    }
+
+    let email = user.email.clone();
+    if let Err(e) = user.delete(conn) {
+        error!("Error deleting the placeholder account of {}: {:#?}", email, e);
+    }
+}
+
+/// Removes the expired invitations every hour, with the accounts of the users that never registered
+pub fn start_invitation_sweep(pool: Pool) {
+    crate::util::spawn_hourly_job("invitation-sweep", pool, sweep_invitations);
 }
 
-use super::ciphers::CipherData;
-use super::ciphers::update_cipher_from_data;
+fn sweep_invitations(conn: &DbConn) {
+    let invitations = match Invitation::find_expired(conn) {
+        Ok(invitations) => invitations,
+        Err(e) => {
+            error!("Error loading the expired invitations: {:#?}", e);
+            return;
+        }
+    };
+
+    for invitation in invitations {
+        let email = invitation.email;
+
+        // A new invite since the invitations were loaded renews it, and keeps the account
+        match Invitation::delete_expired(&email, conn) {
+            Ok(true) => (),
+            Ok(false) => continue,
+            Err(e) => {
+                error!("Error deleting the expired invitation of {}: {:#?}", email, e);
+                continue;
+            }
+        }
+
+        if let Some(user) = User::find_by_mail(&email, conn) {
+            // Every invite renews the invitation, so all the memberships of the placeholder account expired with it
+            if user.password_hash.is_empty() {
+                info!("Removing the expired invitation of {}", email);
+                if let Err(e) = user.delete(conn) {
+                    error!("Error deleting the placeholder account of {}: {:#?}", email, e);
+                }
+            }
+        }
+    }
+}
+
+use diesel::Connection;
+
+use super::ciphers::{CipherData, ImportErrors};
+use super::ciphers::{update_cipher_from_data, ImportSpool};
 
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
@@ -756,6 +1021,20 @@ struct RelationsData {
     Value: usize,
 }
 
+/// Asks the members of the organization to sync their vault once, instead of once per imported cipher
+fn notify_org_import(org_id: &str, conn: &DbConn, ws: &State<WebSocketUsers>) {
+    for member in UserOrganization::find_by_org(org_id, conn) {
+        if member.status != UserOrgStatus::Confirmed as i32 {
+            continue;
+        }
+        if let Some(mut user) = User::find_by_uuid(&member.user_uuid, conn) {
+            if user.update_revision(conn).is_ok() {
+                ws.send_user_update(UpdateType::SyncVault, &user);
+            }
+        }
+    }
+}
+
 #[post("/ciphers/import-organization?<query..>", data = "<data>")]
 fn post_org_import(query: Form<OrgIdData>, data: JsonUpcase<ImportData>, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
     let data: ImportData = data.into_inner().data;
@@ -770,47 +1049,324 @@ fn post_org_import(query: Form<OrgIdData>, data: JsonUpcase<ImportData>, headers
         err!("Only admins or owners can import into an organization")
     }
 
-    // Read and create the collections
-    let collections: Vec<_> = data.Collections.into_iter().map(|coll| {
-        let mut collection = Collection::new(org_id.clone(), coll.Name);
-        if collection.save(&conn).is_err() {
-            err!("Failed to create Collection");
+    // Check the relations between collections and ciphers before touching the database
+    let mut errors = ImportErrors::default();
+    for (index, relation) in data.CollectionRelationships.iter().enumerate() {
+        let key = format!("CollectionRelationships[{}]", index);
+        if relation.Key >= data.Ciphers.len() {
+            errors.add(key.clone(), format!("Cipher index {} is out of range", relation.Key));
+        }
+        if relation.Value >= data.Collections.len() {
+            errors.add(key, format!("Collection index {} is out of range", relation.Value));
+        }
+    }
+
+    let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+        // Read and create the collections
+        let mut collections = Vec::new();
+        for (index, coll) in data.Collections.into_iter().enumerate() {
+            let mut collection = Collection::new(org_id.clone(), coll.Name);
+            if let Err(e) = collection.save(&conn) {
+                errors.add(format!("Collections[{}]", index), format!("Failed to save collection: {}", e));
+            }
+            collections.push(collection);
+        }
+
+        // Read and create the ciphers
+        let mut ciphers = Vec::new();
+        for (index, cipher_data) in data.Ciphers.into_iter().enumerate() {
+            let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
+            if let Err(e) = update_cipher_from_data(&mut cipher, cipher_data, &headers, false, &conn, &ws, None) {
+                errors.add_api_error(format!("Ciphers[{}]", index), e);
+            }
+            ciphers.push(cipher);
         }
-        
-        Ok(collection)
-    }).collect();
 
-    // Read the relations between collections and ciphers
-    let mut relations = Vec::new();
-    for relation in data.CollectionRelationships {
-        relations.push((relation.Key, relation.Value));
+        // Assign the collections, the relations that failed validation are already reported
+        for (index, relation) in data.CollectionRelationships.iter().enumerate() {
+            let (cipher, coll) = match (ciphers.get(relation.Key), collections.get(relation.Value)) {
+                (Some(cipher), Some(coll)) => (cipher, coll),
+                _ => continue,
+            };
+
+            if let Err(e) = CollectionCipher::save(&cipher.uuid, &coll.uuid, &conn) {
+                errors.add(format!("CollectionRelationships[{}]", index), format!("Failed to add cipher to collection: {}", e));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(diesel::result::Error::RollbackTransaction)
+        }
+    });
+
+    if !errors.is_empty() {
+        return errors.into_result();
+    }
+    if let Err(e) = result {
+        err!("Failed to import into the organization, nothing was imported", e)
     }
 
-    // Read and create the ciphers
-    let ciphers: Vec<_> = data.Ciphers.into_iter().map(|cipher_data| {
-        let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
-        update_cipher_from_data(&mut cipher, cipher_data, &headers, false, &conn, &ws, UpdateType::SyncCipherCreate).ok();
-        cipher
-    }).collect();
+    notify_org_import(&org_id, &conn, &ws);
+    Ok(())
+}
 
-    // Assign the collections
-    for (cipher_index, coll_index) in relations {
-        let cipher_id = &ciphers[cipher_index].uuid;
-        let coll = &collections[coll_index];
-        let coll_id = match coll {
-            Ok(coll) => coll.uuid.as_str(),
-            Err(_) => err!("Failed to assign to collection")
-        };
-        
-        match CollectionCipher::save(cipher_id, coll_id, &conn) {
-            Ok(()) => (),
-            Err(_) => err!("Failed to add cipher to collection")
-        };
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ImportStreamItem {
+    Collection: Option<NewCollectionData>,
+    Cipher: Option<CipherData>,
+    // Positions of the collections among the collections of the import
+    CollectionIndexes: Option<Vec<usize>>,
+}
+
+#[post("/ciphers/import-organization-stream?<query..>", data = "<data>")]
+fn post_org_import_stream(query: Form<OrgIdData>, data: Data, headers: Headers, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    let org_id = query.into_inner().organization_id;
+
+    let org_user = match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, &conn) {
+        Some(user) => user,
+        None => err!("User is not part of the organization")
+    };
+
+    if org_user.type_ < UserOrgType::Admin {
+        err!("Only admins or owners can import into an organization")
+    }
+
+    let spool = match ImportSpool::new(data) {
+        Ok(spool) => spool,
+        Err(e) => err!(e),
+    };
+    let mut errors = ImportErrors::default();
+
+    let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+        // Only the ids of the collections are kept, the items are saved as soon as they are read
+        let mut collections: Vec<String> = Vec::new();
+
+        let read = spool.read_lines(|line, item: Result<ImportStreamItem, String>| {
+            let key = format!("Line {}", line);
+            let item = match item {
+                Ok(item) => item,
+                Err(e) => return errors.add(key, e),
+            };
+
+            match (item.Collection, item.Cipher) {
+                (Some(coll), None) => {
+                    let mut collection = Collection::new(org_id.clone(), coll.Name);
+                    if let Err(e) = collection.save(&conn) {
+                        errors.add(key, format!("Failed to save collection: {}", e));
+                    }
+                    collections.push(collection.uuid);
+                }
+                (None, Some(cipher_data)) => {
+                    let mut coll_uuids = Vec::new();
+                    for index in item.CollectionIndexes.unwrap_or_default() {
+                        match collections.get(index) {
+                            Some(uuid) => coll_uuids.push(uuid),
+                            None => return errors.add(key, format!("Collection index {} is out of range, collections must come before their ciphers", index)),
+                        }
+                    }
+
+                    let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
+                    if let Err(e) = update_cipher_from_data(&mut cipher, cipher_data, &headers, false, &conn, &ws, None) {
+                        return errors.add_api_error(key, e);
+                    }
+
+                    for coll_uuid in coll_uuids {
+                        if let Err(e) = CollectionCipher::save(&cipher.uuid, coll_uuid, &conn) {
+                            errors.add(key.clone(), format!("Failed to add cipher to collection: {}", e));
+                        }
+                    }
+                }
+                _ => errors.add(key, "Each line must contain either a Collection or a Cipher".into()),
+            }
+        });
+
+        if let Err(e) = read {
+            errors.add("Body".into(), e);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(diesel::result::Error::RollbackTransaction)
+        }
+    });
+
+    if !errors.is_empty() {
+        return errors.into_result();
+    }
+    if let Err(e) = result {
+        err!("Failed to import into the organization, nothing was imported", e)
+    }
+
+    notify_org_import(&org_id, &conn, &ws);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgKeyData {
+    EncryptedPrivateKey: String,
+    PublicKey: String,
+}
+
+fn _org_keys_json(org: &Organization) -> Value {
+    json!({
+        "PublicKey": org.public_key,
+        "PrivateKey": org.private_key,
+        "Object": "organizationKeys",
+    })
+}
+
+/// The members need the public key to enroll in the account recovery
+#[get("/organizations/<org_id>/keys")]
+fn get_organization_keys(org_id: String, _headers: OrgHeaders, conn: DbConn) -> JsonResult {
+    match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => Ok(Json(_org_keys_json(&org))),
+        None => err!("Can't find organization details"),
     }
+}
+
+/// Organizations created before the account recovery get their key pair here, it can't be replaced later
+#[post("/organizations/<org_id>/keys", data = "<data>")]
+fn post_organization_keys(org_id: String, data: JsonUpcase<OrgKeyData>, _headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+    let data: OrgKeyData = data.into_inner().data;
+
+    let mut org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(organization) => organization,
+        None => err!("Can't find organization details"),
+    };
+
+    if org.private_key.is_some() || org.public_key.is_some() {
+        err!("Organization keys already exist")
+    }
+
+    org.private_key = Some(data.EncryptedPrivateKey);
+    org.public_key = Some(data.PublicKey);
+
+    match org.save(&conn) {
+        Ok(()) => Ok(Json(_org_keys_json(&org))),
+        Err(_) => err!("Failed to save organization keys"),
+    }
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ResetPasswordEnrollmentData {
+    MasterPasswordHash: String,
+    ResetPasswordKey: Option<String>,
+}
 
-    let mut user = headers.user;
-    match user.update_revision(&conn) {
+/// The key is the user key, encrypted with the organization public key. Sending `null` withdraws from the account recovery
+#[put("/organizations/<org_id>/users/<user_id>/reset-password-enrollment", data = "<data>")]
+fn put_reset_password_enrollment(org_id: String, user_id: String, data: JsonUpcase<ResetPasswordEnrollmentData>, headers: OrgHeaders, conn: DbConn) -> EmptyResult {
+    let data: ResetPasswordEnrollmentData = data.into_inner().data;
+
+    if user_id != headers.user.uuid {
+        err!("Users can only enroll themselves")
+    }
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    let org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(organization) => organization,
+        None => err!("Can't find organization details"),
+    };
+
+    if data.ResetPasswordKey.is_some() && org.public_key.is_none() {
+        err!("The organization doesn't have keys for the account recovery")
+    }
+
+    let mut user_org = match UserOrganization::find_by_user_and_org(&user_id, &org_id, &conn) {
+        Some(user_org) => user_org,
+        None => err!("The user isn't a member of the organization"),
+    };
+
+    user_org.reset_password_key = data.ResetPasswordKey;
+
+    match user_org.save(&conn) {
         Ok(()) => Ok(()),
-        Err(_) => err!("Failed to update the revision, please log out and log back in to finish import.")
+        Err(_) => err!("Failed to save the account recovery enrollment"),
+    }
+}
+
+/// Finds the member whose password is reset, admins can't reset the password of owners or their own
+fn _find_reset_password_user(org_id: &str, org_user_id: &str, headers: &AdminHeaders, conn: &DbConn) -> ApiResult<(UserOrganization, User)> {
+    let user_org = match UserOrganization::find_by_uuid_and_org(org_user_id, org_id, conn) {
+        Some(user_org) => user_org,
+        None => err!("The specified user isn't a member of the organization"),
+    };
+
+    if user_org.status != UserOrgStatus::Confirmed as i32 || user_org.reset_password_key.is_none() {
+        err!("The user isn't enrolled in the account recovery")
     }
+
+    if user_org.type_ == UserOrgType::Owner && headers.org_user_type != UserOrgType::Owner {
+        err!("Only owners can recover the account of other owners")
+    }
+
+    if user_org.user_uuid == headers.user.uuid {
+        err!("Use the master password change to update your own password")
+    }
+
+    match User::find_by_uuid(&user_org.user_uuid, conn) {
+        Some(user) => Ok((user_org, user)),
+        None => err!("User doesn't exist"),
+    }
+}
+
+#[get("/organizations/<org_id>/users/<org_user_id>/reset-password-details")]
+fn get_reset_password_details(org_id: String, org_user_id: String, headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    let (user_org, user) = _find_reset_password_user(&org_id, &org_user_id, &headers, &conn)?;
+
+    let org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(organization) => organization,
+        None => err!("Can't find organization details"),
+    };
+
+    Ok(Json(json!({
+        "Kdf": user.client_kdf_type,
+        "KdfIterations": user.client_kdf_iter,
+        "KdfMemory": user.client_kdf_memory,
+        "KdfParallelism": user.client_kdf_parallelism,
+        "ResetPasswordKey": user_org.reset_password_key,
+        "EncryptedPrivateKey": org.private_key,
+        "Object": "organizationUserResetPasswordDetails",
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ResetPasswordData {
+    NewMasterPasswordHash: String,
+    Key: String,
+}
+
+/// Sets a new master password for the member, the key is the same user key encrypted with the new master key
+#[put("/organizations/<org_id>/users/<org_user_id>/reset-password", data = "<data>")]
+fn put_reset_password(org_id: String, org_user_id: String, data: JsonUpcase<ResetPasswordData>, headers: AdminHeaders, conn: DbConn, ws: State<WebSocketUsers>) -> EmptyResult {
+    let data: ResetPasswordData = data.into_inner().data;
+    let (_, mut user) = _find_reset_password_user(&org_id, &org_user_id, &headers, &conn)?;
+
+    user.set_password(&data.NewMasterPasswordHash);
+    user.key = data.Key;
+    user.reset_security_stamp();
+
+    if user.save(&conn).is_err() {
+        err!("Failed to save password")
+    }
+
+    revoke_sessions(&user, &conn, &ws);
+
+    notify_account_change(
+        &user.email,
+        &format!("Your master password was reset by the organization administrator {}.", headers.user.email),
+        &conn,
+    );
+
+    Ok(())
 }
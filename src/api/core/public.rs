@@ -0,0 +1,70 @@
+use rocket::Route;
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::db::DbConn;
+use crate::db::models::*;
+
+use crate::api::JsonResult;
+use crate::auth::OrgApiHeaders;
+
+use crate::CONFIG;
+
+// Endpoints available to organization API keys, the user endpoints reject their tokens
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_organization,
+        get_collections,
+        get_members,
+        get_ciphers,
+    ]
+}
+
+#[get("/public/organization")]
+fn get_organization(headers: OrgApiHeaders) -> JsonResult {
+    Ok(Json(headers.org.to_json()))
+}
+
+#[get("/public/collections")]
+fn get_collections(headers: OrgApiHeaders, conn: DbConn) -> JsonResult {
+    let collections = Collection::find_by_organization(&headers.org.uuid, &conn);
+    let collections_json: Vec<Value> = collections.iter().map(Collection::to_json).collect();
+
+    Ok(Json(json!({
+        "Data": collections_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[get("/public/members")]
+fn get_members(headers: OrgApiHeaders, conn: DbConn) -> JsonResult {
+    let users = UserOrganization::find_by_org(&headers.org.uuid, &conn);
+    let users_json: Vec<Value> = users.iter().map(|u| u.to_json_user_details(&conn)).collect();
+
+    Ok(Json(json!({
+        "Data": users_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[get("/public/ciphers")]
+fn get_ciphers(headers: OrgApiHeaders, conn: DbConn) -> JsonResult {
+    let ciphers = Cipher::find_by_org(&headers.org.uuid, &conn);
+    let ciphers_json: Vec<Value> = ciphers
+        .iter()
+        .map(|c| {
+            // There is no user, so the folders don't apply and all the collections are visible
+            let mut cipher_json = c.to_json(&CONFIG.domain, "", &conn);
+            cipher_json["CollectionIds"] = json!(c.get_all_collections(&conn));
+            cipher_json
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": ciphers_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
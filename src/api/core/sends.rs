@@ -0,0 +1,447 @@
+use std::io::Read;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use data_encoding::HEXLOWER;
+use rocket::http::ContentType;
+use rocket::response::NamedFile;
+use rocket::{Data, Route};
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use multipart::server::save::SavedData;
+use multipart::server::{Multipart, SaveResult};
+
+use crate::api::{ApiResult, EmptyResult, JsonResult, JsonUpcase, NumberOrString};
+use crate::auth::{decode_send_file_jwt, encode_jwt, Headers, SendFileJWTClaims, JWT_ISSUER};
+use crate::crypto;
+use crate::db::models::*;
+use crate::db::{DbConn, Pool};
+use crate::util::UpCase;
+use crate::CONFIG;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_sends,
+        get_send,
+        post_send,
+        post_send_file,
+        put_send,
+        delete_send,
+        put_remove_password,
+        post_access,
+        post_access_file,
+        download_send,
+    ]
+}
+
+/// Sends can't be kept for longer than this, they are meant to be ephemeral
+const SEND_MAX_DAYS: i64 = 31;
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+pub struct SendData {
+    // Only included in the key rotation
+    pub Id: Option<String>,
+    Type: i32,
+    Key: String,
+    Password: Option<String>,
+    MaxAccessCount: Option<NumberOrString>,
+    ExpirationDate: Option<String>,
+    DeletionDate: String,
+    Disabled: bool,
+
+    Name: String,
+    Notes: Option<String>,
+    Text: Option<Value>,
+    File: Option<Value>,
+}
+
+fn _parse_date(date: &str) -> ApiResult<NaiveDateTime> {
+    match DateTime::parse_from_rfc3339(date) {
+        Ok(date) => Ok(date.with_timezone(&Utc).naive_utc()),
+        Err(_) => err!(format!("Invalid date: {}", date)),
+    }
+}
+
+fn _sends_allowed() -> EmptyResult {
+    if !CONFIG.sends_allowed {
+        err!("Sends are disabled on this server")
+    }
+    Ok(())
+}
+
+/// Applies the settings shared by the creation and the update of a send
+fn _update_send_from_data(send: &mut Send, data: SendData) -> EmptyResult {
+    let deletion_date = _parse_date(&data.DeletionDate)?;
+    if deletion_date > Utc::now().naive_utc() + Duration::days(SEND_MAX_DAYS) {
+        err!(format!("The deletion date can't be more than {} days from now", SEND_MAX_DAYS))
+    }
+
+    send.expiration_date = match data.ExpirationDate {
+        Some(ref date) => Some(_parse_date(date)?),
+        None => None,
+    };
+    send.max_access_count = match data.MaxAccessCount {
+        Some(count) => match count.into_i32() {
+            Some(count) if count > 0 => Some(count),
+            _ => err!("The maximum access count must be a positive number"),
+        },
+        None => None,
+    };
+
+    send.name = data.Name;
+    send.notes = data.Notes;
+    send.key = data.Key;
+    send.deletion_date = deletion_date;
+    send.disabled = data.Disabled;
+
+    // An update without password keeps the current one, it's removed with its own endpoint
+    if let Some(ref password) = data.Password {
+        send.set_password(Some(password));
+    }
+
+    Ok(())
+}
+
+/// Applies the changes to an existing send, also used by the key rotation
+pub fn update_send_from_data(send: &mut Send, mut data: SendData) -> EmptyResult {
+    if send.type_ != data.Type {
+        err!("Sends can't change type")
+    }
+
+    // The description of a file is kept, the file itself can't be replaced
+    if send.type_ == SendType::Text as i32 {
+        send.data = _send_data_json(data.Text.take())?;
+    }
+    _update_send_from_data(send, data)
+}
+
+/// The client includes the decrypted value in `Response`, which must never be stored
+fn _send_data_json(data: Option<Value>) -> ApiResult<String> {
+    match data {
+        Some(mut data) => {
+            if let Some(object) = data.as_object_mut() {
+                object.remove("Response");
+            }
+            Ok(data.to_string())
+        }
+        None => err!("Send data not provided"),
+    }
+}
+
+fn _create_send(mut data: SendData, user_uuid: &str) -> ApiResult<Send> {
+    let (type_, send_data) = match data.Type {
+        0 => (SendType::Text, _send_data_json(data.Text.take())?),
+        1 => (SendType::File, _send_data_json(data.File.take())?),
+        _ => err!("Invalid send type"),
+    };
+
+    let mut send = Send::new(type_, String::new(), send_data, String::new(), Utc::now().naive_utc());
+    send.user_uuid = user_uuid.to_string();
+    _update_send_from_data(&mut send, data)?;
+
+    Ok(send)
+}
+
+fn _find_owned_send(uuid: &str, headers: &Headers, conn: &DbConn) -> ApiResult<Send> {
+    let send = match Send::find_by_uuid(uuid, conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    if send.user_uuid != headers.user.uuid {
+        err!("Send is not owned by user")
+    }
+    Ok(send)
+}
+
+#[get("/sends")]
+fn get_sends(headers: Headers, conn: DbConn) -> JsonResult {
+    let sends_json: Vec<Value> = Send::find_by_user(&headers.user.uuid, &conn).iter().map(Send::to_json).collect();
+
+    Ok(Json(json!({
+      "Data": sends_json,
+      "Object": "list",
+      "ContinuationToken": null
+    })))
+}
+
+#[get("/sends/<uuid>")]
+fn get_send(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let send = _find_owned_send(&uuid, &headers, &conn)?;
+    Ok(Json(send.to_json()))
+}
+
+#[post("/sends", data = "<data>")]
+fn post_send(data: JsonUpcase<SendData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _sends_allowed()?;
+    let data: SendData = data.into_inner().data;
+
+    if data.Type == SendType::File as i32 {
+        err!("File sends need to be created with /api/sends/file")
+    }
+
+    let mut send = _create_send(data, &headers.user.uuid)?;
+    if send.save(&conn).is_err() {
+        err!("Failed to save send")
+    }
+
+    Ok(Json(send.to_json()))
+}
+
+/// The multipart body has a `model` field with the send data, followed by the encrypted file in `data`
+#[post("/sends/file", format = "multipart/form-data", data = "<data>")]
+fn post_send_file(data: Data, content_type: &ContentType, headers: Headers, conn: DbConn) -> JsonResult {
+    _sends_allowed()?;
+
+    let boundary = match content_type.params().find(|(name, _)| *name == "boundary") {
+        Some((_, boundary)) => boundary.to_string(),
+        None => err!("No boundary provided"),
+    };
+
+    let mut send: Option<Send> = None;
+    let mut result: EmptyResult = Ok(());
+
+    Multipart::with_body(data.open(), boundary).foreach_entry(|mut field| {
+        if result.is_err() {
+            return;
+        }
+
+        match field.headers.name.as_str() {
+            "model" => {
+                let mut model = String::new();
+                if field.data.read_to_string(&mut model).is_err() {
+                    result = _send_error("Error reading the send model");
+                    return;
+                }
+
+                result = match serde_json::from_str::<UpCase<SendData>>(&model) {
+                    Ok(model) => {
+                        if model.data.Type == SendType::File as i32 {
+                            _create_send(model.data, &headers.user.uuid).map(|created| send = Some(created))
+                        } else {
+                            _send_error("The send is not a file")
+                        }
+                    }
+                    Err(e) => _send_error(&format!("Invalid send model: {}", e)),
+                };
+            }
+            "data" => {
+                let send = match send.as_mut() {
+                    Some(send) => send,
+                    None => {
+                        result = _send_error("The send model must come before the file");
+                        return;
+                    }
+                };
+
+                let file_id = HEXLOWER.encode(&crypto::get_random(vec![0; 10]));
+                let path = send.get_file_path(&file_id);
+
+                let size = match field.data.save()
+                    .memory_threshold(0)
+                    .size_limit(None)
+                    .with_path(path) {
+                    SaveResult::Full(SavedData::File(_, size)) => size as i32,
+                    SaveResult::Full(other) => {
+                        error!("Send file is not a file: {:?}", other);
+                        result = _send_error("Error saving the send file");
+                        return;
+                    },
+                    SaveResult::Partial(_, reason) => {
+                        error!("Partial result: {:?}", reason);
+                        result = _send_error("Error saving the send file");
+                        return;
+                    },
+                    SaveResult::Error(e) => {
+                        error!("Error: {:?}", e);
+                        result = _send_error("Error saving the send file");
+                        return;
+                    }
+                };
+
+                let mut file_data: Value = serde_json::from_str(&send.data).unwrap_or_else(|_| json!({}));
+                file_data["Id"] = json!(file_id);
+                file_data["Size"] = json!(size.to_string());
+                file_data["SizeName"] = json!(crate::util::get_display_size(size));
+                send.data = file_data.to_string();
+            }
+            _ => error!("Invalid multipart name"),
+        }
+    }).expect("Error processing multipart data");
+
+    result?;
+
+    let mut send = match send {
+        Some(send) => send,
+        None => err!("No send model provided"),
+    };
+    if send.file_id().is_none() {
+        err!("No file provided")
+    }
+
+    if send.save(&conn).is_err() {
+        err!("Failed to save send")
+    }
+
+    Ok(Json(send.to_json()))
+}
+
+fn _send_error(msg: &str) -> EmptyResult {
+    err!(msg)
+}
+
+#[put("/sends/<uuid>", data = "<data>")]
+fn put_send(uuid: String, data: JsonUpcase<SendData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _sends_allowed()?;
+    let data: SendData = data.into_inner().data;
+
+    let mut send = _find_owned_send(&uuid, &headers, &conn)?;
+    update_send_from_data(&mut send, data)?;
+
+    if send.save(&conn).is_err() {
+        err!("Failed to save send")
+    }
+
+    Ok(Json(send.to_json()))
+}
+
+#[delete("/sends/<uuid>")]
+fn delete_send(uuid: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    let send = _find_owned_send(&uuid, &headers, &conn)?;
+
+    if send.delete(&conn).is_err() {
+        err!("Failed to delete send")
+    }
+
+    Ok(())
+}
+
+#[put("/sends/<uuid>/remove-password")]
+fn put_remove_password(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let mut send = _find_owned_send(&uuid, &headers, &conn)?;
+
+    send.set_password(None);
+    if send.save(&conn).is_err() {
+        err!("Failed to save send")
+    }
+
+    Ok(Json(send.to_json()))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct SendAccessData {
+    Password: Option<String>,
+}
+
+/// Checks that the send can be accessed by an anonymous recipient
+fn _check_access(send: &Send, password: Option<String>) -> EmptyResult {
+    _sends_allowed()?;
+
+    if let Err(msg) = send.check_available() {
+        err!(msg)
+    }
+
+    if send.password_hash.is_some() {
+        match password {
+            Some(ref password) if send.check_password(password) => (),
+            Some(_) => err!("Invalid password"),
+            None => err!("Password not provided"),
+        }
+    }
+
+    Ok(())
+}
+
+#[post("/sends/access/<access_id>", data = "<data>")]
+fn post_access(access_id: String, data: JsonUpcase<SendAccessData>, conn: DbConn) -> JsonResult {
+    let data: SendAccessData = data.into_inner().data;
+
+    let mut send = match Send::find_by_access_id(&access_id, &conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    _check_access(&send, data.Password)?;
+
+    // The files are counted when they are downloaded
+    if !send.is_file() {
+        send.access_count += 1;
+        if send.save(&conn).is_err() {
+            err!("Failed to save send")
+        }
+    }
+
+    Ok(Json(send.to_json_access()))
+}
+
+#[post("/sends/<uuid>/access/file/<file_id>", data = "<data>")]
+fn post_access_file(uuid: String, file_id: String, data: JsonUpcase<SendAccessData>, conn: DbConn) -> JsonResult {
+    let data: SendAccessData = data.into_inner().data;
+
+    let mut send = match Send::find_by_uuid(&uuid, &conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    if send.file_id().as_ref() != Some(&file_id) {
+        err!("Send file not found")
+    }
+
+    _check_access(&send, data.Password)?;
+
+    send.access_count += 1;
+    if send.save(&conn).is_err() {
+        err!("Failed to save send")
+    }
+
+    // The download link is only valid for a short time, so it can't be used to skip the access count
+    let time_now = Utc::now().naive_utc();
+    let claims = SendFileJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::minutes(2)).timestamp(),
+        iss: JWT_ISSUER.to_string(),
+        sub: send.uuid.clone(),
+        file_id: file_id.clone(),
+    };
+    let token = encode_jwt(&claims);
+
+    Ok(Json(json!({
+        "Id": file_id,
+        "Url": format!("{}/api/sends/{}/{}?t={}", CONFIG.domain, send.uuid, file_id, token),
+        "Object": "send-fileDownload",
+    })))
+}
+
+#[get("/sends/<uuid>/<file_id>?<t>")]
+fn download_send(uuid: String, file_id: String, t: String) -> Option<NamedFile> {
+    let claims = decode_send_file_jwt(&t).ok()?;
+    if claims.sub != uuid || claims.file_id != file_id {
+        return None;
+    }
+
+    NamedFile::open(Path::new(&CONFIG.sends_folder).join(&uuid).join(&file_id)).ok()
+}
+
+/// Deletes the sends that are past their deletion date, with their files, every hour
+pub fn start_send_purge(pool: Pool) {
+    crate::util::spawn_hourly_job("send-purge", pool, purge_sends);
+}
+
+fn purge_sends(conn: &DbConn) {
+    let sends = match Send::find_deleted(conn) {
+        Ok(sends) => sends,
+        Err(e) => {
+            error!("Error loading the sends to delete: {:#?}", e);
+            return;
+        }
+    };
+
+    for send in sends {
+        if let Err(e) = send.delete(conn) {
+            error!("Error deleting send {}: {:#?}", send.uuid, e);
+        }
+    }
+}
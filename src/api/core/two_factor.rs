@@ -11,8 +11,9 @@ use crate::db::{
 
 use crate::crypto;
 
-use crate::api::{ApiResult, JsonResult, JsonUpcase, NumberOrString, PasswordData};
-use crate::auth::Headers;
+use crate::api::{ApiResult, JsonResult, JsonUpcase, NumberOrString, PasswordData, notify_account_change};
+use crate::auth::{Headers, ClientIp};
+use crate::mail;
 
 use rocket::Route;
 
@@ -71,7 +72,7 @@ struct RecoverTwoFactor {
 }
 
 #[post("/two-factor/recover", data = "<data>")]
-fn recover(data: JsonUpcase<RecoverTwoFactor>, conn: DbConn) -> JsonResult {
+fn recover(data: JsonUpcase<RecoverTwoFactor>, conn: DbConn, ip: ClientIp) -> JsonResult {
     let data: RecoverTwoFactor = data.into_inner().data;
 
     use crate::db::models::User;
@@ -99,10 +100,19 @@ fn recover(data: JsonUpcase<RecoverTwoFactor>, conn: DbConn) -> JsonResult {
 
     // Remove the recovery code, not needed without twofactors
     user.totp_recover = None;
-    match user.save(&conn) {
-        Ok(()) => Ok(Json(json!({}))),
-        Err(_) => err!("Failed to remove the user's two factor recovery code")
+    if user.save(&conn).is_err() {
+        err!("Failed to remove the user's two factor recovery code")
     }
+
+    if CONFIG.security_notifications {
+        if CONFIG.mail_enabled() {
+            if let Err(e) = mail::send_twofactor_recovered(&user.email, &ip.ip.to_string(), &conn) {
+                error!("Error sending two factor recovery email: {}", e);
+            }
+        }
+    }
+
+    Ok(Json(json!({})))
 }
 
 #[derive(Deserialize)]
@@ -129,6 +139,7 @@ fn disable_twofactor(
 
     if let Some(twofactor) = TwoFactor::find_by_user_and_type(&headers.user.uuid, type_, &conn) {
         twofactor.delete(&conn).expect("Error deleting twofactor");
+        notify_account_change(&headers.user.email, "A two-step login provider was disabled.", &conn);
     }
 
     Ok(Json(json!({
@@ -221,6 +232,7 @@ fn activate_authenticator(
     let mut user = headers.user;
     _generate_recover_code(&mut user, &conn);
     twofactor.save(&conn).expect("Error saving twofactor");
+    notify_account_change(&user.email, "Authenticator app two-step login was enabled.", &conn);
 
     Ok(Json(json!({
         "Enabled": true,
@@ -387,6 +399,7 @@ fn activate_u2f(data: JsonUpcase<EnableU2FData>, headers: Headers, conn: DbConn)
 
                 let mut user = headers.user;
                 _generate_recover_code(&mut user, &conn);
+                notify_account_change(&user.email, "U2F two-step login was enabled.", &conn);
 
                 Ok(Json(json!({
                     "Enabled": true,
@@ -688,6 +701,7 @@ fn activate_yubikey(data: JsonUpcase<EnableYubikeyData>, headers: Headers, conn:
     );
     yubikey_registration
         .save(&conn).expect("Failed to save Yubikey info");
+    notify_account_change(&headers.user.email, "YubiKey OTP two-step login was enabled.", &conn);
 
     let mut result = jsonify_yubikeys(yubikey_metadata.Keys);
 
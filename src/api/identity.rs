@@ -1,34 +1,42 @@
-use rocket::request::{Form, FormItems, FromForm};
-use rocket::Route;
+use rocket::request::{Form, FormItems, FromForm, LenientForm};
+use rocket::response::Redirect;
+use rocket::{Route, State};
 
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
 use num_traits::FromPrimitive;
 
+use chrono::{Duration, Utc};
+
+use reqwest::Url;
+
 use crate::db::models::*;
 use crate::db::DbConn;
 
 use crate::util::{self, JsonMap};
 
-use crate::api::{ApiResult, EmptyResult, JsonResult};
+use crate::api::{ApiResult, EmptyResult, JsonResult, WebSocketUsers};
 
+use crate::api::core::accounts::send_verification_email;
 use crate::auth::ClientIp;
+use crate::mail;
+use crate::sso;
 
 use crate::CONFIG;
 
 pub fn routes() -> Vec<Route> {
-    routes![login]
+    routes![login, sso_authorize, sso_callback]
 }
 
 #[post("/connect/token", data = "<data>")]
-fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp) -> JsonResult {
+fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp, ws: State<WebSocketUsers>) -> JsonResult {
     let data: ConnectData = data.into_inner();
 
     match data.grant_type.as_ref() {
         "refresh_token" => {
             _check_is_some(&data.refresh_token, "refresh_token cannot be blank")?;
-            _refresh_login(data, conn)
+            _refresh_login(data, conn, &ws)
         }
         "password" => {
             _check_is_some(&data.client_id, "client_id cannot be blank")?;
@@ -42,20 +50,61 @@ fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp) -> JsonResult {
 
             _password_login(data, conn, ip)
         }
+        "authorization_code" => {
+            _check_is_some(&data.code, "code cannot be blank")?;
+            _check_is_some(&data.code_verifier, "code_verifier cannot be blank")?;
+            _check_is_some(&data.redirect_uri, "redirect_uri cannot be blank")?;
+
+            _check_is_some(&data.device_identifier, "device_identifier cannot be blank")?;
+            _check_is_some(&data.device_name, "device_name cannot be blank")?;
+            _check_is_some(&data.device_type, "device_type cannot be blank")?;
+
+            _sso_login(data, conn, ip)
+        }
+        "client_credentials" => {
+            _check_is_some(&data.client_id, "client_id cannot be blank")?;
+            _check_is_some(&data.client_secret, "client_secret cannot be blank")?;
+            _check_is_some(&data.scope, "scope cannot be blank")?;
+
+            _api_key_login(data, conn, ip)
+        }
         t => err!("Invalid type", t),
     }
 }
 
-fn _refresh_login(data: ConnectData, conn: DbConn) -> JsonResult {
+fn _refresh_login(data: ConnectData, conn: DbConn, ws: &WebSocketUsers) -> JsonResult {
     // Extract token
     let token = data.refresh_token.unwrap();
 
+    // Devices that only used API keys have no refresh token
+    if token.is_empty() {
+        err!("Invalid refresh token")
+    }
+
     // Get device by refresh token
     let mut device = match Device::find_by_refresh_token(&token, &conn) {
         Some(device) => device,
-        None => err!("Invalid refresh token"),
+        None => {
+            // A refresh token that was already rotated may have been stolen, so the session is revoked
+            if let Some(device) = Device::find_by_previous_refresh_token(&token, &conn) {
+                warn!("Reuse of a rotated refresh token detected, revoking device {}", device.uuid);
+
+                let device_uuid = device.uuid.clone();
+                if let Some(user) = User::find_by_uuid(&device.user_uuid, &conn) {
+                    ws.send_device_logout(&user, &device_uuid);
+                }
+                if let Err(e) = device.delete(&conn) {
+                    error!("Error revoking device: {:#?}", e);
+                }
+            }
+            err!("Invalid refresh token")
+        }
     };
 
+    if device.is_refresh_token_expired() {
+        err!("Refresh token expired")
+    }
+
     // COMMON
     let user = User::find_by_uuid(&device.user_uuid, &conn).unwrap();
     let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
@@ -83,7 +132,7 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult
 
     // Get the user
     let username = data.username.as_ref().unwrap();
-    let user = match User::find_by_mail(username, &conn) {
+    let mut user = match User::find_by_mail(username, &conn) {
         Some(user) => user,
         None => err!(format!(
             "Username or password is incorrect. Try again. IP: {}. Username: {}.",
@@ -100,36 +149,88 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult
         ))
     }
 
+    // Upgrade the hash to the configured algorithm while the password is available
+    if user.password_needs_rehash() {
+        user.set_password(password);
+        match user.save(&conn) {
+            Ok(()) => info!("Upgraded the password hash of {}", user.email),
+            Err(e) => error!("Error upgrading the password hash of {}: {:#?}", user.email, e),
+        }
+    }
+
+    _check_email_verified(&mut user, &conn)?;
+
+    _authenticated_login(user, data, conn, ip)
+}
+
+/// Accounts created by self-registration can't be used until the email is verified, whatever the login method
+fn _check_email_verified(user: &mut User, conn: &DbConn) -> EmptyResult {
+    if CONFIG.signups_verify() && user.verified_at.is_none() {
+        let now = Utc::now().naive_utc();
+        let resend = match user.last_verifying_at {
+            Some(last) => now - last > Duration::hours(1),
+            None => true,
+        };
+
+        if resend {
+            if let Err(e) = send_verification_email(user, conn) {
+                error!("Error sending verification email: {}", e);
+            }
+        }
+
+        err!("Please verify your email before logging in, check your inbox for the verification link")
+    }
+
+    Ok(())
+}
+
+/// Starts a new session on the device once the user has been identified, by password or by single sign-on
+fn _authenticated_login(user: User, data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult {
     // On iOS, device_type sends "iOS", on others it sends a number
     let device_type = util::try_parse_string(data.device_type.as_ref()).unwrap_or(0);
     let device_id = data.device_identifier.clone().expect("No device id provided");
     let device_name = data.device_name.clone().expect("No device name provided");
 
     // Find device or create new
-    let mut device = match Device::find_by_uuid(&device_id, &conn) {
+    let (mut device, new_device) = match Device::find_by_uuid(&device_id, &conn) {
         Some(device) => {
             // Check if owned device, and recreate if not
             if device.user_uuid != user.uuid {
                 info!("Device exists but is owned by another user. The old device will be discarded");
-                Device::new(device_id, user.uuid.clone(), device_name, device_type)
+                (Device::new(device_id, user.uuid.clone(), device_name, device_type), true)
             } else {
-                device
+                (device, false)
             }
         }
-        None => Device::new(device_id, user.uuid.clone(), device_name, device_type),
+        None => (Device::new(device_id, user.uuid.clone(), device_name, device_type), true),
     };
 
     let twofactor_token = twofactor_auth(&user.uuid, &data.clone(), &mut device, &conn)?;
 
+    // A new login always starts a new refresh token lifetime
+    device.start_session();
+
     // Common
     let user = User::find_by_uuid(&device.user_uuid, &conn).unwrap();
     let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
 
+    let ip_addr = ip.ip.to_string();
+    let new_ip = Device::find_by_user_and_ip(&user.uuid, &ip_addr, &conn).is_none();
+    device.last_ip = Some(ip_addr.clone());
+
     let (access_token, expires_in) = device.refresh_tokens(&user, orgs);
     if let Err(e) = device.save(&conn) {
         err!("Failed to add device to user", e)
     }
 
+    if CONFIG.security_notifications && (new_device || new_ip) {
+        if CONFIG.mail_enabled() {
+            if let Err(e) = mail::send_new_device_logged_in(&user.email, &ip_addr, &device.updated_at, &device.name, &conn) {
+                error!("Error sending new device email: {}", e);
+            }
+        }
+    }
+
     let mut result = json!({
         "access_token": access_token,
         "expires_in": expires_in,
@@ -144,7 +245,7 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult
         result["TwoFactorToken"] = Value::String(token);
     }
 
-    info!("User {} logged in successfully. IP: {}", username, ip.ip);
+    info!("User {} logged in successfully. IP: {}", user.email, ip.ip);
     Ok(Json(result))
 }
 
@@ -278,7 +379,7 @@ fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &DbConn) -> Api
 #[derive(Debug, Clone, Default)]
 #[allow(non_snake_case)]
 struct ConnectData {
-    grant_type: String, // refresh_token, password
+    grant_type: String, // refresh_token, password, authorization_code, client_credentials
 
     // Needed for grant_type="refresh_token"
     refresh_token: Option<String>,
@@ -289,6 +390,14 @@ struct ConnectData {
     scope: Option<String>,
     username: Option<String>,
 
+    // Needed for grant_type="authorization_code"
+    code: Option<String>,
+    code_verifier: Option<String>,
+    redirect_uri: Option<String>,
+
+    // Needed for grant_type="client_credentials"
+    client_secret: Option<String>,
+
     device_identifier: Option<String>,
     device_name: Option<String>,
     device_type: Option<String>,
@@ -313,9 +422,13 @@ impl<'f> FromForm<'f> for ConnectData {
                 "granttype" => form.grant_type = value,
                 "refreshtoken" => form.refresh_token = Some(value),
                 "clientid" => form.client_id = Some(value),
+                "clientsecret" => form.client_secret = Some(value),
                 "password" => form.password = Some(value),
                 "scope" => form.scope = Some(value),
                 "username" => form.username = Some(value),
+                "code" => form.code = Some(value),
+                "codeverifier" => form.code_verifier = Some(value),
+                "redirecturi" => form.redirect_uri = Some(value),
                 "deviceidentifier" => form.device_identifier = Some(value),
                 "devicename" => form.device_name = Some(value),
                 "devicetype" => form.device_type = Some(value),
@@ -330,6 +443,282 @@ impl<'f> FromForm<'f> for ConnectData {
     }
 }
 
+fn _api_key_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult {
+    let client_id = data.client_id.as_ref().unwrap();
+    let client_secret = data.client_secret.as_ref().unwrap();
+    let scope = data.scope.as_ref().unwrap();
+
+    let api_key = match ApiKey::find_by_client_id(client_id, &conn) {
+        Some(api_key) => api_key,
+        None => err!(format!("Invalid API key. IP: {}. Client ID: {}.", ip.ip, client_id)),
+    };
+
+    if !api_key.check_secret(client_secret) {
+        err!(format!("Invalid API key. IP: {}. Client ID: {}.", ip.ip, client_id))
+    }
+
+    if scope != api_key.scope() {
+        err!("Scope not supported")
+    }
+
+    match (&api_key.user_uuid, &api_key.org_uuid) {
+        (Some(user_uuid), None) => _user_api_key_login(user_uuid, &api_key, &data, &conn),
+        (None, Some(org_uuid)) => _org_api_key_login(org_uuid, &api_key),
+        _ => err!("Invalid API key"),
+    }
+}
+
+fn _user_api_key_login(user_uuid: &str, api_key: &ApiKey, data: &ConnectData, conn: &DbConn) -> JsonResult {
+    _check_is_some(&data.device_identifier, "device_identifier cannot be blank")?;
+    _check_is_some(&data.device_name, "device_name cannot be blank")?;
+    _check_is_some(&data.device_type, "device_type cannot be blank")?;
+
+    let user = match User::find_by_uuid(user_uuid, conn) {
+        Some(user) => user,
+        None => err!("Invalid API key"),
+    };
+
+    let device_type = util::try_parse_string(data.device_type.as_ref()).unwrap_or(0);
+    let device_id = data.device_identifier.clone().unwrap();
+    let device_name = data.device_name.clone().unwrap();
+
+    let mut device = match Device::find_by_uuid(&device_id, conn) {
+        Some(device) => {
+            if device.user_uuid != user.uuid {
+                err!("Device exists but is owned by another user")
+            }
+            device
+        }
+        None => Device::new(device_id, user.uuid.clone(), device_name, device_type),
+    };
+
+    // API keys don't get a refresh token, the client authenticates again when the access token expires
+    let orgs = UserOrganization::find_by_user(&user.uuid, conn);
+    let (access_token, expires_in) = device.access_token(&user, orgs, vec![ApiKey::USER_SCOPE.into()], Some(api_key.revision));
+    if let Err(e) = device.save(conn) {
+        err!("Failed to add device to user", e)
+    }
+
+    Ok(Json(json!({
+        "access_token": access_token,
+        "expires_in": expires_in,
+        "token_type": "Bearer",
+        "scope": ApiKey::USER_SCOPE,
+        "Key": user.key,
+        "PrivateKey": user.private_key,
+    })))
+}
+
+fn _org_api_key_login(org_uuid: &str, api_key: &ApiKey) -> JsonResult {
+    use crate::auth::{encode_jwt, OrgApiKeyJWTClaims, DEFAULT_VALIDITY, JWT_ISSUER};
+
+    let time_now = Utc::now().naive_utc();
+    let claims = OrgApiKeyJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + *DEFAULT_VALIDITY).timestamp(),
+        iss: JWT_ISSUER.to_string(),
+        sub: org_uuid.to_string(),
+
+        client_id: api_key.client_id.clone(),
+        key_revision: api_key.revision,
+        scope: vec![ApiKey::ORG_SCOPE.into()],
+    };
+
+    Ok(Json(json!({
+        "access_token": encode_jwt(&claims),
+        "expires_in": DEFAULT_VALIDITY.num_seconds(),
+        "token_type": "Bearer",
+        "scope": ApiKey::ORG_SCOPE,
+    })))
+}
+
+#[derive(FromForm)]
+struct SsoAuthorizeData {
+    response_type: String,
+    redirect_uri: String,
+    state: Option<String>,
+    code_challenge: String,
+    code_challenge_method: String,
+}
+
+/// Starts a single sign-on login, the client is sent to the identity provider and comes back
+/// with an authorization code that it redeems at /connect/token with its PKCE code verifier
+#[get("/sso/authorize?<data..>")]
+fn sso_authorize(data: LenientForm<SsoAuthorizeData>, conn: DbConn) -> ApiResult<Redirect> {
+    let data = data.into_inner();
+
+    let config = match CONFIG.sso {
+        Some(ref config) => config,
+        None => err!("Single sign-on is disabled"),
+    };
+
+    if data.response_type != "code" {
+        err!("Response type not supported")
+    }
+
+    if data.code_challenge_method != "S256" {
+        err!("Code challenge method not supported")
+    }
+
+    if !_is_allowed_redirect(&data.redirect_uri) {
+        err!("Invalid redirect_uri", data.redirect_uri)
+    }
+
+    if let Err(e) = SsoAuth::delete_expired(&conn) {
+        error!("Error deleting expired SSO logins: {:#?}", e);
+    }
+
+    let auth = SsoAuth::new(data.redirect_uri, data.state, data.code_challenge);
+    let url = match sso::authorize_url(config, &auth) {
+        Ok(url) => url,
+        Err(e) => err!("Error contacting the identity provider", e),
+    };
+
+    match auth.save(&conn) {
+        Ok(()) => Ok(Redirect::to(url)),
+        Err(e) => err!("Error saving the SSO login", e),
+    }
+}
+
+#[derive(FromForm)]
+struct SsoCallbackData {
+    state: String,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+/// The identity provider sends the user back here after the login
+#[get("/sso/callback?<data..>")]
+fn sso_callback(data: LenientForm<SsoCallbackData>, conn: DbConn) -> ApiResult<Redirect> {
+    let data = data.into_inner();
+
+    let config = match CONFIG.sso {
+        Some(ref config) => config,
+        None => err!("Single sign-on is disabled"),
+    };
+
+    let mut auth = match SsoAuth::find_by_state(&data.state, &conn) {
+        Some(auth) => auth,
+        None => err!("Invalid SSO state"),
+    };
+
+    if auth.is_expired() || auth.auth_code.is_some() {
+        err!("The SSO login has expired")
+    }
+
+    let code = match (data.code, data.error) {
+        (Some(code), None) => code,
+        (_, error) => err!("The identity provider rejected the login", error),
+    };
+
+    let email = match sso::verified_email(config, &auth, &code) {
+        Ok(email) => email,
+        Err(e) => err!("Error validating the SSO login", e),
+    };
+
+    // The vault keys are created by the clients, so only existing accounts can log in
+    let user = match User::find_by_mail(&email, &conn) {
+        Some(user) => user,
+        None => err!(format!("There is no account for {}, register before using single sign-on", email)),
+    };
+
+    if let Some(ref org_uuid) = config.organization {
+        _sso_provision(&user, org_uuid, &conn);
+    }
+
+    let auth_code = auth.authorize(user.uuid.clone());
+    if let Err(e) = auth.save(&conn) {
+        err!("Error saving the SSO login", e)
+    }
+
+    let mut url = match Url::parse(&auth.redirect_uri) {
+        Ok(url) => url,
+        Err(e) => err!("Invalid redirect_uri", e),
+    };
+    url.query_pairs_mut().append_pair("code", &auth_code);
+    if let Some(ref state) = auth.client_state {
+        url.query_pairs_mut().append_pair("state", state);
+    }
+
+    Ok(Redirect::to(url.into_string()))
+}
+
+/// Only the web vault and the native clients can receive authorization codes
+fn _is_allowed_redirect(redirect_uri: &str) -> bool {
+    let url = match Url::parse(redirect_uri) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+
+    match url.scheme() {
+        "bitwarden" => true,
+        "http" if url.host_str() == Some("localhost") || url.host_str() == Some("127.0.0.1") => true,
+        _ => Url::parse(&CONFIG.domain).map(|domain| domain.origin() == url.origin()).unwrap_or(false),
+    }
+}
+
+/// Adds the user to the single sign-on organization, where an admin still has to confirm them
+fn _sso_provision(user: &User, org_uuid: &str, conn: &DbConn) {
+    if UserOrganization::find_by_user_and_org(&user.uuid, org_uuid, conn).is_some() {
+        return;
+    }
+
+    if Organization::find_by_uuid(org_uuid, conn).is_none() {
+        error!("The SSO organization {} doesn't exist", org_uuid);
+        return;
+    }
+
+    let user_org = UserOrganization::new(user.uuid.clone(), org_uuid.to_string());
+    match user_org.save(conn) {
+        Ok(()) => info!("User {} added to the SSO organization", user.email),
+        Err(e) => error!("Error adding {} to the SSO organization: {:#?}", user.email, e),
+    }
+}
+
+fn _sso_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult {
+    if CONFIG.sso.is_none() {
+        err!("Single sign-on is disabled")
+    }
+
+    let code = data.code.as_ref().unwrap();
+    let auth = match SsoAuth::find_by_auth_code(code, &conn) {
+        Some(auth) => auth,
+        None => err!(format!("Invalid authorization code. IP: {}.", ip.ip)),
+    };
+
+    // Authorization codes can only be used once
+    let (expired, redirect_uri, code_challenge, user_uuid) = (
+        auth.is_expired(),
+        auth.redirect_uri.clone(),
+        auth.code_challenge.clone(),
+        auth.user_uuid.clone(),
+    );
+    if let Err(e) = auth.delete(&conn) {
+        err!("Error deleting the SSO login", e)
+    }
+
+    if expired {
+        err!("The authorization code has expired")
+    }
+
+    if data.redirect_uri.as_ref() != Some(&redirect_uri) {
+        err!("Invalid redirect_uri")
+    }
+
+    if sso::pkce_challenge(data.code_verifier.as_ref().unwrap()) != code_challenge {
+        err!(format!("Invalid code verifier. IP: {}.", ip.ip))
+    }
+
+    let mut user = match user_uuid.and_then(|uuid| User::find_by_uuid(&uuid, &conn)) {
+        Some(user) => user,
+        None => err!("Invalid authorization code"),
+    };
+
+    _check_email_verified(&mut user, &conn)?;
+
+    _authenticated_login(user, data, conn, ip)
+}
+
 fn _check_is_some<T>(value: &Option<T>, msg: &str) -> EmptyResult {
     if value.is_none() {
         err!(msg)
@@ -1,10 +1,15 @@
+mod admin;
+mod catchers;
 pub(crate) mod core;
 mod icons;
 mod identity;
 mod web;
 mod notifications;
 
+pub use self::admin::routes as admin_routes;
+pub use self::catchers::catchers;
 pub use self::core::routes as core_routes;
+pub use self::core::{start_emergency_access_job, start_invitation_sweep, start_revision_purge, start_send_purge, start_trash_purge};
 pub use self::icons::routes as icons_routes;
 pub use self::identity::routes as identity_routes;
 pub use self::web::routes as web_routes;
@@ -15,6 +20,11 @@ use rocket::response::status::BadRequest;
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
+use crate::db::models::{Device, User};
+use crate::db::DbConn;
+use crate::mail;
+use crate::CONFIG;
+
 // Type aliases for API methods results
 type ApiResult<T> = Result<T, BadRequest<Json<Value>>>;
 type JsonResult = ApiResult<Json<Value>>;
@@ -52,3 +62,27 @@ impl NumberOrString {
         }  
     }
 }
+
+// Security notifications, only sent when enabled and the mail service is configured
+fn notify_account_change(address: &str, change: &str, conn: &DbConn) {
+    if !CONFIG.security_notifications {
+        return;
+    }
+
+    if CONFIG.mail_enabled() {
+        if let Err(e) = mail::send_account_change(address, change, conn) {
+            error!("Error sending account change notification: {}", e);
+        }
+    }
+}
+
+/// Logs out all the devices of the user after the password was changed by someone else.
+/// The refresh tokens don't depend on the security stamp, so the devices are removed too
+fn revoke_sessions(user: &User, conn: &DbConn, ws: &WebSocketUsers) {
+    for device in Device::find_by_user(&user.uuid, conn) {
+        ws.send_device_logout(user, &device.uuid);
+        if let Err(e) = device.delete(conn) {
+            error!("Error revoking device: {:#?}", e);
+        }
+    }
+}
@@ -117,6 +117,9 @@ pub struct WSHandler {
     users: WebSocketUsers,
 }
 
+/// A WebSocket connection, with the device that opened it
+type DeviceSender = (String, Sender);
+
 const RECORD_SEPARATOR: u8 = 0x1e;
 const INITIAL_RESPONSE: [u8; 3] = [0x7b, 0x7d, RECORD_SEPARATOR]; // {, }, <RS>
 
@@ -131,23 +134,26 @@ const PING: Token = Token(1);
 
 impl Handler for WSHandler {
     fn on_open(&mut self, hs: Handshake) -> ws::Result<()> {
-        // TODO: Improve this split
-        let path = hs.request.resource();
-        let mut query_split: Vec<_> = path.split('?').nth(1).unwrap().split('&').collect();
-        query_split.sort();
-        let access_token = &query_split[0][13..];
-        let _id = &query_split[1][3..];
+        // The token is sent in the Authorization header, or in the query when the client can't set headers
+        use crate::auth;
+        let token = match hs.request.header("Authorization") {
+            Some(header) => std::str::from_utf8(header)
+                .map_err(|_| auth::AuthError::Malformed)
+                .and_then(auth::parse_bearer),
+            None => hs
+                .request
+                .resource()
+                .splitn(2, '?')
+                .nth(1)
+                .and_then(|query| query.split('&').find(|param| param.starts_with("access_token=")))
+                .map(|param| auth::validate_token(&param["access_token=".len()..]))
+                .unwrap_or(Err(auth::AuthError::Missing)),
+        };
 
         // Validate the user
-        use crate::auth;
-        let claims = match auth::decode_jwt(access_token) {
+        let claims = match token.and_then(auth::decode_jwt) {
             Ok(claims) => claims,
-            Err(_) => {
-                return Err(ws::Error::new(
-                    ws::ErrorKind::Internal,
-                    "Invalid access token provided",
-                ))
-            }
+            Err(e) => return Err(ws::Error::new(ws::ErrorKind::Internal, e.description())),
         };
 
         // Assign the user to the handler
@@ -155,8 +161,8 @@ impl Handler for WSHandler {
         self.user_uuid = Some(user_uuid.clone());
 
         // Add the current Sender to the user list
-        let handler_insert = self.out.clone();
-        let handler_update = self.out.clone();
+        let handler_insert = (claims.device.clone(), self.out.clone());
+        let handler_update = (claims.device, self.out.clone());
 
         self.users.map.upsert(
             user_uuid,
@@ -230,7 +236,7 @@ impl Factory for WSFactory {
         // Remove handler
         if let Some(user_uuid) = &handler.user_uuid {
             if let Some(mut user_conn) = self.users.map.get_mut(user_uuid) {
-                user_conn.remove_item(&handler.out);
+                user_conn.retain(|(_, out)| out != &handler.out);
             }
         }
     }
@@ -238,21 +244,42 @@ impl Factory for WSFactory {
 
 #[derive(Clone)]
 pub struct WebSocketUsers {
-    pub map: Arc<CHashMap<String, Vec<Sender>>>,
+    pub map: Arc<CHashMap<String, Vec<DeviceSender>>>,
 }
 
 impl WebSocketUsers {
     fn send_update(&self, user_uuid: &String, data: &[u8]) -> ws::Result<()> {
         if let Some(user) = self.map.get(user_uuid) {
-            for sender in user.iter() {
+            for (_, sender) in user.iter() {
                 sender.send(data)?;
             }
         }
         Ok(())
     }
 
+    fn send_device_update(&self, user_uuid: &str, device_uuid: &str, data: &[u8]) -> ws::Result<()> {
+        if let Some(user) = self.map.get(user_uuid) {
+            for (_, sender) in user.iter().filter(|(device, _)| device == device_uuid) {
+                sender.send(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Logs out a single device of the user, the other devices are not notified
+    pub fn send_device_logout(&self, user: &User, device_uuid: &str) {
+        let data = create_update(
+            vec![
+                ("UserId".into(), user.uuid.clone().into()),
+                ("Date".into(), serialize_date(user.updated_at)),
+            ],
+            UpdateType::LogOut,
+        );
+
+        self.send_device_update(&user.uuid, device_uuid, &data).ok();
+    }
+
     // NOTE: The last modified date needs to be updated before calling these methods
-    #[allow(dead_code)]
     pub fn send_user_update(&self, ut: UpdateType, user: &User) {
         let data = create_update(
             vec![
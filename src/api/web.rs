@@ -13,9 +13,9 @@ use crate::CONFIG;
 
 pub fn routes() -> Vec<Route> {
     if CONFIG.web_vault_enabled {
-        routes![web_index, app_id, web_files, attachments, alive]
+        routes![web_index, app_id, web_files, attachments, alive, jwks]
     } else {
-        routes![attachments, alive]
+        routes![attachments, alive, jwks]
     }
 }
 
@@ -81,3 +81,9 @@ fn alive() -> Json<String> {
 
     Json(format_date(&Utc::now().naive_utc()))
 }
+
+/// Public keys that validate the access tokens, for services that accept them
+#[get("/.well-known/jwks.json")]
+fn jwks() -> Json<Value> {
+    Json(crate::auth::jwks())
+}
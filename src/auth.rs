@@ -1,41 +1,207 @@
 ///
 /// JWT Handling
 ///
-use crate::util::read_file;
-use chrono::Duration;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 
-use jsonwebtoken::{self, Algorithm, Header};
+use chrono::{Duration, Timelike, Utc};
+use data_encoding::{BASE64URL_NOPAD, HEXLOWER};
+
+use jsonwebtoken::{self, errors::ErrorKind, Algorithm, Header, TokenData, Validation};
+use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
+use serde_json::Value;
 
+use crate::crypto::{self, RsaKey};
+use crate::util::{file_exists, read_file};
 use crate::CONFIG;
 
 const JWT_ALGORITHM: Algorithm = Algorithm::RS256;
 
 lazy_static! {
-    pub static ref DEFAULT_VALIDITY: Duration = Duration::hours(2);
+    pub static ref DEFAULT_VALIDITY: Duration = Duration::seconds(CONFIG.access_token_validity);
     pub static ref JWT_ISSUER: String = CONFIG.domain.clone();
+    // A separate issuer, so the other tokens of the user can't be used to verify the email
+    pub static ref JWT_VERIFYEMAIL_ISSUER: String = format!("{}|verifyemail", CONFIG.domain);
 
-    static ref JWT_HEADER: Header = Header::new(JWT_ALGORITHM);
+    static ref JWT_HEADER: Header = Header {
+        kid: Some(active_key().kid.clone()),
+        ..Header::new(JWT_ALGORITHM)
+    };
 
-    static ref PRIVATE_RSA_KEY: Vec<u8> = match read_file(&CONFIG.private_rsa_key) {
-        Ok(key) => key,
-        Err(e) => panic!("Error loading private RSA Key from {}\n Error: {}", CONFIG.private_rsa_key, e)
+    static ref RSA_KEYS: Vec<RsaKey> = match load_keys() {
+        Ok(keys) => keys,
+        Err(e) => panic!("Error loading RSA keys from {}\n Error: {}", CONFIG.rsa_keys_folder, e)
     };
+}
+
+///
+/// Signing keys
+///
+/// Every key is stored as a PEM file named after its key id, which is the time it was created followed by a random suffix.
+/// The newest key signs the new tokens, the older ones only validate the tokens signed before the rotation.
+///
+
+fn active_key() -> &'static RsaKey {
+    RSA_KEYS.last().expect("No RSA keys available")
+}
+
+fn key_path(kid: &str) -> String {
+    format!("{}/{}.pem", CONFIG.rsa_keys_folder, kid)
+}
+
+pub fn list_key_ids() -> Result<Vec<String>, String> {
+    let entries = fs::read_dir(&CONFIG.rsa_keys_folder).map_err(|e| format!("Error reading the keys folder: {}", e))?;
+
+    let mut kids: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".pem"))
+        .map(|name| name.trim_end_matches(".pem").to_string())
+        .collect();
+
+    kids.sort();
+    Ok(kids)
+}
+
+fn load_keys() -> Result<Vec<RsaKey>, String> {
+    let keys = list_key_ids()?
+        .into_iter()
+        .map(|kid| {
+            let pem = read_file(&key_path(&kid))?;
+            crypto::load_rsa_key(kid, &pem)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if keys.is_empty() {
+        return Err("No keys found".into());
+    }
 
-    static ref PUBLIC_RSA_KEY: Vec<u8> = match read_file(&CONFIG.public_rsa_key) {
-        Ok(key) => key,
-        Err(e) => panic!("Error loading public RSA Key from {}\n Error: {}", CONFIG.public_rsa_key, e)
+    Ok(keys)
+}
+
+fn save_key(pem: &[u8]) -> Result<String, String> {
+    fs::create_dir_all(&CONFIG.rsa_keys_folder).map_err(|e| format!("Error creating the keys folder: {}", e))?;
+
+    // The timestamp keeps the ids sorted by creation, the suffix keeps them unique
+    let now = Utc::now();
+    let kid = format!(
+        "{}{:06}-{}",
+        now.format("%Y%m%d%H%M%S"),
+        now.nanosecond() / 1000,
+        HEXLOWER.encode(&crypto::get_random(vec![0; 4]))
+    );
+    let path = key_path(&kid);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    // Only the owner can read the private keys
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(pem))
+        .map_err(|e| format!("Error writing {}: {}", path, e))?;
+    Ok(kid)
+}
+
+/// Makes sure there is a signing key, importing the key created by previous versions if there is one.
+/// Returns true when the first key was just created.
+pub fn init_keys() -> Result<bool, String> {
+    fs::create_dir_all(&CONFIG.rsa_keys_folder).map_err(|e| format!("Error creating the keys folder: {}", e))?;
+
+    if !list_key_ids()?.is_empty() {
+        return Ok(false);
+    }
+
+    let pem = if file_exists(&CONFIG.legacy_rsa_key) {
+        info!("Importing the existing RSA key from {}", CONFIG.legacy_rsa_key);
+        crypto::rsa_der_to_pem(&read_file(&CONFIG.legacy_rsa_key)?)?
+    } else {
+        info!("RSA keys don't exist, creating them...");
+        crypto::generate_rsa_key()?
     };
+
+    save_key(&pem).and(Ok(true))
+}
+
+/// Creates a new signing key, which is used for the new tokens after the server is restarted
+pub fn rotate_keys() -> Result<String, String> {
+    save_key(&crypto::generate_rsa_key()?)
+}
+
+/// Removes a retired key, the tokens signed with it won't be valid anymore
+pub fn remove_key(kid: &str) -> Result<(), String> {
+    let kids = list_key_ids()?;
+
+    if !kids.iter().any(|k| k == kid) {
+        return Err(format!("The key {} doesn't exist", kid));
+    }
+
+    if kids.last().map(String::as_str) == Some(kid) {
+        return Err("The active key can't be removed, rotate the keys first".into());
+    }
+
+    fs::remove_file(key_path(kid)).map_err(|e| format!("Error removing the key {}: {}", kid, e))
+}
+
+/// The public keys in JSON Web Key Set format, so other services can validate the tokens
+pub fn jwks() -> Value {
+    let keys: Vec<Value> = RSA_KEYS
+        .iter()
+        .map(|key| {
+            json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": key.kid,
+                "n": BASE64URL_NOPAD.encode(&key.n),
+                "e": BASE64URL_NOPAD.encode(&key.e),
+            })
+        })
+        .collect();
+
+    json!({ "keys": keys })
 }
 
 pub fn encode_jwt<T: Serialize>(claims: &T) -> String {
-    match jsonwebtoken::encode(&JWT_HEADER, claims, &PRIVATE_RSA_KEY) {
+    match jsonwebtoken::encode(&JWT_HEADER, claims, &active_key().private_der) {
         Ok(token) => token,
         Err(e) => panic!("Error encoding jwt {}", e)
     }
 }
 
-pub fn decode_jwt(token: &str) -> Result<JWTClaims, String> {
+fn decode_with_keys<T: DeserializeOwned>(token: &str, validation: &Validation) -> jsonwebtoken::errors::Result<TokenData<T>> {
+    let header = jsonwebtoken::decode_header(token)?;
+
+    match header.kid {
+        Some(kid) => match RSA_KEYS.iter().find(|key| key.kid == kid) {
+            Some(key) => jsonwebtoken::decode(token, &key.public_der, validation),
+            None => Err(ErrorKind::InvalidToken.into()),
+        },
+        // Tokens signed before key rotation was supported don't have a key id
+        None => {
+            let mut result = Err(ErrorKind::InvalidSignature.into());
+            for key in RSA_KEYS.iter() {
+                result = jsonwebtoken::decode(token, &key.public_der, validation);
+                if let Err(ref e) = result {
+                    if let ErrorKind::InvalidSignature = *e.kind() {
+                        continue;
+                    }
+                }
+                break;
+            }
+            result
+        }
+    }
+}
+
+pub fn decode_jwt(token: &str) -> Result<JWTClaims, AuthError> {
     let validation = jsonwebtoken::Validation {
         leeway: 30, // 30 seconds
         validate_exp: true,
@@ -47,11 +213,11 @@ pub fn decode_jwt(token: &str) -> Result<JWTClaims, String> {
         algorithms: vec![JWT_ALGORITHM],
     };
 
-    match jsonwebtoken::decode(token, &PUBLIC_RSA_KEY, &validation) {
+    match decode_with_keys(token, &validation) {
         Ok(decoded) => Ok(decoded.claims),
         Err(msg) => {
             error!("Error validating jwt - {:#?}", msg);
-            Err(msg.to_string())
+            Err(AuthError::from_jwt(&msg))
         }
     }
 }
@@ -68,7 +234,49 @@ pub fn decode_invite_jwt(token: &str) -> Result<InviteJWTClaims, String> {
         algorithms: vec![JWT_ALGORITHM],
     };
 
-    match jsonwebtoken::decode(token, &PUBLIC_RSA_KEY, &validation) {
+    match decode_with_keys(token, &validation) {
+        Ok(decoded) => Ok(decoded.claims),
+        Err(msg) => {
+            error!("Error validating jwt - {:#?}", msg);
+            Err(msg.to_string())
+        }
+    }
+}
+
+pub fn decode_verify_email_jwt(token: &str) -> Result<VerifyEmailJWTClaims, String> {
+    let validation = jsonwebtoken::Validation {
+        leeway: 30, // 30 seconds
+        validate_exp: true,
+        validate_iat: false, // IssuedAt is the same as NotBefore
+        validate_nbf: true,
+        aud: None,
+        iss: Some(JWT_VERIFYEMAIL_ISSUER.clone()),
+        sub: None,
+        algorithms: vec![JWT_ALGORITHM],
+    };
+
+    match decode_with_keys(token, &validation) {
+        Ok(decoded) => Ok(decoded.claims),
+        Err(msg) => {
+            error!("Error validating jwt - {:#?}", msg);
+            Err(msg.to_string())
+        }
+    }
+}
+
+pub fn decode_send_file_jwt(token: &str) -> Result<SendFileJWTClaims, String> {
+    let validation = jsonwebtoken::Validation {
+        leeway: 30, // 30 seconds
+        validate_exp: true,
+        validate_iat: false, // IssuedAt is the same as NotBefore
+        validate_nbf: true,
+        aud: None,
+        iss: Some(JWT_ISSUER.clone()),
+        sub: None,
+        algorithms: vec![JWT_ALGORITHM],
+    };
+
+    match decode_with_keys(token, &validation) {
         Ok(decoded) => Ok(decoded.claims),
         Err(msg) => {
             error!("Error validating jwt - {:#?}", msg);
@@ -77,6 +285,48 @@ pub fn decode_invite_jwt(token: &str) -> Result<InviteJWTClaims, String> {
     }
 }
 
+pub fn decode_emergency_access_invite_jwt(token: &str) -> Result<EmergencyAccessInviteJWTClaims, String> {
+    let validation = jsonwebtoken::Validation {
+        leeway: 30, // 30 seconds
+        validate_exp: true,
+        validate_iat: false, // IssuedAt is the same as NotBefore
+        validate_nbf: true,
+        aud: None,
+        iss: Some(JWT_ISSUER.clone()),
+        sub: None,
+        algorithms: vec![JWT_ALGORITHM],
+    };
+
+    match decode_with_keys(token, &validation) {
+        Ok(decoded) => Ok(decoded.claims),
+        Err(msg) => {
+            error!("Error validating jwt - {:#?}", msg);
+            Err(msg.to_string())
+        }
+    }
+}
+
+pub fn decode_org_api_jwt(token: &str) -> Result<OrgApiKeyJWTClaims, AuthError> {
+    let validation = jsonwebtoken::Validation {
+        leeway: 30, // 30 seconds
+        validate_exp: true,
+        validate_iat: false, // IssuedAt is the same as NotBefore
+        validate_nbf: true,
+        aud: None,
+        iss: Some(JWT_ISSUER.clone()),
+        sub: None,
+        algorithms: vec![JWT_ALGORITHM],
+    };
+
+    match decode_with_keys(token, &validation) {
+        Ok(decoded) => Ok(decoded.claims),
+        Err(msg) => {
+            error!("Error validating jwt - {:#?}", msg);
+            Err(AuthError::from_jwt(&msg))
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JWTClaims {
     // Not before
@@ -106,6 +356,10 @@ pub struct JWTClaims {
     pub scope: Vec<String>,
     // [ "Application" ]
     pub amr: Vec<String>,
+
+    // Revision of the user API key, only in the tokens issued with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_revision: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -124,14 +378,194 @@ pub struct InviteJWTClaims {
     pub user_org_id: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrgApiKeyJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject, the organization uuid
+    pub sub: String,
+
+    pub client_id: String,
+    // Revision of the key, the tokens issued before the last rotation are rejected
+    pub key_revision: i32,
+    // [ "api.organization" ]
+    pub scope: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyEmailJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject
+    pub sub: String,
+
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendFileJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject, the send uuid
+    pub sub: String,
+
+    pub file_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmergencyAccessInviteJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject, the emergency access uuid
+    pub sub: String,
+
+    pub email: String,
+    pub grantor_name: String,
+}
+
 ///
 /// Bearer token authentication
 ///
 use rocket::Outcome;
+use rocket::http::Status;
 use rocket::request::{self, Request, FromRequest};
 
+/// Why a request couldn't be authenticated, as reported in the WWW-Authenticate header (RFC 6750, section 3)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthError {
+    /// No token was sent
+    Missing,
+    /// The Authorization header doesn't contain exactly one bearer token
+    Malformed,
+    /// The token was valid, but it expired and the client should refresh it
+    Expired,
+    /// The token is not valid anymore, the client has to log in again
+    Invalid,
+    /// The token doesn't give access to this endpoint
+    InsufficientScope,
+}
+
+impl AuthError {
+    fn from_jwt(error: &jsonwebtoken::errors::Error) -> Self {
+        match *error.kind() {
+            ErrorKind::ExpiredSignature => AuthError::Expired,
+            _ => AuthError::Invalid,
+        }
+    }
+
+    pub fn status(self) -> Status {
+        match self {
+            AuthError::Malformed => Status::BadRequest,
+            AuthError::InsufficientScope => Status::Forbidden,
+            AuthError::Missing | AuthError::Expired | AuthError::Invalid => Status::Unauthorized,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            AuthError::Missing => "No access token provided",
+            AuthError::Malformed => "The Authorization header must contain one bearer token",
+            AuthError::Expired => "The access token expired",
+            AuthError::Invalid => "The access token is invalid",
+            AuthError::InsufficientScope => "The access token doesn't have the required scope",
+        }
+    }
+
+    /// Value of the WWW-Authenticate header. When no token was sent, the error is omitted
+    pub fn www_authenticate(self) -> String {
+        let code = match self {
+            AuthError::Missing => return "Bearer realm=\"bitwarden_rs\"".into(),
+            AuthError::Malformed => "invalid_request",
+            AuthError::Expired | AuthError::Invalid => "invalid_token",
+            AuthError::InsufficientScope => "insufficient_scope",
+        };
+
+        format!(
+            "Bearer realm=\"bitwarden_rs\", error=\"{}\", error_description=\"{}\"",
+            code,
+            self.description()
+        )
+    }
+}
+
+/// Error of the failed authentication guard, kept for the error catchers
+struct AuthFailure(Option<AuthError>);
+
+fn auth_failure<S>(request: &Request, error: AuthError) -> request::Outcome<S, &'static str> {
+    error!("{}", error.description());
+    request.local_cache(|| AuthFailure(Some(error)));
+    Outcome::Failure((error.status(), error.description()))
+}
+
+/// Returns why the authentication of this request failed, if it did
+pub fn request_auth_error(request: &Request) -> Option<AuthError> {
+    request.local_cache(|| AuthFailure(None)).0
+}
+
+/// Message of the failed role check, kept for the error catchers
+struct RoleFailure(Option<&'static str>);
+
+/// The user is authenticated, but their role in the organization doesn't allow the request
+fn role_failure<S>(request: &Request, message: &'static str) -> request::Outcome<S, &'static str> {
+    error!("{}", message);
+    request.local_cache(|| RoleFailure(Some(message)));
+    Outcome::Failure((Status::Forbidden, message))
+}
+
+/// Returns why the role check of this request failed, if it did
+pub fn request_role_error(request: &Request) -> Option<&'static str> {
+    request.local_cache(|| RoleFailure(None)).0
+}
+
+/// Extracts the token of an `Authorization: Bearer <token>` header (RFC 6750, section 2.1)
+pub fn parse_bearer(header: &str) -> Result<&str, AuthError> {
+    let mut parts = header.split_whitespace();
+
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(scheme), Some(token), None) if scheme.eq_ignore_ascii_case("Bearer") => validate_token(token),
+        _ => Err(AuthError::Malformed),
+    }
+}
+
+/// Checks that the token only has the characters allowed in a bearer token (b64token)
+pub fn validate_token(token: &str) -> Result<&str, AuthError> {
+    let value = token.trim_end_matches('=');
+
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || "-._~+/".contains(c)) {
+        Ok(token)
+    } else {
+        Err(AuthError::Malformed)
+    }
+}
+
+fn bearer_token<'a>(request: &'a Request) -> Result<&'a str, AuthError> {
+    let mut headers = request.headers().get("Authorization");
+
+    match (headers.next(), headers.next()) {
+        (Some(header), None) => parse_bearer(header),
+        (None, _) => Err(AuthError::Missing),
+        _ => Err(AuthError::Malformed),
+    }
+}
+
 use crate::db::DbConn;
-use crate::db::models::{User, Organization, UserOrganization, UserOrgType, UserOrgStatus, Device};
+use crate::db::models::{ApiKey, User, Organization, UserOrganization, UserOrgType, UserOrgStatus, Device};
 
 pub struct Headers {
     pub host: String,
@@ -148,13 +582,15 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
         // Get host
         let host = if CONFIG.domain_set {
             CONFIG.domain.clone()
-        } else if let Some(referer) = headers.get_one("Referer") {
-            referer.to_string()
-        } else {   
-            // Try to guess from the headers
+        } else {
+            // Try to guess from the headers, the forwarded headers are only accepted from trusted proxies.
+            // The Referer is never used, any page can set it to another site
             use std::env;
 
-            let protocol = if let Some(proto) = headers.get_one("X-Forwarded-Proto") {
+            let trusted = request.remote().map(|addr| is_trusted_proxy(&util::normalize_ip(addr.ip()))).unwrap_or(false);
+            let forwarded = |name: &'static str| if trusted { headers.get_one(name) } else { None };
+
+            let protocol = if let Some(proto) = forwarded("X-Forwarded-Proto") {
                 proto
             } else if env::var("ROCKET_TLS").is_ok() {
                 "https"
@@ -162,7 +598,7 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
                 "http"
             };
 
-            let host = if let Some(host) = headers.get_one("X-Forwarded-Host") {
+            let host = if let Some(host) = forwarded("X-Forwarded-Host") {
                 host
             } else if let Some(host) = headers.get_one("Host") {
                 host
@@ -174,20 +610,22 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
         };
 
         // Get access_token
-        let access_token: &str = match request.headers().get_one("Authorization") {
-            Some(a) => match a.rsplit("Bearer ").next() {
-                Some(split) => split,
-                None => err_handler!("No access token provided"),
-            },
-            None => err_handler!("No access token provided"),
+        let access_token = match bearer_token(request) {
+            Ok(token) => token,
+            Err(e) => return auth_failure(request, e),
         };
 
         // Check JWT token is valid and get device and user from it
         let claims: JWTClaims = match decode_jwt(access_token) {
             Ok(claims) => claims,
-            Err(_) => err_handler!("Invalid claim")
+            Err(e) => return auth_failure(request, e),
         };
 
+        // Organization API keys can only access the organization endpoints
+        if !claims.scope.iter().any(|s| s == ApiKey::USER_SCOPE) {
+            return auth_failure(request, AuthError::InsufficientScope);
+        }
+
         let device_uuid = claims.device;
         let user_uuid = claims.sub;
 
@@ -196,18 +634,27 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
             _ => err_handler!("Error getting DB")
         };
 
+        // The device was revoked or the user changed their password or logged out everywhere
         let device = match Device::find_by_uuid(&device_uuid, &conn) {
             Some(device) => device,
-            None => err_handler!("Invalid device id")
+            None => return auth_failure(request, AuthError::Invalid),
         };
 
         let user = match User::find_by_uuid(&user_uuid, &conn) {
             Some(user) => user,
-            None => err_handler!("Device has no user associated")
+            None => return auth_failure(request, AuthError::Invalid),
         };
 
         if user.security_stamp != claims.sstamp {
-            err_handler!("Invalid security stamp")
+            return auth_failure(request, AuthError::Invalid);
+        }
+
+        // Rotating the user API key revokes the tokens issued with the previous secret
+        if let Some(revision) = claims.api_key_revision {
+            match ApiKey::find_by_user(&user.uuid, &conn) {
+                Some(ref key) if key.revision == revision => (),
+                _ => return auth_failure(request, AuthError::Invalid),
+            }
         }
 
         Outcome::Success(Headers { host, device, user })
@@ -242,14 +689,14 @@ impl<'a, 'r> FromRequest<'a, 'r> for OrgHeaders {
                                 if user.status == UserOrgStatus::Confirmed as i32 {
                                     user
                                 } else {
-                                    err_handler!("The current user isn't confirmed member of the organization")
+                                    return role_failure(request, "The current user isn't confirmed member of the organization");
                                 }
                             }
                             None => {
                                 if headers.user.is_server_admin() && org_id == Organization::VIRTUAL_ID {
                                     UserOrganization::new_virtual(headers.user.uuid.clone(), UserOrgType::Owner, UserOrgStatus::Confirmed)
                                 } else {
-                                    err_handler!("The current user isn't member of the organization")
+                                    return role_failure(request, "The current user isn't member of the organization");
                                 }
                             }
                         };
@@ -297,7 +744,7 @@ impl<'a, 'r> FromRequest<'a, 'r> for AdminHeaders {
                         org_user_type: headers.org_user_type,
                     })
                 } else {
-                    err_handler!("You need to be Admin or Owner to call this endpoint")
+                    role_failure(request, "You need to be Admin or Owner to call this endpoint")
                 }
             }
         }
@@ -325,18 +772,63 @@ impl<'a, 'r> FromRequest<'a, 'r> for OwnerHeaders {
                         user: headers.user,
                     })
                 } else {
-                    err_handler!("You need to be Owner to call this endpoint")
+                    role_failure(request, "You need to be Owner to call this endpoint")
                 }
             }
         }
     }
 }
 
+/// Authenticates a request made with an organization API key
+pub struct OrgApiHeaders {
+    pub org: Organization,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for OrgApiHeaders {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let access_token = match bearer_token(request) {
+            Ok(token) => token,
+            Err(e) => return auth_failure(request, e),
+        };
+
+        let claims: OrgApiKeyJWTClaims = match decode_org_api_jwt(access_token) {
+            Ok(claims) => claims,
+            Err(e) => return auth_failure(request, e),
+        };
+
+        if !claims.scope.iter().any(|s| s == ApiKey::ORG_SCOPE) {
+            return auth_failure(request, AuthError::InsufficientScope);
+        }
+
+        let conn = match request.guard::<DbConn>() {
+            Outcome::Success(conn) => conn,
+            _ => err_handler!("Error getting DB")
+        };
+
+        // Rotating the key revokes the tokens issued with the previous secret
+        match ApiKey::find_by_client_id(&claims.client_id, &conn) {
+            Some(ref key) if key.org_uuid.as_ref() == Some(&claims.sub)
+                && key.revision == claims.key_revision => (),
+            _ => return auth_failure(request, AuthError::Invalid),
+        }
+
+        match Organization::find_by_uuid(&claims.sub, &conn) {
+            Some(org) => Outcome::Success(Self { org }),
+            None => auth_failure(request, AuthError::Invalid),
+        }
+    }
+}
+
 ///
 /// Client IP address detection
 ///
 use std::net::IpAddr;
 
+use crate::config::IpHeader;
+use crate::util;
+
 pub struct ClientIp {
     pub ip: IpAddr,
 }
@@ -345,11 +837,86 @@ impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        let ip = match request.client_ip() {
-            Some(addr) => addr,
+        let ip = match request.remote() {
+            Some(addr) => client_ip(request, util::normalize_ip(addr.ip())),
             None => "0.0.0.0".parse().unwrap(),
         };
 
         Outcome::Success(ClientIp { ip })
     }
 }
+
+fn is_trusted_proxy(ip: &IpAddr) -> bool {
+    CONFIG.trusted_proxies.iter().any(|network| network.contains(ip))
+}
+
+/// The proxy headers are only used when the request comes from a trusted proxy, otherwise they could be spoofed
+fn client_ip(request: &Request, peer: IpAddr) -> IpAddr {
+    if !is_trusted_proxy(&peer) {
+        return peer;
+    }
+
+    let headers = request.headers();
+    match CONFIG.ip_header {
+        IpHeader::XRealIp => headers
+            .get_one("X-Real-IP")
+            .and_then(|ip| ip.trim().parse().ok())
+            .map(util::normalize_ip)
+            .unwrap_or(peer),
+        IpHeader::XForwardedFor => {
+            let chain = headers.get("X-Forwarded-For").flat_map(|value| value.split(','));
+            first_untrusted(chain.map(|ip| ip.trim().parse().ok()), peer)
+        }
+        IpHeader::Forwarded => {
+            let chain = headers
+                .get("Forwarded")
+                .flat_map(|value| value.split(','))
+                .map(|element| {
+                    element
+                        .split(';')
+                        .filter_map(|pair| {
+                            let mut pair = pair.splitn(2, '=');
+                            match (pair.next(), pair.next()) {
+                                (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("for") => Some(value),
+                                _ => None,
+                            }
+                        })
+                        .next()
+                        .and_then(parse_forwarded_for)
+                });
+            first_untrusted(chain, peer)
+        }
+    }
+}
+
+/// Every proxy appends the address it received the request from, so the client is the rightmost address
+/// that isn't a trusted proxy. An address that can't be parsed stops the search, as it can't be trusted
+fn first_untrusted<I: Iterator<Item = Option<IpAddr>>>(chain: I, peer: IpAddr) -> IpAddr {
+    let chain: Vec<_> = chain.collect();
+    let mut client = peer;
+
+    for ip in chain.into_iter().rev() {
+        match ip.map(util::normalize_ip) {
+            Some(ip) => {
+                client = ip;
+                if !is_trusted_proxy(&ip) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+
+    client
+}
+
+/// Parses the `for` parameter of the Forwarded header (RFC 7239), like `192.0.2.60`, `"192.0.2.60:4711"` or `"[2001:db8::1]:4711"`
+fn parse_forwarded_for(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+
+    if value.starts_with('[') {
+        return value[1..].split(']').next().and_then(|ip| ip.parse().ok());
+    }
+
+    value.split(':').next().and_then(|ip| ip.parse().ok())
+}
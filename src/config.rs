@@ -0,0 +1,715 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use lettre::smtp::authentication::Mechanism;
+use serde_json::Value;
+
+use crate::crypto::{Argon2Params, PasswordAlgorithm};
+use crate::db::models::CipherRevision;
+use crate::util::IpNetwork;
+
+/// All the settings that can be set in the config file, which uses the same names as the environment variables
+const KNOWN_KEYS: &[&str] = &[
+    "DATA_FOLDER", "DATABASE_URL", "RSA_KEY_FILENAME", "RSA_KEYS_FOLDER", "ICON_CACHE_FOLDER", "ATTACHMENTS_FOLDER", "SENDS_FOLDER", "TMP_FOLDER",
+    "ICON_CACHE_TTL", "ICON_CACHE_NEGTTL",
+    "WEB_VAULT_FOLDER", "WEB_VAULT_ENABLED",
+    "WEBSOCKET_ENABLED", "WEBSOCKET_ADDRESS", "WEBSOCKET_PORT",
+    "EXTENDED_LOGGING", "LOG_FILE",
+    "LOCAL_ICON_EXTRACTOR", "SIGNUPS_ALLOWED", "SIGNUPS_DOMAINS_WHITELIST", "SIGNUPS_VERIFY", "INVITATIONS_ALLOWED", "INVITATION_EXPIRATION_HOURS",
+    "TRASH_AUTO_DELETE_DAYS", "REVISION_RETENTION_DAYS", "IMPORT_STREAM_LIMIT", "SENDS_ALLOWED",
+    "EMERGENCY_ACCESS_ALLOWED", "EMERGENCY_REMINDER_HOURS",
+    "SERVER_ADMIN_EMAIL",
+    "ACCESS_TOKEN_VALIDITY", "REFRESH_TOKEN_ABSOLUTE_LIFETIME", "REFRESH_TOKEN_IDLE_LIFETIME",
+    "CLIENT_KDF_MIN_ITERATIONS", "PASSWORD_ITERATIONS", "PASSWORD_ALGORITHM", "ARGON2_MEMORY", "ARGON2_ITERATIONS", "ARGON2_PARALLELISM",
+    "SHOW_PASSWORD_HINT", "SECURITY_NOTIFICATIONS",
+    "DOMAIN", "TRUSTED_PROXIES", "IP_HEADER",
+    "YUBICO_CLIENT_ID", "YUBICO_SECRET_KEY", "YUBICO_SERVER",
+    "SSO_ENABLED", "SSO_AUTHORITY", "SSO_CLIENT_ID", "SSO_CLIENT_SECRET", "SSO_SCOPES", "SSO_ORGANIZATION",
+    "SSO_ALLOW_UNVERIFIED_EMAIL",
+    "MAIL_SINK", "SMTP_HOST", "SMTP_FROM", "SMTP_PORT", "SMTP_SECURITY", "SMTP_SSL", "SMTP_CA_CERT",
+    "SMTP_USERNAME", "SMTP_PASSWORD", "SMTP_AUTH_MECHANISM", "SMTP_HELO_NAME", "SMTP_TIMEOUT",
+];
+
+/// Reads the settings from the environment variables, falling back to the values in the config file.
+/// Any value that can't be parsed is recorded as an error instead of silently using the default.
+struct ConfigSource {
+    file: HashMap<String, String>,
+    errors: Vec<String>,
+}
+
+impl ConfigSource {
+    fn load() -> Self {
+        // Doesn't override the variables already set, so a reload only picks up the changes of the config file
+        dotenv::dotenv().ok();
+
+        let mut source = ConfigSource {
+            file: HashMap::new(),
+            errors: Vec::new(),
+        };
+
+        // The config file is optional, unless its path is set explicitly
+        let (path, required) = match env::var("CONFIG_FILE") {
+            Ok(path) => (path, true),
+            Err(_) => {
+                let data_folder = env::var("DATA_FOLDER").unwrap_or_else(|_| "data".into());
+                (format!("{}/config.json", data_folder), false)
+            }
+        };
+
+        if !required && !Path::new(&path).exists() {
+            return source;
+        }
+
+        match source.read_file(&path) {
+            Ok(file) => source.file = file,
+            Err(e) => source.error(format!("Error reading config file '{}': {}", path, e)),
+        }
+
+        source
+    }
+
+    fn read_file(&mut self, path: &str) -> Result<HashMap<String, String>, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        let values = match serde_json::from_str(&contents).map_err(|e| e.to_string())? {
+            Value::Object(values) => values,
+            _ => return Err("the config file must contain a JSON object".into()),
+        };
+
+        let mut file = HashMap::new();
+
+        for (key, value) in values {
+            if !KNOWN_KEYS.contains(&key.as_str()) {
+                self.error(format!("Unknown setting '{}' in the config file", key));
+                continue;
+            }
+
+            let value = match value {
+                Value::Null => continue,
+                Value::String(s) => s,
+                Value::Bool(b) => b.to_string(),
+                Value::Number(n) => n.to_string(),
+                _ => {
+                    self.error(format!("Setting '{}' in the config file must be a string, number or boolean", key));
+                    continue;
+                }
+            };
+
+            file.insert(key, value);
+        }
+
+        Ok(file)
+    }
+
+    fn get<V: FromStr>(&mut self, key: &str) -> Option<V> {
+        let (value, origin) = match env::var(key) {
+            Ok(value) => (value, "environment variable"),
+            Err(_) => match self.file.get(key) {
+                Some(value) => (value.clone(), "config file"),
+                None => return None,
+            },
+        };
+
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.error(format!("Invalid value '{}' for {} in the {}", value, key, origin));
+                None
+            }
+        }
+    }
+
+    fn get_or<V: FromStr>(&mut self, key: &str, default: V) -> V {
+        self.get(key).unwrap_or(default)
+    }
+
+    fn error(&mut self, error: String) {
+        self.errors.push(error);
+    }
+
+    fn finish(self) -> Result<(), Vec<String>> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MailTransport {
+    Smtp,
+    File(String),
+    Stdout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpSecurity {
+    Off,
+    Starttls,
+    ForceTls,
+}
+
+#[derive(Debug)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_security: SmtpSecurity,
+    pub smtp_ca_certs: Vec<Vec<u8>>,
+    pub smtp_from: String,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_auth_mechanism: Option<Mechanism>,
+    pub smtp_helo_name: Option<String>,
+    pub smtp_timeout: u64,
+}
+
+impl MailConfig {
+    fn load(source: &mut ConfigSource) -> Option<Self> {
+        // A mail sink replaces the SMTP server, emails are written to stdout or to a folder instead.
+        // This is intended for development and testing.
+        let transport = match source.get::<String>("MAIL_SINK") {
+            Some(ref sink) if sink == "stdout" => MailTransport::Stdout,
+            Some(folder) => MailTransport::File(folder),
+            None => MailTransport::Smtp,
+        };
+
+        // When SMTP_HOST is absent, we assume the user does not want to enable it.
+        let smtp_host = match (source.get("SMTP_HOST"), &transport) {
+            (Some(host), _) => host,
+            (None, MailTransport::Smtp) => return None,
+            (None, _) => String::new(),
+        };
+
+        let smtp_from = match (source.get("SMTP_FROM"), &transport) {
+            (Some(from), _) => from,
+            (None, MailTransport::Smtp) => {
+                source.error("Please specify SMTP_FROM to enable SMTP support.".into());
+                String::new()
+            }
+            (None, _) => "bitwarden-rs@localhost".to_string(),
+        };
+
+        // SMTP_SSL is kept for compatibility, when true it means STARTTLS is required
+        let smtp_ssl = source.get_or("SMTP_SSL", true);
+        let smtp_security = match source.get::<String>("SMTP_SECURITY") {
+            Some(security) => match security.to_lowercase().as_str() {
+                "off" => SmtpSecurity::Off,
+                "starttls" => SmtpSecurity::Starttls,
+                "force_tls" => SmtpSecurity::ForceTls,
+                _ => {
+                    source.error(format!("Invalid SMTP_SECURITY value '{}', use one of 'off', 'starttls' or 'force_tls'.", security));
+                    SmtpSecurity::Starttls
+                }
+            },
+            None if smtp_ssl => SmtpSecurity::Starttls,
+            None => SmtpSecurity::Off,
+        };
+
+        let smtp_port = source.get("SMTP_PORT").unwrap_or(match smtp_security {
+            SmtpSecurity::Off => 25u16,
+            SmtpSecurity::Starttls => 587u16,
+            SmtpSecurity::ForceTls => 465u16,
+        });
+
+        let smtp_ca_certs = match source.get::<String>("SMTP_CA_CERT") {
+            Some(path) => load_ca_certs(&path).unwrap_or_else(|e| {
+                source.error(format!("Error loading SMTP_CA_CERT '{}': {}", path, e));
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+
+        let smtp_username = source.get("SMTP_USERNAME");
+        let smtp_password = source.get("SMTP_PASSWORD");
+        if smtp_username.is_some() && smtp_password.is_none() {
+            source.error("SMTP_PASSWORD is mandatory when specifying SMTP_USERNAME.".into());
+        }
+
+        let smtp_auth_mechanism = match source.get::<String>("SMTP_AUTH_MECHANISM") {
+            Some(_) if smtp_username.is_none() => {
+                source.error("SMTP_AUTH_MECHANISM requires SMTP_USERNAME and SMTP_PASSWORD.".into());
+                None
+            }
+            Some(mechanism) => match mechanism.to_lowercase().as_str() {
+                "plain" => Some(Mechanism::Plain),
+                "login" => Some(Mechanism::Login),
+                "xoauth2" => Some(Mechanism::Xoauth2),
+                _ => {
+                    source.error(format!("Invalid SMTP_AUTH_MECHANISM value '{}', use one of 'plain', 'login' or 'xoauth2'.", mechanism));
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let smtp_helo_name = source.get("SMTP_HELO_NAME");
+        let smtp_timeout = source.get_or("SMTP_TIMEOUT", 15);
+        if smtp_timeout == 0 {
+            source.error("SMTP_TIMEOUT must be greater than zero.".into());
+        }
+
+        Some(MailConfig {
+            transport,
+            smtp_host,
+            smtp_port,
+            smtp_security,
+            smtp_ca_certs,
+            smtp_from,
+            smtp_username,
+            smtp_password,
+            smtp_auth_mechanism,
+            smtp_helo_name,
+            smtp_timeout,
+        })
+    }
+}
+
+/// Reads a PEM bundle and returns every certificate in it, checking that all of them can be parsed
+fn load_ca_certs(path: &str) -> Result<Vec<Vec<u8>>, String> {
+    const END_MARKER: &str = "-----END CERTIFICATE-----";
+
+    let bundle = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut certs = Vec::new();
+
+    for block in bundle.split_terminator(END_MARKER) {
+        if block.trim().is_empty() {
+            continue;
+        }
+
+        let pem = format!("{}{}\n", block.trim_start(), END_MARKER).into_bytes();
+        native_tls::Certificate::from_pem(&pem).map_err(|e| e.to_string())?;
+        certs.push(pem);
+    }
+
+    if certs.is_empty() {
+        return Err("no certificates found".into());
+    }
+
+    Ok(certs)
+}
+
+/// Settings that can be changed without restarting the server
+#[derive(Debug)]
+struct RuntimeConfig {
+    signups_allowed: bool,
+    signups_domains_whitelist: Vec<String>,
+    signups_verify: bool,
+    invitations_allowed: bool,
+    show_password_hint: bool,
+
+    icon_cache_ttl: u64,
+    icon_cache_negttl: u64,
+
+    mail: Option<Arc<MailConfig>>,
+}
+
+impl RuntimeConfig {
+    fn load(source: &mut ConfigSource) -> Self {
+        let signups_domains_whitelist = source
+            .get_or("SIGNUPS_DOMAINS_WHITELIST", String::new())
+            .split(',')
+            .map(|domain| domain.trim().to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+
+        let signups_verify = source.get_or("SIGNUPS_VERIFY", false);
+        let mail = MailConfig::load(source).map(Arc::new);
+
+        if signups_verify && mail.is_none() {
+            source.error("SIGNUPS_VERIFY requires the mail service to be configured.".into());
+        }
+
+        RuntimeConfig {
+            signups_allowed: source.get_or("SIGNUPS_ALLOWED", true),
+            signups_domains_whitelist,
+            signups_verify,
+            invitations_allowed: source.get_or("INVITATIONS_ALLOWED", true),
+            show_password_hint: source.get_or("SHOW_PASSWORD_HINT", true),
+
+            // icon_cache_ttl defaults to 30 days (30 * 24 * 60 * 60 seconds)
+            icon_cache_ttl: source.get_or("ICON_CACHE_TTL", 2592000u64),
+            // icon_cache_negttl defaults to 3 days (3 * 24 * 60 * 60 seconds)
+            icon_cache_negttl: source.get_or("ICON_CACHE_NEGTTL", 259200u64),
+
+            mail,
+        }
+    }
+}
+
+/// Header used by the trusted proxies to send the address of the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpHeader {
+    XRealIp,
+    XForwardedFor,
+    Forwarded,
+}
+
+#[derive(Debug)]
+pub struct SsoConfig {
+    pub authority: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: String,
+    pub organization: Option<String>,
+    pub allow_unverified_email: bool,
+}
+
+impl SsoConfig {
+    fn load(source: &mut ConfigSource) -> Option<Self> {
+        if !source.get_or("SSO_ENABLED", false) {
+            return None;
+        }
+
+        let authority: Option<String> = source.get("SSO_AUTHORITY");
+        let client_id = source.get("SSO_CLIENT_ID");
+
+        let (authority, client_id) = match (authority, client_id) {
+            (Some(authority), Some(client_id)) => (authority, client_id),
+            _ => {
+                source.error("SSO_ENABLED requires SSO_AUTHORITY and SSO_CLIENT_ID to be set.".into());
+                return None;
+            }
+        };
+
+        // The provider's keys are downloaded from it, so they can only be trusted over TLS.
+        // Plain HTTP is accepted for a provider on the same machine, like a local mock server
+        let secure = match reqwest::Url::parse(&authority) {
+            Ok(url) => match url.scheme() {
+                "https" => true,
+                "http" => match url.host_str() {
+                    Some("localhost") | Some("127.0.0.1") | Some("[::1]") => true,
+                    _ => false,
+                },
+                _ => false,
+            },
+            Err(_) => false,
+        };
+        if !secure {
+            source.error("SSO_AUTHORITY must be an https:// URL, plain http:// is only allowed for localhost.".into());
+            return None;
+        }
+
+        Some(SsoConfig {
+            // The discovery document is found relative to the issuer, which never ends with a slash
+            authority: authority.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret: source.get("SSO_CLIENT_SECRET"),
+            scopes: source.get_or("SSO_SCOPES", "openid email profile".to_string()),
+            organization: source.get("SSO_ORGANIZATION"),
+            allow_unverified_email: source.get_or("SSO_ALLOW_UNVERIFIED_EMAIL", false),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub database_url: String,
+    pub icon_cache_folder: String,
+    pub attachments_folder: String,
+    pub sends_folder: String,
+    pub tmp_folder: String,
+
+    pub rsa_keys_folder: String,
+    pub legacy_rsa_key: String,
+
+    pub web_vault_folder: String,
+    pub web_vault_enabled: bool,
+
+    pub websocket_enabled: bool,
+    pub websocket_url: String,
+
+    pub extended_logging: bool,
+    pub log_file: Option<String>,
+
+    pub local_icon_extractor: bool,
+    pub server_admin_email: Option<String>,
+    pub invitation_expiration_hours: i64,
+    pub trash_auto_delete_days: Option<i64>,
+    pub revision_retention_days: i32,
+    pub import_stream_limit: u64,
+    pub sends_allowed: bool,
+    pub emergency_access_allowed: bool,
+    pub emergency_reminder_hours: i64,
+    pub client_kdf_min_iterations: i32,
+    pub password_iterations: i32,
+    pub password_algorithm: PasswordAlgorithm,
+    pub argon2_params: Argon2Params,
+    pub security_notifications: bool,
+
+    pub access_token_validity: i64,
+    pub refresh_token_absolute_lifetime: i64,
+    pub refresh_token_idle_lifetime: i64,
+
+    pub domain: String,
+    pub domain_set: bool,
+
+    pub trusted_proxies: Vec<IpNetwork>,
+    pub ip_header: IpHeader,
+
+    pub yubico_cred_set: bool,
+    pub yubico_client_id: String,
+    pub yubico_secret_key: String,
+    pub yubico_server: Option<String>,
+
+    pub sso: Option<SsoConfig>,
+
+    runtime: RwLock<RuntimeConfig>,
+}
+
+impl Config {
+    /// Loads the config from the environment variables and the config file, returning all the invalid settings
+    pub fn load() -> Result<Self, Vec<String>> {
+        let mut source = ConfigSource::load();
+
+        let df = source.get_or("DATA_FOLDER", "data".to_string());
+        let key = source.get_or("RSA_KEY_FILENAME", format!("{}/{}", &df, "rsa_key"));
+
+        let domain = source.get("DOMAIN");
+
+        // Access tokens are valid for 2 hours by default, refresh tokens expire after 30 days without use
+        let access_token_validity = source.get_or("ACCESS_TOKEN_VALIDITY", 7200i64);
+        let refresh_token_absolute_lifetime = source.get_or("REFRESH_TOKEN_ABSOLUTE_LIFETIME", 0i64);
+        let refresh_token_idle_lifetime = source.get_or("REFRESH_TOKEN_IDLE_LIFETIME", 2592000i64);
+
+        if access_token_validity <= 0 {
+            source.error("ACCESS_TOKEN_VALIDITY must be greater than zero.".into());
+        }
+        if refresh_token_absolute_lifetime < 0 || refresh_token_idle_lifetime < 0 {
+            source.error("REFRESH_TOKEN_ABSOLUTE_LIFETIME and REFRESH_TOKEN_IDLE_LIFETIME can't be negative.".into());
+        }
+
+        let password_algorithm = match source.get_or("PASSWORD_ALGORITHM", "pbkdf2".to_string()).to_lowercase().as_str() {
+            "pbkdf2" => PasswordAlgorithm::Pbkdf2,
+            "argon2id" => PasswordAlgorithm::Argon2id,
+            other => {
+                source.error(format!("Invalid PASSWORD_ALGORITHM '{}', use pbkdf2 or argon2id", other));
+                PasswordAlgorithm::Pbkdf2
+            }
+        };
+
+        // Defaults to 64 MiB of memory, 3 passes and 4 lanes
+        let argon2_params = Argon2Params {
+            memory: source.get_or("ARGON2_MEMORY", 65536u32),
+            iterations: source.get_or("ARGON2_ITERATIONS", 3u32),
+            parallelism: source.get_or("ARGON2_PARALLELISM", 4u32),
+        };
+
+        if argon2_params.iterations < 1 || argon2_params.parallelism < 1 || argon2_params.memory < 8 * argon2_params.parallelism {
+            source.error("ARGON2_ITERATIONS and ARGON2_PARALLELISM must be at least 1, and ARGON2_MEMORY at least 8 times ARGON2_PARALLELISM.".into());
+        }
+
+        // Invitations expire after 5 days by default
+        let invitation_expiration_hours = source.get_or("INVITATION_EXPIRATION_HOURS", 120i64);
+        if invitation_expiration_hours <= 0 {
+            source.error("INVITATION_EXPIRATION_HOURS must be greater than zero.".into());
+        }
+
+        // Items stay in the trash until they are deleted by the user when this isn't set
+        let trash_auto_delete_days: Option<i64> = source.get("TRASH_AUTO_DELETE_DAYS");
+        if trash_auto_delete_days.map_or(false, |days| days <= 0) {
+            source.error("TRASH_AUTO_DELETE_DAYS must be greater than zero.".into());
+        }
+
+        // Zero disables the cipher history, organizations can override it
+        let revision_retention_days = source.get_or("REVISION_RETENTION_DAYS", 30);
+        if revision_retention_days < 0 || revision_retention_days > CipherRevision::MAX_RETENTION_DAYS {
+            source.error(format!("REVISION_RETENTION_DAYS must be between 0 and {}.", CipherRevision::MAX_RETENTION_DAYS));
+        }
+
+        // In MiB, streaming imports are saved to disk before they are imported
+        let import_stream_limit = source.get_or("IMPORT_STREAM_LIMIT", 512u64);
+        if import_stream_limit == 0 {
+            source.error("IMPORT_STREAM_LIMIT must be greater than zero.".into());
+        }
+
+        let emergency_reminder_hours = source.get_or("EMERGENCY_REMINDER_HOURS", 24i64);
+        if emergency_reminder_hours <= 0 {
+            source.error("EMERGENCY_REMINDER_HOURS must be greater than zero.".into());
+        }
+
+        let client_kdf_min_iterations = source.get_or("CLIENT_KDF_MIN_ITERATIONS", 5_000);
+        if client_kdf_min_iterations < 1 || client_kdf_min_iterations > 2_000_000 {
+            source.error("CLIENT_KDF_MIN_ITERATIONS must be between 1 and 2000000.".into());
+        }
+
+        let trusted_proxies = source
+            .get_or("TRUSTED_PROXIES", String::new())
+            .split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .filter_map(|network| match network.parse() {
+                Ok(network) => Some(network),
+                Err(e) => {
+                    source.error(format!("Invalid TRUSTED_PROXIES: {}", e));
+                    None
+                }
+            })
+            .collect();
+
+        let ip_header = match source.get_or("IP_HEADER", "X-Real-IP".to_string()).to_lowercase().as_str() {
+            "x-real-ip" => IpHeader::XRealIp,
+            "x-forwarded-for" => IpHeader::XForwardedFor,
+            "forwarded" => IpHeader::Forwarded,
+            other => {
+                source.error(format!("Invalid IP_HEADER '{}', use X-Real-IP, X-Forwarded-For or Forwarded", other));
+                IpHeader::XRealIp
+            }
+        };
+
+        let yubico_client_id = source.get("YUBICO_CLIENT_ID");
+        let yubico_secret_key = source.get("YUBICO_SECRET_KEY");
+
+        let config = Config {
+            database_url: source.get_or("DATABASE_URL", format!("{}/{}", &df, "db.sqlite3")),
+            icon_cache_folder: source.get_or("ICON_CACHE_FOLDER", format!("{}/{}", &df, "icon_cache")),
+            attachments_folder: source.get_or("ATTACHMENTS_FOLDER", format!("{}/{}", &df, "attachments")),
+            sends_folder: source.get_or("SENDS_FOLDER", format!("{}/{}", &df, "sends")),
+            tmp_folder: source.get_or("TMP_FOLDER", format!("{}/{}", &df, "tmp")),
+
+            rsa_keys_folder: source.get_or("RSA_KEYS_FOLDER", format!("{}/{}", &df, "rsa_keys")),
+            // Single key used before key rotation was supported, imported on the first start
+            legacy_rsa_key: format!("{}.der", &key),
+
+            web_vault_folder: source.get_or("WEB_VAULT_FOLDER", "web-vault/".into()),
+            web_vault_enabled: source.get_or("WEB_VAULT_ENABLED", true),
+
+            websocket_enabled: source.get_or("WEBSOCKET_ENABLED", false),
+            websocket_url: format!(
+                "{}:{}",
+                source.get_or("WEBSOCKET_ADDRESS", "0.0.0.0".to_string()),
+                source.get_or("WEBSOCKET_PORT", 3012u16)
+            ),
+
+            extended_logging: source.get_or("EXTENDED_LOGGING", true),
+            log_file: source.get("LOG_FILE"),
+
+            local_icon_extractor: source.get_or("LOCAL_ICON_EXTRACTOR", false),
+            server_admin_email: source.get("SERVER_ADMIN_EMAIL"),
+            invitation_expiration_hours,
+            trash_auto_delete_days,
+            revision_retention_days,
+            import_stream_limit: import_stream_limit.saturating_mul(1024 * 1024),
+            sends_allowed: source.get_or("SENDS_ALLOWED", true),
+            emergency_access_allowed: source.get_or("EMERGENCY_ACCESS_ALLOWED", true),
+            emergency_reminder_hours,
+            client_kdf_min_iterations,
+            password_iterations: source.get_or("PASSWORD_ITERATIONS", 100_000),
+            password_algorithm,
+            argon2_params,
+            security_notifications: source.get_or("SECURITY_NOTIFICATIONS", false),
+
+            access_token_validity,
+            refresh_token_absolute_lifetime,
+            refresh_token_idle_lifetime,
+
+            domain_set: domain.is_some(),
+            domain: domain.unwrap_or_else(|| "http://localhost".into()),
+
+            trusted_proxies,
+            ip_header,
+
+            yubico_cred_set: yubico_client_id.is_some() && yubico_secret_key.is_some(),
+            yubico_client_id: yubico_client_id.unwrap_or_else(|| "00000".into()),
+            yubico_secret_key: yubico_secret_key.unwrap_or_else(|| "AAAAAAA".into()),
+            yubico_server: source.get("YUBICO_SERVER"),
+
+            sso: SsoConfig::load(&mut source),
+
+            runtime: RwLock::new(RuntimeConfig::load(&mut source)),
+        };
+
+        source.finish().and(Ok(config))
+    }
+
+    /// Reads the config again and applies the settings that can change at runtime.
+    /// The other settings are ignored until the server is restarted.
+    pub fn reload(&self) -> Result<(), Vec<String>> {
+        let mut source = ConfigSource::load();
+        let runtime = RuntimeConfig::load(&mut source);
+        source.finish()?;
+
+        if let Some(ref mail_config) = runtime.mail {
+            crate::mail::check_config(mail_config).map_err(|e| vec![format!("Invalid SMTP configuration: {}", e)])?;
+        }
+
+        *self.runtime.write().unwrap() = runtime;
+        Ok(())
+    }
+
+    pub fn signups_allowed(&self) -> bool {
+        self.runtime.read().unwrap().signups_allowed
+    }
+
+    /// Checks if a new user can register with this email without an invitation,
+    /// which requires signups to be allowed and the email domain to be whitelisted, if there is a whitelist
+    pub fn is_signup_allowed(&self, email: &str) -> bool {
+        let runtime = self.runtime.read().unwrap();
+
+        if !runtime.signups_allowed {
+            return false;
+        }
+
+        if runtime.signups_domains_whitelist.is_empty() {
+            return true;
+        }
+
+        match email.rsplit('@').next() {
+            Some(domain) if email.contains('@') => {
+                let domain = domain.to_lowercase();
+                runtime.signups_domains_whitelist.iter().any(|d| d == &domain)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn signups_verify(&self) -> bool {
+        self.runtime.read().unwrap().signups_verify
+    }
+
+    pub fn invitations_allowed(&self) -> bool {
+        self.runtime.read().unwrap().invitations_allowed
+    }
+
+    pub fn show_password_hint(&self) -> bool {
+        self.runtime.read().unwrap().show_password_hint
+    }
+
+    pub fn icon_cache_ttl(&self) -> u64 {
+        self.runtime.read().unwrap().icon_cache_ttl
+    }
+
+    pub fn icon_cache_negttl(&self) -> u64 {
+        self.runtime.read().unwrap().icon_cache_negttl
+    }
+
+    pub fn mail(&self) -> Option<Arc<MailConfig>> {
+        self.runtime.read().unwrap().mail.clone()
+    }
+
+    pub fn mail_enabled(&self) -> bool {
+        self.runtime.read().unwrap().mail.is_some()
+    }
+
+    /// The current values of the settings that can change at runtime, without secrets
+    pub fn runtime_json(&self) -> Value {
+        let runtime = self.runtime.read().unwrap();
+
+        json!({
+            "SignupsAllowed": runtime.signups_allowed,
+            "SignupsDomainsWhitelist": runtime.signups_domains_whitelist,
+            "SignupsVerify": runtime.signups_verify,
+            "InvitationsAllowed": runtime.invitations_allowed,
+            "ShowPasswordHint": runtime.show_password_hint,
+            "IconCacheTtl": runtime.icon_cache_ttl,
+            "IconCacheNegTtl": runtime.icon_cache_negttl,
+            "MailEnabled": runtime.mail.is_some(),
+            "SmtpHost": runtime.mail.as_ref().map(|m| m.smtp_host.clone()),
+        })
+    }
+}
@@ -19,6 +19,58 @@ pub fn verify_password_hash(secret: &[u8], salt: &[u8], previous: &[u8], iterati
     pbkdf2::verify(DIGEST_ALG, iterations, salt, secret, previous).is_ok()
 }
 
+///
+/// Argon2id derivation
+///
+
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive)]
+pub enum PasswordAlgorithm {
+    Pbkdf2 = 0,
+    Argon2id = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Argon2Params {
+    pub memory: u32, // KiB
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Argon2Params {
+    fn config(&self) -> argon2::Config {
+        argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            version: argon2::Version::Version13,
+            mem_cost: self.memory,
+            time_cost: self.iterations,
+            lanes: self.parallelism,
+            thread_mode: argon2::ThreadMode::Parallel,
+            secret: &[],
+            ad: &[],
+            hash_length: OUTPUT_LEN as u32,
+        }
+    }
+
+    /// Prefix of the hashes created with these parameters, like `$argon2id$v=19$m=65536,t=3,p=4$`
+    pub fn encoded_prefix(&self) -> String {
+        format!("$argon2id$v=19$m={},t={},p={}$", self.memory, self.iterations, self.parallelism)
+    }
+}
+
+/// Returns the hash in the PHC string format, which includes the parameters and the salt
+pub fn hash_password_argon2(secret: &[u8], salt: &[u8], params: &Argon2Params) -> Vec<u8> {
+    argon2::hash_encoded(secret, salt, &params.config())
+        .expect("Error hashing password with Argon2")
+        .into_bytes()
+}
+
+pub fn verify_password_argon2(secret: &[u8], encoded: &[u8]) -> bool {
+    match std::str::from_utf8(encoded) {
+        Ok(encoded) => argon2::verify_encoded(encoded, secret).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
 ///
 /// Random values
 ///
@@ -34,3 +86,55 @@ pub fn get_random(mut array: Vec<u8>) -> Vec<u8> {
 
     array
 }
+
+///
+/// RSA keys
+///
+
+pub struct RsaKey {
+    pub kid: String,
+    pub private_der: Vec<u8>,
+    pub public_der: Vec<u8>,
+    // Modulus and exponent, for the JWK representation
+    pub n: Vec<u8>,
+    pub e: Vec<u8>,
+}
+
+pub fn generate_rsa_key() -> Result<Vec<u8>, String> {
+    use openssl::rsa::Rsa;
+
+    let rsa = Rsa::generate(2048).map_err(|e| e.to_string())?;
+    rsa.private_key_to_pem().map_err(|e| e.to_string())
+}
+
+pub fn rsa_der_to_pem(private_der: &[u8]) -> Result<Vec<u8>, String> {
+    use openssl::rsa::Rsa;
+
+    let rsa = Rsa::private_key_from_der(private_der).map_err(|e| e.to_string())?;
+    rsa.private_key_to_pem().map_err(|e| e.to_string())
+}
+
+/// Builds the PKCS#1 DER public key from its modulus and exponent, as found in a JSON Web Key
+pub fn rsa_public_der(n: &[u8], e: &[u8]) -> Result<Vec<u8>, String> {
+    use openssl::bn::BigNum;
+    use openssl::rsa::Rsa;
+
+    let n = BigNum::from_slice(n).map_err(|e| e.to_string())?;
+    let e = BigNum::from_slice(e).map_err(|e| e.to_string())?;
+    let rsa = Rsa::from_public_components(n, e).map_err(|e| e.to_string())?;
+    rsa.public_key_to_der_pkcs1().map_err(|e| e.to_string())
+}
+
+pub fn load_rsa_key(kid: String, private_pem: &[u8]) -> Result<RsaKey, String> {
+    use openssl::rsa::Rsa;
+
+    let rsa = Rsa::private_key_from_pem(private_pem).map_err(|e| e.to_string())?;
+
+    Ok(RsaKey {
+        kid,
+        private_der: rsa.private_key_to_der().map_err(|e| e.to_string())?,
+        public_der: rsa.public_key_to_der_pkcs1().map_err(|e| e.to_string())?,
+        n: rsa.n().to_vec(),
+        e: rsa.e().to_vec(),
+    })
+}
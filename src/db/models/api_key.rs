@@ -0,0 +1,150 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::crypto;
+
+#[derive(Debug, Identifiable, Queryable, Insertable)]
+#[table_name = "api_keys"]
+#[primary_key(uuid)]
+pub struct ApiKey {
+    pub uuid: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+
+    pub user_uuid: Option<String>,
+    pub org_uuid: Option<String>,
+
+    pub client_id: String,
+    pub secret_hash: Vec<u8>,
+    pub secret_salt: Vec<u8>,
+    // Incremented on every rotation, the tokens issued with an older revision are rejected
+    pub revision: i32,
+}
+
+/// Local methods
+impl ApiKey {
+    pub const USER_PREFIX: &'static str = "user.";
+    pub const ORG_PREFIX: &'static str = "organization.";
+
+    pub const USER_SCOPE: &'static str = "api";
+    pub const ORG_SCOPE: &'static str = "api.organization";
+
+    const SECRET_ITERATIONS: u32 = 10_000;
+
+    fn new(user_uuid: Option<String>, org_uuid: Option<String>, client_id: String) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            uuid: crate::util::get_uuid(),
+            created_at: now,
+            updated_at: now,
+
+            user_uuid,
+            org_uuid,
+
+            client_id,
+            secret_hash: Vec::new(),
+            secret_salt: Vec::new(),
+            revision: 0,
+        }
+    }
+
+    pub fn new_for_user(user_uuid: String) -> Self {
+        let client_id = format!("{}{}", Self::USER_PREFIX, user_uuid);
+        Self::new(Some(user_uuid), None, client_id)
+    }
+
+    pub fn new_for_org(org_uuid: String) -> Self {
+        let client_id = format!("{}{}", Self::ORG_PREFIX, org_uuid);
+        Self::new(None, Some(org_uuid), client_id)
+    }
+
+    /// Generates a new secret, which is only stored hashed and needs to be shown to the user now
+    pub fn rotate_secret(&mut self) -> String {
+        use data_encoding::BASE64URL_NOPAD;
+
+        let secret = BASE64URL_NOPAD.encode(&crypto::get_random(vec![0u8; 24]));
+
+        self.secret_salt = crypto::get_random_64();
+        self.secret_hash = crypto::hash_password(secret.as_bytes(), &self.secret_salt, Self::SECRET_ITERATIONS);
+        self.updated_at = Utc::now().naive_utc();
+        self.revision += 1;
+
+        secret
+    }
+
+    pub fn check_secret(&self, secret: &str) -> bool {
+        crypto::verify_password_hash(secret.as_bytes(), &self.secret_salt, &self.secret_hash, Self::SECRET_ITERATIONS)
+    }
+
+    pub fn scope(&self) -> &'static str {
+        if self.org_uuid.is_some() {
+            Self::ORG_SCOPE
+        } else {
+            Self::USER_SCOPE
+        }
+    }
+
+    /// The secret is only included right after it's generated
+    pub fn to_json(&self, secret: Option<&str>) -> Value {
+        json!({
+            "ClientId": self.client_id,
+            "ApiKey": secret,
+            "Scope": self.scope(),
+            "CreationDate": crate::util::format_date(&self.created_at),
+            "RevisionDate": crate::util::format_date(&self.updated_at),
+            "Object": "apiKey",
+        })
+    }
+}
+
+use diesel;
+use diesel::prelude::*;
+use crate::db::DbConn;
+use crate::db::schema::api_keys;
+
+/// Database methods
+impl ApiKey {
+    pub fn save(&self, conn: &DbConn) -> QueryResult<()> {
+        diesel::replace_into(api_keys::table)
+            .values(self)
+            .execute(&**conn)
+            .and(Ok(()))
+    }
+
+    pub fn delete(self, conn: &DbConn) -> QueryResult<()> {
+        diesel::delete(
+            api_keys::table.filter(
+                api_keys::uuid.eq(self.uuid)
+            )
+        ).execute(&**conn).and(Ok(()))
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> QueryResult<()> {
+        diesel::delete(api_keys::table.filter(api_keys::user_uuid.eq(user_uuid)))
+            .execute(&**conn).and(Ok(()))
+    }
+
+    pub fn delete_all_by_organization(org_uuid: &str, conn: &DbConn) -> QueryResult<()> {
+        diesel::delete(api_keys::table.filter(api_keys::org_uuid.eq(org_uuid)))
+            .execute(&**conn).and(Ok(()))
+    }
+
+    pub fn find_by_client_id(client_id: &str, conn: &DbConn) -> Option<Self> {
+        api_keys::table
+            .filter(api_keys::client_id.eq(client_id))
+            .first::<Self>(&**conn).ok()
+    }
+
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        api_keys::table
+            .filter(api_keys::user_uuid.eq(user_uuid))
+            .first::<Self>(&**conn).ok()
+    }
+
+    pub fn find_by_organization(org_uuid: &str, conn: &DbConn) -> Option<Self> {
+        api_keys::table
+            .filter(api_keys::org_uuid.eq(org_uuid))
+            .first::<Self>(&**conn).ok()
+    }
+}
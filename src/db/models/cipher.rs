@@ -1,7 +1,7 @@
 use chrono::{NaiveDateTime, Utc};
 use serde_json::Value;
 
-use super::{User, Organization, Attachment, FolderCipher, CollectionCipher, UserOrganization, UserOrgType, UserOrgStatus};
+use super::{User, Organization, Attachment, CipherRevision, FolderCipher, CollectionCipher, UserOrganization, UserOrgType, UserOrgStatus};
 
 #[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
 #[table_name = "ciphers"]
@@ -31,6 +31,9 @@ pub struct Cipher {
 
     pub favorite: bool,
     pub password_history: Option<String>,
+
+    // Set while the cipher is in the trash
+    pub deleted_at: Option<NaiveDateTime>,
 }
 
 /// Local methods
@@ -55,6 +58,8 @@ impl Cipher {
 
             data: String::new(),
             password_history: None,
+
+            deleted_at: None,
         }
     }
 }
@@ -97,6 +102,7 @@ impl Cipher {
             "Id": self.uuid,
             "Type": self.type_,
             "RevisionDate": format_date(&self.updated_at),
+            "DeletedDate": self.deleted_at.as_ref().map(format_date),
             "FolderId": self.get_folder_uuid(&user_uuid, &conn),
             "Favorite": self.favorite,
             "OrganizationId": self.organization_uuid,
@@ -165,6 +171,7 @@ impl Cipher {
         FolderCipher::delete_all_by_cipher(&self.uuid, &conn)?;
         CollectionCipher::delete_all_by_cipher(&self.uuid, &conn)?;
         Attachment::delete_all_by_cipher(&self.uuid, &conn)?;
+        CipherRevision::delete_all_by_cipher(&self.uuid, &conn)?;
 
         diesel::delete(
             ciphers::table.filter(
@@ -334,6 +341,13 @@ impl Cipher {
             .load::<Self>(&**conn).expect("Error loading ciphers")
     }
 
+    /// Ciphers that have been in the trash since before the given date
+    pub fn find_deleted_before(date: &NaiveDateTime, conn: &DbConn) -> QueryResult<Vec<Self>> {
+        ciphers::table
+            .filter(ciphers::deleted_at.lt(date))
+            .load::<Self>(&**conn)
+    }
+
     pub fn find_by_folder(folder_uuid: &str, conn: &DbConn) -> Vec<Self> {
         folders_ciphers::table.inner_join(ciphers::table)
             .filter(folders_ciphers::folder_uuid.eq(folder_uuid))
@@ -363,4 +377,11 @@ impl Cipher {
         .select(ciphers_collections::collection_uuid)
         .load::<String>(&**conn).unwrap_or_default()
     }
+
+    pub fn get_all_collections(&self, conn: &DbConn) -> Vec<String> {
+        ciphers_collections::table
+        .filter(ciphers_collections::cipher_uuid.eq(&self.uuid))
+        .select(ciphers_collections::collection_uuid)
+        .load::<String>(&**conn).unwrap_or_default()
+    }
 }
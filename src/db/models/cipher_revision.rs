@@ -0,0 +1,175 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::{Cipher, Organization};
+use crate::CONFIG;
+
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "cipher_revisions"]
+#[belongs_to(Cipher, foreign_key = "cipher_uuid")]
+#[primary_key(uuid)]
+pub struct CipherRevision {
+    pub uuid: String,
+    pub cipher_uuid: String,
+    // The user whose change replaced this revision
+    pub author_uuid: Option<String>,
+    // When this revision was saved, and when it was replaced
+    pub revision_date: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+
+    pub name: String,
+    pub notes: Option<String>,
+    pub fields: Option<String>,
+    pub data: String,
+}
+
+/// Local methods
+impl CipherRevision {
+    /// About a hundred years, longer retentions would overflow the dates
+    pub const MAX_RETENTION_DAYS: i32 = 36_500;
+
+    pub fn new(cipher: &Cipher, author_uuid: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            cipher_uuid: cipher.uuid.clone(),
+            author_uuid: Some(author_uuid),
+            revision_date: cipher.updated_at,
+            created_at: Utc::now().naive_utc(),
+
+            name: cipher.name.clone(),
+            notes: cipher.notes.clone(),
+            fields: cipher.fields.clone(),
+            data: cipher.data.clone(),
+        }
+    }
+
+    /// Puts the contents of this revision back into the cipher
+    pub fn apply_to(&self, cipher: &mut Cipher) {
+        cipher.name = self.name.clone();
+        cipher.notes = self.notes.clone();
+        cipher.fields = self.fields.clone();
+        cipher.data = self.data.clone();
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        let fields_json: Value = self.fields.as_ref()
+            .and_then(|fields| serde_json::from_str(fields).ok())
+            .unwrap_or(Value::Null);
+        let data_json: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+
+        json!({
+            "Id": self.uuid,
+            "CipherId": self.cipher_uuid,
+            "AuthorId": self.author_uuid,
+            "RevisionDate": format_date(&self.revision_date),
+            "ReplacedDate": format_date(&self.created_at),
+            "Name": self.name,
+            "Notes": self.notes,
+            "Fields": fields_json,
+            "Data": data_json,
+            "Object": "cipherRevision",
+        })
+    }
+}
+
+use diesel;
+use diesel::prelude::*;
+use crate::db::DbConn;
+use crate::db::schema::cipher_revisions;
+
+/// Database methods
+impl CipherRevision {
+    pub fn save(&self, conn: &DbConn) -> QueryResult<()> {
+        diesel::replace_into(cipher_revisions::table)
+            .values(self)
+            .execute(&**conn)
+            .and(Ok(()))
+    }
+
+    /// Number of days the revisions of the cipher are kept, zero when the history is disabled
+    pub fn retention_days(cipher: &Cipher, conn: &DbConn) -> i32 {
+        cipher.organization_uuid.as_ref()
+            .and_then(|org_uuid| Organization::find_by_uuid(org_uuid, conn))
+            .and_then(|org| org.revision_retention_days)
+            .unwrap_or(CONFIG.revision_retention_days)
+    }
+
+    /// Saves the current contents of the cipher before they are replaced, and removes the revisions past the retention
+    pub fn archive(cipher: &Cipher, author_uuid: &str, conn: &DbConn) -> QueryResult<()> {
+        let days = Self::retention_days(cipher, conn);
+        if days > 0 {
+            Self::new(cipher, author_uuid.to_string()).save(conn)?;
+        }
+
+        Self::delete_expired(&cipher.uuid, days, conn)
+    }
+
+    pub fn delete_expired(cipher_uuid: &str, days: i32, conn: &DbConn) -> QueryResult<()> {
+        diesel::delete(
+            cipher_revisions::table
+                .filter(cipher_revisions::cipher_uuid.eq(cipher_uuid))
+                .filter(cipher_revisions::created_at.lt(Self::retention_limit(days)))
+        ).execute(&**conn).and(Ok(()))
+    }
+
+    /// Removes the revisions past the retention of every cipher, including the ciphers that haven't changed since
+    pub fn purge_expired(conn: &DbConn) -> QueryResult<()> {
+        use crate::db::schema::{ciphers, organizations};
+
+        // The personal ciphers use the server retention
+        diesel::delete(
+            cipher_revisions::table
+                .filter(cipher_revisions::cipher_uuid.eq_any(
+                    ciphers::table.filter(ciphers::organization_uuid.is_null()).select(ciphers::uuid)
+                ))
+                .filter(cipher_revisions::created_at.lt(Self::retention_limit(CONFIG.revision_retention_days)))
+        ).execute(&**conn)?;
+
+        let orgs = organizations::table
+            .select((organizations::uuid, organizations::revision_retention_days))
+            .load::<(String, Option<i32>)>(&**conn)?;
+
+        for (org_uuid, days) in orgs {
+            let limit = Self::retention_limit(days.unwrap_or(CONFIG.revision_retention_days));
+
+            diesel::delete(
+                cipher_revisions::table
+                    .filter(cipher_revisions::cipher_uuid.eq_any(
+                        ciphers::table.filter(ciphers::organization_uuid.eq(org_uuid)).select(ciphers::uuid)
+                    ))
+                    .filter(cipher_revisions::created_at.lt(limit))
+            ).execute(&**conn)?;
+        }
+
+        Ok(())
+    }
+
+    fn retention_limit(days: i32) -> NaiveDateTime {
+        // Retentions saved before the maximum was enforced are capped as well
+        Utc::now().naive_utc() - Duration::days(i64::from(days.min(Self::MAX_RETENTION_DAYS)))
+    }
+
+    pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> QueryResult<()> {
+        diesel::delete(
+            cipher_revisions::table.filter(
+                cipher_revisions::cipher_uuid.eq(cipher_uuid)
+            )
+        ).execute(&**conn).and(Ok(()))
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        cipher_revisions::table
+            .filter(cipher_revisions::uuid.eq(uuid))
+            .first::<Self>(&**conn).ok()
+    }
+
+    /// The revisions of the cipher, newest first
+    pub fn find_by_cipher(cipher_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        cipher_revisions::table
+            .filter(cipher_revisions::cipher_uuid.eq(cipher_uuid))
+            .order(cipher_revisions::created_at.desc())
+            .load::<Self>(&**conn).expect("Error loading cipher revisions")
+    }
+}
@@ -1,4 +1,5 @@
 use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
 
 use super::User;
 
@@ -21,6 +22,12 @@ pub struct Device {
     pub refresh_token: String,
 
     pub twofactor_remember: Option<String>,
+
+    pub last_ip: Option<String>,
+
+    pub refresh_token_issued_at: Option<NaiveDateTime>,
+    pub refresh_token_used_at: Option<NaiveDateTime>,
+    pub previous_refresh_token: Option<String>,
 }
 
 /// Local methods
@@ -40,6 +47,11 @@ impl Device {
             push_token: None,
             refresh_token: String::new(),
             twofactor_remember: None,
+            last_ip: None,
+
+            refresh_token_issued_at: None,
+            refresh_token_used_at: None,
+            previous_refresh_token: None,
         }
     }
 
@@ -57,19 +69,74 @@ impl Device {
         self.twofactor_remember = None;
     }
 
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "Type": self.type_,
+            "Identifier": self.uuid,
+            "CreationDate": crate::util::format_date(&self.created_at),
+            "RevisionDate": crate::util::format_date(&self.updated_at),
+            "LastIp": self.last_ip,
+            "TwoFactorRemembered": self.twofactor_remember.is_some(),
+            "Object": "device",
+        })
+    }
+
+
+    /// Starts a new session after the user has authenticated, discarding the previous refresh token.
+    /// The absolute lifetime of the refresh tokens is counted from this moment.
+    pub fn start_session(&mut self) {
+        self.refresh_token = String::new();
+        self.previous_refresh_token = None;
+        self.refresh_token_issued_at = Some(Utc::now().naive_utc());
+    }
+
+    /// Checks if the refresh token has been unused for too long, or if the session is too old
+    pub fn is_refresh_token_expired(&self) -> bool {
+        use chrono::Duration;
+        use crate::CONFIG;
+
+        let time_now = Utc::now().naive_utc();
+        let issued_at = self.refresh_token_issued_at.unwrap_or(self.created_at);
+        let used_at = self.refresh_token_used_at.unwrap_or(issued_at);
+
+        let absolute = CONFIG.refresh_token_absolute_lifetime;
+        let idle = CONFIG.refresh_token_idle_lifetime;
+
+        (absolute > 0 && time_now - issued_at > Duration::seconds(absolute))
+            || (idle > 0 && time_now - used_at > Duration::seconds(idle))
+    }
 
     pub fn refresh_tokens(&mut self, user: &super::User, orgs: Vec<super::UserOrganization>) -> (String, i64) {
-        // If there is no refresh token, we create one
-        if self.refresh_token.is_empty() {
-            use data_encoding::BASE64URL;
-            use crate::crypto;
+        use data_encoding::BASE64URL;
+        use crate::crypto;
 
-            self.refresh_token = BASE64URL.encode(&crypto::get_random_64());
+        // The refresh token is rotated every time it's used, the previous one is kept to detect if it's reused
+        let refresh_token = BASE64URL.encode(&crypto::get_random_64());
+        if self.refresh_token.is_empty() {
+            self.refresh_token = refresh_token;
+        } else {
+            self.previous_refresh_token = Some(std::mem::replace(&mut self.refresh_token, refresh_token));
         }
 
         // Update the expiration of the device and the last update date
         let time_now = Utc::now().naive_utc();
         self.updated_at = time_now;
+        self.refresh_token_used_at = Some(time_now);
+
+        if self.refresh_token_issued_at.is_none() {
+            self.refresh_token_issued_at = Some(time_now);
+        }
+
+        self.access_token(user, orgs, vec!["api".into(), "offline_access".into()], None)
+    }
+
+    /// Creates an access token for this device, without a refresh token.
+    /// The tokens issued with a user API key carry its revision, so rotating the key revokes them
+    pub fn access_token(&mut self, user: &super::User, orgs: Vec<super::UserOrganization>, scope: Vec<String>, api_key_revision: Option<i32>) -> (String, i64) {
+        let time_now = Utc::now().naive_utc();
+        self.updated_at = time_now;
 
         let orgowner: Vec<_> = orgs.iter().filter(|o| o.type_ == 0).map(|o| o.org_uuid.clone()).collect();
         let orgadmin: Vec<_> = orgs.iter().filter(|o| o.type_ == 1).map(|o| o.org_uuid.clone()).collect();
@@ -88,7 +155,7 @@ impl Device {
             premium: true,
             name: user.name.to_string(),
             email: user.email.to_string(),
-            email_verified: true,
+            email_verified: user.verified_at.is_some(),
 
             orgowner,
             orgadmin,
@@ -97,8 +164,10 @@ impl Device {
 
             sstamp: user.security_stamp.to_string(),
             device: self.uuid.to_string(),
-            scope: vec!["api".into(), "offline_access".into()],
+            scope,
             amr: vec!["Application".into()],
+
+            api_key_revision,
         };
 
         (encode_jwt(&claims), DEFAULT_VALIDITY.num_seconds())
@@ -132,6 +201,12 @@ impl Device {
         )).execute(&**conn).and(Ok(()))
     }
 
+    pub fn clear_twofactor_remember_by_user(user_uuid: &str, conn: &DbConn) -> QueryResult<()> {
+        diesel::update(devices::table.filter(devices::user_uuid.eq(user_uuid)))
+            .set(devices::twofactor_remember.eq(None::<String>))
+            .execute(&**conn).and(Ok(()))
+    }
+
     pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> QueryResult<()> {
         for device in Self::find_by_user(user_uuid, &conn) {
             device.delete(&conn)?;
@@ -145,12 +220,32 @@ impl Device {
             .first::<Self>(&**conn).ok()
     }
 
+    pub fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        devices::table
+            .filter(devices::uuid.eq(uuid))
+            .filter(devices::user_uuid.eq(user_uuid))
+            .first::<Self>(&**conn).ok()
+    }
+
     pub fn find_by_refresh_token(refresh_token: &str, conn: &DbConn) -> Option<Self> {
         devices::table
             .filter(devices::refresh_token.eq(refresh_token))
             .first::<Self>(&**conn).ok()
     }
 
+    pub fn find_by_previous_refresh_token(refresh_token: &str, conn: &DbConn) -> Option<Self> {
+        devices::table
+            .filter(devices::previous_refresh_token.eq(refresh_token))
+            .first::<Self>(&**conn).ok()
+    }
+
+    pub fn find_by_user_and_ip(user_uuid: &str, ip: &str, conn: &DbConn) -> Option<Self> {
+        devices::table
+            .filter(devices::user_uuid.eq(user_uuid))
+            .filter(devices::last_ip.eq(ip))
+            .first::<Self>(&**conn).ok()
+    }
+
     pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
         devices::table
             .filter(devices::user_uuid.eq(user_uuid))
@@ -0,0 +1,210 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::User;
+
+#[derive(Debug, Identifiable, Queryable, Insertable)]
+#[table_name = "emergency_access"]
+#[primary_key(uuid)]
+pub struct EmergencyAccess {
+    pub uuid: String,
+    pub grantor_uuid: String,
+    // Not set until the invitation is accepted
+    pub grantee_uuid: Option<String>,
+    // The invited email, only used until the invitation is accepted
+    pub email: Option<String>,
+    // The key of the grantor, encrypted with the public key of the grantee
+    pub key_encrypted: Option<String>,
+    pub type_: i32,
+    pub status: i32,
+    pub wait_time_days: i32,
+    pub recovery_initiated_at: Option<NaiveDateTime>,
+    pub last_notification_at: Option<NaiveDateTime>,
+    pub updated_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum EmergencyAccessType {
+    View = 0,
+    Takeover = 1,
+}
+
+impl EmergencyAccessType {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(EmergencyAccessType::View),
+            1 => Some(EmergencyAccessType::Takeover),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum EmergencyAccessStatus {
+    Invited = 0,
+    Accepted = 1,
+    Confirmed = 2,
+    RecoveryInitiated = 3,
+    RecoveryApproved = 4,
+}
+
+/// Local methods
+impl EmergencyAccess {
+    pub fn new(grantor_uuid: String, email: String, type_: EmergencyAccessType, wait_time_days: i32) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            uuid: crate::util::get_uuid(),
+            grantor_uuid,
+            grantee_uuid: None,
+            email: Some(email),
+            key_encrypted: None,
+            type_: type_ as i32,
+            status: EmergencyAccessStatus::Invited as i32,
+            wait_time_days,
+            recovery_initiated_at: None,
+            last_notification_at: None,
+            updated_at: now,
+            created_at: now,
+        }
+    }
+
+    pub fn has_status(&self, status: EmergencyAccessStatus) -> bool {
+        self.status == status as i32
+    }
+
+    pub fn has_type(&self, type_: EmergencyAccessType) -> bool {
+        self.type_ == type_ as i32
+    }
+
+    /// When the recovery is approved automatically if the grantor doesn't reject it
+    pub fn recovery_deadline(&self) -> Option<NaiveDateTime> {
+        self.recovery_initiated_at
+            .map(|date| date + Duration::days(i64::from(self.wait_time_days)))
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Id": self.uuid,
+            "Status": self.status,
+            "Type": self.type_,
+            "WaitTimeDays": self.wait_time_days,
+            "Object": "emergencyAccess",
+        })
+    }
+}
+
+use diesel;
+use diesel::prelude::*;
+use crate::db::DbConn;
+use crate::db::schema::emergency_access;
+
+/// Database methods
+impl EmergencyAccess {
+    /// What the grantor sees about the grantee
+    pub fn to_json_grantee_details(&self, conn: &DbConn) -> Value {
+        let grantee = self.grantee_uuid.as_ref().and_then(|uuid| User::find_by_uuid(uuid, conn));
+        let (email, name) = match grantee {
+            Some(grantee) => (Some(grantee.email), Some(grantee.name)),
+            None => (self.email.clone(), None),
+        };
+
+        json!({
+            "Id": self.uuid,
+            "Status": self.status,
+            "Type": self.type_,
+            "WaitTimeDays": self.wait_time_days,
+            "GranteeId": self.grantee_uuid,
+            "Email": email,
+            "Name": name,
+            "Object": "emergencyAccessGranteeDetails",
+        })
+    }
+
+    /// What the grantee sees about the grantor
+    pub fn to_json_grantor_details(&self, conn: &DbConn) -> Value {
+        let grantor = User::find_by_uuid(&self.grantor_uuid, conn);
+
+        json!({
+            "Id": self.uuid,
+            "Status": self.status,
+            "Type": self.type_,
+            "WaitTimeDays": self.wait_time_days,
+            "GrantorId": self.grantor_uuid,
+            "Email": grantor.as_ref().map(|g| &g.email),
+            "Name": grantor.as_ref().map(|g| &g.name),
+            "Object": "emergencyAccessGrantorDetails",
+        })
+    }
+
+    pub fn save(&mut self, conn: &DbConn) -> QueryResult<()> {
+        User::update_uuid_revision(&self.grantor_uuid, conn);
+        self.updated_at = Utc::now().naive_utc();
+
+        diesel::replace_into(emergency_access::table)
+            .values(&*self)
+            .execute(&**conn)
+            .and(Ok(()))
+    }
+
+    pub fn delete(self, conn: &DbConn) -> QueryResult<()> {
+        User::update_uuid_revision(&self.grantor_uuid, conn);
+
+        diesel::delete(
+            emergency_access::table.filter(
+                emergency_access::uuid.eq(self.uuid)
+            )
+        ).execute(&**conn).and(Ok(()))
+    }
+
+    /// Removes the accesses given by the user and the ones given to the user
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> QueryResult<()> {
+        diesel::delete(
+            emergency_access::table.filter(
+                emergency_access::grantor_uuid.eq(user_uuid)
+                .or(emergency_access::grantee_uuid.eq(user_uuid))
+            )
+        ).execute(&**conn).and(Ok(()))
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        emergency_access::table
+            .filter(emergency_access::uuid.eq(uuid))
+            .first::<Self>(&**conn).ok()
+    }
+
+    pub fn find_by_grantor_and_email(grantor_uuid: &str, email: &str, conn: &DbConn) -> Option<Self> {
+        emergency_access::table
+            .filter(emergency_access::grantor_uuid.eq(grantor_uuid))
+            .filter(emergency_access::email.eq(email))
+            .first::<Self>(&**conn).ok()
+    }
+
+    pub fn find_by_grantor_and_grantee(grantor_uuid: &str, grantee_uuid: &str, conn: &DbConn) -> Option<Self> {
+        emergency_access::table
+            .filter(emergency_access::grantor_uuid.eq(grantor_uuid))
+            .filter(emergency_access::grantee_uuid.eq(grantee_uuid))
+            .first::<Self>(&**conn).ok()
+    }
+
+    pub fn find_by_grantor(grantor_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        emergency_access::table
+            .filter(emergency_access::grantor_uuid.eq(grantor_uuid))
+            .load::<Self>(&**conn).expect("Error loading emergency accesses")
+    }
+
+    pub fn find_by_grantee(grantee_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        emergency_access::table
+            .filter(emergency_access::grantee_uuid.eq(grantee_uuid))
+            .load::<Self>(&**conn).expect("Error loading emergency accesses")
+    }
+
+    /// The recoveries waiting for the grantor to approve or reject them
+    pub fn find_recoveries_initiated(conn: &DbConn) -> QueryResult<Vec<Self>> {
+        emergency_access::table
+            .filter(emergency_access::status.eq(EmergencyAccessStatus::RecoveryInitiated as i32))
+            .filter(emergency_access::recovery_initiated_at.is_not_null())
+            .load::<Self>(&**conn)
+    }
+}
@@ -1,5 +1,6 @@
 mod attachment;
 mod cipher;
+mod cipher_revision;
 mod device;
 mod folder;
 mod user;
@@ -7,13 +8,24 @@ mod user;
 mod collection;
 mod organization;
 mod two_factor;
+mod queued_mail;
+mod api_key;
+mod sso_auth;
+mod send;
+mod emergency_access;
 
 pub use self::attachment::Attachment;
 pub use self::cipher::Cipher;
+pub use self::cipher_revision::CipherRevision;
 pub use self::device::Device;
 pub use self::folder::{Folder, FolderCipher};
 pub use self::user::{User, Invitation};
 pub use self::organization::Organization;
 pub use self::organization::{UserOrganization, UserOrgStatus, UserOrgType};
 pub use self::collection::{Collection, CollectionUser, CollectionCipher};
-pub use self::two_factor::{TwoFactor, TwoFactorType};
\ No newline at end of file
+pub use self::two_factor::{TwoFactor, TwoFactorType};
+pub use self::queued_mail::QueuedMail;
+pub use self::api_key::ApiKey;
+pub use self::sso_auth::SsoAuth;
+pub use self::send::{Send, SendType};
+pub use self::emergency_access::{EmergencyAccess, EmergencyAccessStatus, EmergencyAccessType};
@@ -10,6 +10,11 @@ pub struct Organization {
     pub uuid: String,
     pub name: String,
     pub billing_email: String,
+    // Days the previous versions of the ciphers are kept, REVISION_RETENTION_DAYS when not set
+    pub revision_retention_days: Option<i32>,
+    // Used for the account recovery, the private key is encrypted with the organization key
+    pub private_key: Option<String>,
+    pub public_key: Option<String>,
 }
 
 #[derive(Debug, Identifiable, Queryable, Insertable)]
@@ -24,6 +29,8 @@ pub struct UserOrganization {
     pub key: String,
     pub status: i32,
     pub type_: i32,
+    // The key of the user, encrypted with the organization public key when enrolled in the account recovery
+    pub reset_password_key: Option<String>,
 }
 
 pub enum UserOrgStatus {
@@ -162,6 +169,9 @@ impl Organization {
 
             name,
             billing_email,
+            revision_retention_days: None,
+            private_key: None,
+            public_key: None,
         }
     }
 
@@ -169,7 +179,10 @@ impl Organization {
         Self {
             uuid: String::from(Organization::VIRTUAL_ID),
             name: String::from("bitwarden_rs"),
-            billing_email: String::from("none@none.none")
+            billing_email: String::from("none@none.none"),
+            revision_retention_days: None,
+            private_key: None,
+            public_key: None,
         }
     }
 
@@ -194,6 +207,9 @@ impl Organization {
             "BusinessTaxNumber": null,
 
             "BillingEmail": self.billing_email,
+            "RevisionRetentionDays": self.revision_retention_days,
+            "UseResetPassword": true,
+            "HasPublicAndPrivateKeys": self.private_key.is_some() && self.public_key.is_some(),
             "Plan": "TeamsAnnually",
             "PlanType": 5, // TeamsAnnually plan
             "UsersGetPremium": true,
@@ -214,6 +230,7 @@ impl UserOrganization {
             key: String::new(),
             status: UserOrgStatus::Accepted as i32,
             type_: UserOrgType::User as i32,
+            reset_password_key: None,
         }
     }
 
@@ -228,6 +245,7 @@ impl UserOrganization {
             key: String::new(),
             status: status as i32,
             type_: type_ as i32,
+            reset_password_key: None,
         }
     }
 }
@@ -256,7 +274,7 @@ impl Organization {
     }
 
     pub fn delete(self, conn: &DbConn) -> QueryResult<()> {
-        use super::{Cipher, Collection};
+        use super::{ApiKey, Cipher, Collection};
 
         if self.uuid == Organization::VIRTUAL_ID {
             return Err(diesel::result::Error::NotFound)
@@ -265,6 +283,7 @@ impl Organization {
         Cipher::delete_all_by_organization(&self.uuid, &conn)?;
         Collection::delete_all_by_organization(&self.uuid, &conn)?;
         UserOrganization::delete_all_by_organization(&self.uuid, &conn)?;
+        ApiKey::delete_all_by_organization(&self.uuid, &conn)?;
 
         diesel::delete(
             organizations::table.filter(
@@ -307,6 +326,9 @@ impl UserOrganization {
             "Status": self.status,
             "Type": self.type_,
             "Enabled": true,
+            "UseResetPassword": true,
+            "HasPublicAndPrivateKeys": org.private_key.is_some() && org.public_key.is_some(),
+            "ResetPasswordEnrolled": self.reset_password_key.is_some(),
 
             "Object": "profileOrganization",
         })
@@ -324,6 +346,7 @@ impl UserOrganization {
             "Status": self.status,
             "Type": self.type_,
             "AccessAll": self.access_all,
+            "ResetPasswordEnrolled": self.reset_password_key.is_some(),
 
             "Object": "organizationUserUserDetails",
         })
@@ -360,6 +383,7 @@ impl UserOrganization {
             "Type": self.type_,
             "AccessAll": self.access_all,
             "Collections": coll_uuids,
+            "ResetPasswordEnrolled": self.reset_password_key.is_some(),
 
             "Object": "organizationUserDetails",
         })
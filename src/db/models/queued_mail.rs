@@ -0,0 +1,94 @@
+use std::cmp;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+#[derive(Debug, Identifiable, Queryable, Insertable)]
+#[table_name = "mail_queue"]
+#[primary_key(uuid)]
+pub struct QueuedMail {
+    pub uuid: String,
+    pub created_at: NaiveDateTime,
+
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub html: bool,
+
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_error: Option<String>,
+}
+
+/// Local methods
+impl QueuedMail {
+    pub const MAX_ATTEMPTS: i32 = 10;
+    const RETRY_BASE_SECONDS: i64 = 30;
+    const RETRY_MAX_SECONDS: i64 = 60 * 60;
+
+    pub fn new(recipient: String, subject: String, body: String, html: bool) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            uuid: crate::util::get_uuid(),
+            created_at: now,
+
+            recipient,
+            subject,
+            body,
+            html,
+
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+        }
+    }
+
+    pub fn reschedule(&mut self, error: String) {
+        self.attempts += 1;
+
+        // Exponential backoff: 30s, 1m, 2m, 4m... up to one hour between attempts
+        let shift = cmp::min(self.attempts - 1, 16) as u32;
+        let delay = cmp::min(Self::RETRY_BASE_SECONDS << shift, Self::RETRY_MAX_SECONDS);
+
+        self.next_attempt_at = Utc::now().naive_utc() + Duration::seconds(delay);
+        self.last_error = Some(error);
+    }
+}
+
+use diesel;
+use diesel::prelude::*;
+use crate::db::DbConn;
+use crate::db::schema::mail_queue;
+
+/// Database methods
+impl QueuedMail {
+    pub fn save(&self, conn: &DbConn) -> QueryResult<()> {
+        crate::util::retry(
+            || {
+                diesel::replace_into(mail_queue::table)
+                    .values(self)
+                    .execute(&**conn)
+            },
+            10,
+        )
+        .and(Ok(()))
+    }
+
+    pub fn delete(self, conn: &DbConn) -> QueryResult<()> {
+        diesel::delete(
+            mail_queue::table.filter(
+                mail_queue::uuid.eq(self.uuid)
+            )
+        ).execute(&**conn).and(Ok(()))
+    }
+
+    pub fn find_pending(limit: i64, conn: &DbConn) -> QueryResult<Vec<Self>> {
+        let now = Utc::now().naive_utc();
+
+        mail_queue::table
+            .filter(mail_queue::next_attempt_at.le(now))
+            .order(mail_queue::created_at.asc())
+            .limit(limit)
+            .load::<Self>(&**conn)
+    }
+}
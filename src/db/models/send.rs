@@ -0,0 +1,274 @@
+use chrono::{NaiveDateTime, Utc};
+use data_encoding::{BASE64, BASE64URL_NOPAD};
+use serde_json::Value;
+
+use super::User;
+use crate::crypto;
+use crate::CONFIG;
+
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "sends"]
+#[belongs_to(User, foreign_key = "user_uuid")]
+#[primary_key(uuid)]
+pub struct Send {
+    pub uuid: String,
+    pub user_uuid: String,
+
+    pub name: String,
+    pub notes: Option<String>,
+
+    /*
+    Text = 0,
+    File = 1
+    */
+    pub type_: i32,
+    // Encrypted text, or the description of the file
+    pub data: String,
+    pub key: String,
+
+    pub password_hash: Option<Vec<u8>>,
+    pub password_salt: Option<Vec<u8>>,
+    pub password_iter: Option<i32>,
+
+    pub max_access_count: Option<i32>,
+    pub access_count: i32,
+
+    pub creation_date: NaiveDateTime,
+    pub revision_date: NaiveDateTime,
+    pub expiration_date: Option<NaiveDateTime>,
+    pub deletion_date: NaiveDateTime,
+
+    pub disabled: bool,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum SendType {
+    Text = 0,
+    File = 1,
+}
+
+/// Local methods
+impl Send {
+    pub fn new(type_: SendType, name: String, data: String, key: String, deletion_date: NaiveDateTime) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid: String::new(),
+
+            name,
+            notes: None,
+
+            type_: type_ as i32,
+            data,
+            key,
+
+            password_hash: None,
+            password_salt: None,
+            password_iter: None,
+
+            max_access_count: None,
+            access_count: 0,
+
+            creation_date: now,
+            revision_date: now,
+            expiration_date: None,
+            deletion_date,
+
+            disabled: false,
+        }
+    }
+
+    /// The password is already hashed by the client, but it's hashed again like the master password
+    pub fn set_password(&mut self, password: Option<&str>) {
+        match password {
+            Some(password) => {
+                let salt = crypto::get_random_64();
+                let iterations = CONFIG.password_iterations;
+
+                self.password_hash = Some(crypto::hash_password(password.as_bytes(), &salt, iterations as u32));
+                self.password_salt = Some(salt);
+                self.password_iter = Some(iterations);
+            }
+            None => {
+                self.password_hash = None;
+                self.password_salt = None;
+                self.password_iter = None;
+            }
+        }
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        match (&self.password_hash, &self.password_salt, self.password_iter) {
+            (Some(hash), Some(salt), Some(iterations)) => {
+                crypto::verify_password_hash(password.as_bytes(), salt, hash, iterations as u32)
+            }
+            _ => false,
+        }
+    }
+
+    /// The id used in the links shared with the recipients, shorter than the uuid
+    pub fn access_id(&self) -> String {
+        match uuid::Uuid::parse_str(&self.uuid) {
+            Ok(uuid) => BASE64URL_NOPAD.encode(uuid.as_bytes()),
+            Err(_) => self.uuid.clone(),
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.type_ == SendType::File as i32
+    }
+
+    /// The id of the stored file, for file sends
+    pub fn file_id(&self) -> Option<String> {
+        let data: Value = serde_json::from_str(&self.data).ok()?;
+        data["Id"].as_str().map(String::from)
+    }
+
+    pub fn get_file_path(&self, file_id: &str) -> String {
+        format!("{}/{}/{}", CONFIG.sends_folder, self.uuid, file_id)
+    }
+
+    /// Checks if the send can still be accessed by the recipients
+    pub fn check_available(&self) -> Result<(), &'static str> {
+        let now = Utc::now().naive_utc();
+
+        if self.disabled {
+            return Err("Send has been disabled");
+        }
+        if self.max_access_count.map_or(false, |max| self.access_count >= max) {
+            return Err("Max access count reached");
+        }
+        if self.expiration_date.map_or(false, |date| date <= now) {
+            return Err("Send has expired");
+        }
+        if self.deletion_date <= now {
+            return Err("Send has been deleted");
+        }
+
+        Ok(())
+    }
+
+    fn data_json(&self) -> (Value, Value) {
+        let data: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
+
+        if self.is_file() {
+            (Value::Null, data)
+        } else {
+            (data, Value::Null)
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        let (text, file) = self.data_json();
+
+        json!({
+            "Id": self.uuid,
+            "AccessId": self.access_id(),
+            "Type": self.type_,
+
+            "Name": self.name,
+            "Notes": self.notes,
+            "Text": text,
+            "File": file,
+
+            "Key": self.key,
+            "MaxAccessCount": self.max_access_count,
+            "AccessCount": self.access_count,
+            "Password": self.password_hash.as_ref().map(|hash| BASE64.encode(hash)),
+            "Disabled": self.disabled,
+
+            "RevisionDate": format_date(&self.revision_date),
+            "ExpirationDate": self.expiration_date.as_ref().map(format_date),
+            "DeletionDate": format_date(&self.deletion_date),
+            "Object": "send",
+        })
+    }
+
+    /// What the recipients see, the key is only part of the link
+    pub fn to_json_access(&self) -> Value {
+        use crate::util::format_date;
+
+        let (text, file) = self.data_json();
+
+        json!({
+            "Id": self.uuid,
+            "Type": self.type_,
+
+            "Name": self.name,
+            "Text": text,
+            "File": file,
+
+            "ExpirationDate": self.expiration_date.as_ref().map(format_date),
+            "Object": "send-access",
+        })
+    }
+}
+
+use diesel;
+use diesel::prelude::*;
+use crate::db::DbConn;
+use crate::db::schema::sends;
+
+/// Database methods
+impl Send {
+    pub fn save(&mut self, conn: &DbConn) -> QueryResult<()> {
+        User::update_uuid_revision(&self.user_uuid, conn);
+        self.revision_date = Utc::now().naive_utc();
+
+        diesel::replace_into(sends::table)
+            .values(&*self)
+            .execute(&**conn)
+            .and(Ok(()))
+    }
+
+    pub fn delete(&self, conn: &DbConn) -> QueryResult<()> {
+        User::update_uuid_revision(&self.user_uuid, conn);
+
+        diesel::delete(
+            sends::table.filter(
+                sends::uuid.eq(&self.uuid)
+            )
+        ).execute(&**conn)?;
+
+        if let Some(file_id) = self.file_id() {
+            crate::util::delete_file(&self.get_file_path(&file_id));
+        }
+        Ok(())
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> QueryResult<()> {
+        for send in Self::find_by_user(user_uuid, conn) {
+            send.delete(conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        sends::table
+            .filter(sends::uuid.eq(uuid))
+            .first::<Self>(&**conn).ok()
+    }
+
+    pub fn find_by_access_id(access_id: &str, conn: &DbConn) -> Option<Self> {
+        let bytes = BASE64URL_NOPAD.decode(access_id.as_bytes()).ok()?;
+        let uuid = uuid::Uuid::from_slice(&bytes).ok()?;
+
+        Self::find_by_uuid(&uuid.to_string(), conn)
+    }
+
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        sends::table
+            .filter(sends::user_uuid.eq(user_uuid))
+            .load::<Self>(&**conn).expect("Error loading sends")
+    }
+
+    /// Sends past their deletion date, which aren't accessible anymore
+    pub fn find_deleted(conn: &DbConn) -> QueryResult<Vec<Self>> {
+        sends::table
+            .filter(sends::deletion_date.lt(Utc::now().naive_utc()))
+            .load::<Self>(&**conn)
+    }
+}
@@ -0,0 +1,116 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+
+/// A single-sign-on login in progress. It's created when the client is sent to the
+/// identity provider and deleted once the client redeems the authorization code.
+#[derive(Debug, Identifiable, Queryable, Insertable)]
+#[table_name = "sso_auths"]
+#[primary_key(state)]
+pub struct SsoAuth {
+    pub state: String,
+    pub created_at: NaiveDateTime,
+
+    // Sent to the identity provider
+    pub nonce: String,
+    pub verifier: String,
+
+    // Received from the client
+    pub redirect_uri: String,
+    pub client_state: Option<String>,
+    pub code_challenge: String,
+
+    // Set once the identity provider has authenticated the user
+    pub auth_code: Option<String>,
+    pub user_uuid: Option<String>,
+}
+
+/// Local methods
+impl SsoAuth {
+    /// The whole flow, including the login at the identity provider, must be completed in this time
+    const VALIDITY_MINUTES: i64 = 10;
+
+    pub fn new(redirect_uri: String, client_state: Option<String>, code_challenge: String) -> Self {
+        use data_encoding::BASE64URL_NOPAD;
+        use crate::crypto;
+
+        Self {
+            state: BASE64URL_NOPAD.encode(&crypto::get_random(vec![0u8; 32])),
+            created_at: Utc::now().naive_utc(),
+
+            nonce: BASE64URL_NOPAD.encode(&crypto::get_random(vec![0u8; 32])),
+            verifier: BASE64URL_NOPAD.encode(&crypto::get_random(vec![0u8; 32])),
+
+            redirect_uri,
+            client_state,
+            code_challenge,
+
+            auth_code: None,
+            user_uuid: None,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Utc::now().naive_utc() - self.created_at > Duration::minutes(Self::VALIDITY_MINUTES)
+    }
+
+    pub fn authorize(&mut self, user_uuid: String) -> String {
+        use data_encoding::BASE64URL_NOPAD;
+
+        let auth_code = BASE64URL_NOPAD.encode(&crate::crypto::get_random(vec![0u8; 32]));
+        self.auth_code = Some(auth_code.clone());
+        self.user_uuid = Some(user_uuid);
+        auth_code
+    }
+}
+
+use diesel;
+use diesel::prelude::*;
+use crate::db::DbConn;
+use crate::db::schema::sso_auths;
+
+/// Database methods
+impl SsoAuth {
+    pub fn save(&self, conn: &DbConn) -> QueryResult<()> {
+        diesel::replace_into(sso_auths::table)
+            .values(self)
+            .execute(&**conn)
+            .and(Ok(()))
+    }
+
+    pub fn delete(self, conn: &DbConn) -> QueryResult<()> {
+        diesel::delete(
+            sso_auths::table.filter(
+                sso_auths::state.eq(self.state)
+            )
+        ).execute(&**conn).and(Ok(()))
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> QueryResult<()> {
+        diesel::delete(
+            sso_auths::table.filter(
+                sso_auths::user_uuid.eq(user_uuid)
+            )
+        ).execute(&**conn).and(Ok(()))
+    }
+
+    pub fn delete_expired(conn: &DbConn) -> QueryResult<()> {
+        let limit = Utc::now().naive_utc() - Duration::minutes(Self::VALIDITY_MINUTES);
+
+        diesel::delete(
+            sso_auths::table.filter(
+                sso_auths::created_at.lt(limit)
+            )
+        ).execute(&**conn).and(Ok(()))
+    }
+
+    pub fn find_by_state(state: &str, conn: &DbConn) -> Option<Self> {
+        sso_auths::table
+            .filter(sso_auths::state.eq(state))
+            .first::<Self>(&**conn).ok()
+    }
+
+    pub fn find_by_auth_code(auth_code: &str, conn: &DbConn) -> Option<Self> {
+        sso_auths::table
+            .filter(sso_auths::auth_code.eq(auth_code))
+            .first::<Self>(&**conn).ok()
+    }
+}
@@ -1,7 +1,9 @@
-use chrono::{NaiveDateTime, Utc};
+use chrono::{Duration, NaiveDateTime, Utc};
 use serde_json::Value;
 
-use crate::crypto;
+use num_traits::FromPrimitive;
+
+use crate::crypto::{self, PasswordAlgorithm};
 use crate::CONFIG;
 
 
@@ -36,12 +38,26 @@ pub struct User {
     
     pub client_kdf_type: i32,
     pub client_kdf_iter: i32,
+
+    pub verified_at: Option<NaiveDateTime>,
+    pub last_verifying_at: Option<NaiveDateTime>,
+
+    pub password_algorithm: i32,
+
+    pub client_kdf_memory: Option<i32>,
+    pub client_kdf_parallelism: Option<i32>,
 }
 
 /// Local methods
 impl User {
     pub const CLIENT_KDF_TYPE_DEFAULT: i32 = 0; // PBKDF2: 0
     pub const CLIENT_KDF_ITER_DEFAULT: i32 = 5_000;
+    pub const CLIENT_KDF_ITER_MAX: i32 = 2_000_000;
+
+    pub const CLIENT_KDF_TYPE_ARGON2ID: i32 = 1;
+    pub const CLIENT_KDF_ARGON2_ITER_DEFAULT: i32 = 3;
+    pub const CLIENT_KDF_ARGON2_MEMORY_DEFAULT: i32 = 64; // MiB
+    pub const CLIENT_KDF_ARGON2_PARALLELISM_DEFAULT: i32 = 4;
 
     pub fn new(mail: String) -> Self {
         let now = Utc::now().naive_utc();
@@ -73,14 +89,99 @@ impl User {
             
             client_kdf_type: Self::CLIENT_KDF_TYPE_DEFAULT,
             client_kdf_iter: Self::CLIENT_KDF_ITER_DEFAULT,
+
+            verified_at: None,
+            last_verifying_at: None,
+
+            password_algorithm: CONFIG.password_algorithm as i32,
+
+            client_kdf_memory: None,
+            client_kdf_parallelism: None,
         }
     }
 
+    /// Accounts with fewer PBKDF2 iterations than the server minimum should change their KDF settings
+    pub fn is_client_kdf_weak(&self) -> bool {
+        self.client_kdf_type == Self::CLIENT_KDF_TYPE_DEFAULT && self.client_kdf_iter < CONFIG.client_kdf_min_iterations
+    }
+
+    /// Sets the KDF used by the clients to derive the master key, the missing parameters use the defaults of the KDF.
+    /// Weak parameters are rejected, as the server can't detect a weak master key later
+    pub fn set_client_kdf(&mut self, kdf_type: i32, iterations: Option<i32>, memory: Option<i32>, parallelism: Option<i32>) -> Result<(), String> {
+        match kdf_type {
+            Self::CLIENT_KDF_TYPE_DEFAULT => {
+                if memory.is_some() || parallelism.is_some() {
+                    return Err("PBKDF2 doesn't use the memory and parallelism parameters".into());
+                }
+
+                // The clients derive the key with the iterations they sent, so a missing value can't be raised to the minimum
+                let iterations = match iterations {
+                    Some(iterations) => iterations,
+                    None if Self::CLIENT_KDF_ITER_DEFAULT >= CONFIG.client_kdf_min_iterations => Self::CLIENT_KDF_ITER_DEFAULT,
+                    None => {
+                        return Err(format!(
+                            "KdfIterations is required, the server needs at least {} PBKDF2 iterations",
+                            CONFIG.client_kdf_min_iterations
+                        ))
+                    }
+                };
+                if iterations < CONFIG.client_kdf_min_iterations || iterations > Self::CLIENT_KDF_ITER_MAX {
+                    return Err(format!(
+                        "PBKDF2 iterations must be between {} and {}",
+                        CONFIG.client_kdf_min_iterations,
+                        Self::CLIENT_KDF_ITER_MAX
+                    ));
+                }
+
+                self.client_kdf_iter = iterations;
+                self.client_kdf_memory = None;
+                self.client_kdf_parallelism = None;
+            }
+            Self::CLIENT_KDF_TYPE_ARGON2ID => {
+                let iterations = iterations.unwrap_or(Self::CLIENT_KDF_ARGON2_ITER_DEFAULT);
+                let memory = memory.unwrap_or(Self::CLIENT_KDF_ARGON2_MEMORY_DEFAULT);
+                let parallelism = parallelism.unwrap_or(Self::CLIENT_KDF_ARGON2_PARALLELISM_DEFAULT);
+
+                if iterations < 2 || iterations > 10 {
+                    return Err("Argon2 iterations must be between 2 and 10".into());
+                }
+                if memory < 15 || memory > 1024 {
+                    return Err("Argon2 memory must be between 15 MiB and 1024 MiB".into());
+                }
+                if parallelism < 1 || parallelism > 16 {
+                    return Err("Argon2 parallelism must be between 1 and 16".into());
+                }
+
+                self.client_kdf_iter = iterations;
+                self.client_kdf_memory = Some(memory);
+                self.client_kdf_parallelism = Some(parallelism);
+            }
+            _ => return Err("KDF type not supported".into()),
+        }
+
+        self.client_kdf_type = kdf_type;
+        Ok(())
+    }
+
     pub fn check_valid_password(&self, password: &str) -> bool {
-        crypto::verify_password_hash(password.as_bytes(),
-                                     &self.salt,
-                                     &self.password_hash,
-                                     self.password_iterations as u32)
+        match PasswordAlgorithm::from_i32(self.password_algorithm) {
+            Some(PasswordAlgorithm::Pbkdf2) => crypto::verify_password_hash(password.as_bytes(),
+                                                                             &self.salt,
+                                                                             &self.password_hash,
+                                                                             self.password_iterations as u32),
+            Some(PasswordAlgorithm::Argon2id) => crypto::verify_password_argon2(password.as_bytes(), &self.password_hash),
+            None => false,
+        }
+    }
+
+    /// Whether the password hash was created with an algorithm or parameters that are not the configured ones anymore
+    pub fn password_needs_rehash(&self) -> bool {
+        match PasswordAlgorithm::from_i32(self.password_algorithm) {
+            Some(algorithm) if algorithm != CONFIG.password_algorithm => true,
+            Some(PasswordAlgorithm::Pbkdf2) => self.password_iterations != CONFIG.password_iterations,
+            Some(PasswordAlgorithm::Argon2id) => !self.password_hash.starts_with(CONFIG.argon2_params.encoded_prefix().as_bytes()),
+            None => true,
+        }
     }
 
     pub fn check_valid_recovery_code(&self, recovery_code: &str) -> bool {
@@ -91,10 +192,19 @@ impl User {
         }
     }
 
+    /// Hashes the password with the configured algorithm and parameters
     pub fn set_password(&mut self, password: &str) {
-        self.password_hash = crypto::hash_password(password.as_bytes(),
-                                                   &self.salt,
-                                                   self.password_iterations as u32);
+        self.password_algorithm = CONFIG.password_algorithm as i32;
+        self.password_iterations = CONFIG.password_iterations;
+
+        self.password_hash = match CONFIG.password_algorithm {
+            PasswordAlgorithm::Pbkdf2 => crypto::hash_password(password.as_bytes(),
+                                                               &self.salt,
+                                                               self.password_iterations as u32),
+            PasswordAlgorithm::Argon2id => crypto::hash_password_argon2(password.as_bytes(),
+                                                                        &self.salt,
+                                                                        &CONFIG.argon2_params),
+        };
     }
 
     pub fn reset_security_stamp(&mut self) {
@@ -113,7 +223,7 @@ use diesel;
 use diesel::prelude::*;
 use crate::db::DbConn;
 use crate::db::schema::{users, invitations};
-use super::{Cipher, Folder, Device, UserOrganization, UserOrgType};
+use super::{ApiKey, Cipher, EmergencyAccess, Folder, Device, Send, SsoAuth, UserOrganization, UserOrgType};
 
 /// Database methods
 impl User {
@@ -131,7 +241,7 @@ impl User {
             "Id": self.uuid,
             "Name": self.name,
             "Email": self.email,
-            "EmailVerified": true,
+            "EmailVerified": self.verified_at.is_some(),
             "Premium": true,
             "MasterPasswordHint": self.password_hint,
             "Culture": "en-US",
@@ -139,6 +249,11 @@ impl User {
             "Key": self.key,
             "PrivateKey": self.private_key,
             "SecurityStamp": self.security_stamp,
+            "Kdf": self.client_kdf_type,
+            "KdfIterations": self.client_kdf_iter,
+            "KdfMemory": self.client_kdf_memory,
+            "KdfParallelism": self.client_kdf_parallelism,
+            "KdfUpgradeRequired": self.is_client_kdf_weak(),
             "Organizations": orgs_json,
             "Object": "profile"
         })
@@ -168,6 +283,10 @@ impl User {
         Cipher::delete_all_by_user(&self.uuid, &*conn)?;
         Folder::delete_all_by_user(&self.uuid, &*conn)?;
         Device::delete_all_by_user(&self.uuid, &*conn)?;
+        ApiKey::delete_all_by_user(&self.uuid, &*conn)?;
+        SsoAuth::delete_all_by_user(&self.uuid, &*conn)?;
+        Send::delete_all_by_user(&self.uuid, &*conn)?;
+        EmergencyAccess::delete_all_by_user(&self.uuid, &*conn)?;
         Invitation::take(&self.email, &*conn); // Delete invitation if any
 
         diesel::delete(users::table.filter(
@@ -211,6 +330,14 @@ impl User {
         users::table
         .load::<Self>(&**conn).expect("Error loading users")
     }
+
+    pub fn find_weak_client_kdf(conn: &DbConn) -> Vec<Self> {
+        users::table
+            .filter(users::client_kdf_type.eq(Self::CLIENT_KDF_TYPE_DEFAULT))
+            .filter(users::client_kdf_iter.lt(CONFIG.client_kdf_min_iterations))
+            .order(users::email.asc())
+            .load::<Self>(&**conn).expect("Error loading users")
+    }
 }
 
 #[derive(Debug, Identifiable, Queryable, Insertable)]
@@ -218,15 +345,36 @@ impl User {
 #[primary_key(email)]
 pub struct Invitation {
     pub email: String,
+    pub org_uuid: Option<String>,
+    pub invited_by_uuid: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
 }
 
 impl Invitation {
-    pub fn new(email: String) -> Self {
+    pub fn new(email: String, org_uuid: Option<String>, invited_by_uuid: Option<String>) -> Self {
+        let now = Utc::now().naive_utc();
+
         Self {
-            email
+            email: email.to_lowercase(),
+            org_uuid,
+            invited_by_uuid,
+            created_at: now,
+            expires_at: now + Duration::hours(CONFIG.invitation_expiration_hours),
         }
     }
 
+    pub fn to_json(&self) -> Value {
+        json!({
+            "Email": self.email,
+            "OrganizationId": self.org_uuid,
+            "InvitedById": self.invited_by_uuid,
+            "CreationDate": crate::util::format_date(&self.created_at),
+            "ExpirationDate": crate::util::format_date(&self.expires_at),
+            "Object": "invitation",
+        })
+    }
+
     pub fn save(&mut self, conn: &DbConn) -> QueryResult<()> {
         diesel::replace_into(invitations::table)
         .values(&*self)
@@ -241,18 +389,48 @@ impl Invitation {
         .and(Ok(()))
     }
 
+    /// Only deletes the invitation if it's still expired, it may have been renewed by a new invite.
+    /// Returns whether it was deleted
+    pub fn delete_expired(mail: &str, conn: &DbConn) -> QueryResult<bool> {
+        diesel::delete(invitations::table
+            .filter(invitations::email.eq(mail.to_lowercase()))
+            .filter(invitations::expires_at.le(Utc::now().naive_utc())))
+        .execute(&**conn)
+        .map(|deleted| deleted > 0)
+    }
+
+    /// Extends the expiration when the invitation is sent again
+    pub fn renew(mail: &str, conn: &DbConn) -> QueryResult<()> {
+        let expires_at = Utc::now().naive_utc() + Duration::hours(CONFIG.invitation_expiration_hours);
+
+        diesel::update(invitations::table.filter(
+        invitations::email.eq(mail.to_lowercase())))
+        .set(invitations::expires_at.eq(expires_at))
+        .execute(&**conn)
+        .and(Ok(()))
+    }
+
+    /// Only returns the invitation if it hasn't expired
     pub fn find_by_mail(mail: &str, conn: &DbConn) -> Option<Self> {
         let lower_mail = mail.to_lowercase();
         invitations::table
             .filter(invitations::email.eq(lower_mail))
+            .filter(invitations::expires_at.gt(Utc::now().naive_utc()))
             .first::<Self>(&**conn).ok()
     }
 
+    /// Used by the invitation sweep, which logs the errors instead of stopping
+    pub fn find_expired(conn: &DbConn) -> QueryResult<Vec<Self>> {
+        invitations::table
+            .filter(invitations::expires_at.le(Utc::now().naive_utc()))
+            .load::<Self>(&**conn)
+    }
+
     pub fn take(mail: &str, conn: &DbConn) -> bool {
-        CONFIG.invitations_allowed &&
+        CONFIG.invitations_allowed() &&
         match Self::find_by_mail(mail, &conn) {
             Some(invitation) => invitation.delete(&conn).is_ok(),
             None => false
         }
     }
-}
\ No newline at end of file
+}
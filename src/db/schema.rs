@@ -1,3 +1,17 @@
+table! {
+    api_keys (uuid) {
+        uuid -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        user_uuid -> Nullable<Text>,
+        org_uuid -> Nullable<Text>,
+        client_id -> Text,
+        secret_hash -> Binary,
+        secret_salt -> Binary,
+        revision -> Integer,
+    }
+}
+
 table! {
     attachments (id) {
         id -> Text,
@@ -8,6 +22,20 @@ table! {
     }
 }
 
+table! {
+    cipher_revisions (uuid) {
+        uuid -> Text,
+        cipher_uuid -> Text,
+        author_uuid -> Nullable<Text>,
+        revision_date -> Timestamp,
+        created_at -> Timestamp,
+        name -> Text,
+        notes -> Nullable<Text>,
+        fields -> Nullable<Text>,
+        data -> Text,
+    }
+}
+
 table! {
     ciphers (uuid) {
         uuid -> Text,
@@ -23,6 +51,7 @@ table! {
         data -> Text,
         favorite -> Bool,
         password_history -> Nullable<Text>,
+        deleted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -53,6 +82,28 @@ table! {
         push_token -> Nullable<Text>,
         refresh_token -> Text,
         twofactor_remember -> Nullable<Text>,
+        last_ip -> Nullable<Text>,
+        refresh_token_issued_at -> Nullable<Timestamp>,
+        refresh_token_used_at -> Nullable<Timestamp>,
+        previous_refresh_token -> Nullable<Text>,
+    }
+}
+
+table! {
+    emergency_access (uuid) {
+        uuid -> Text,
+        grantor_uuid -> Text,
+        grantee_uuid -> Nullable<Text>,
+        email -> Nullable<Text>,
+        key_encrypted -> Nullable<Text>,
+        #[sql_name = "type"]
+        type_ -> Integer,
+        status -> Integer,
+        wait_time_days -> Integer,
+        recovery_initiated_at -> Nullable<Timestamp>,
+        last_notification_at -> Nullable<Timestamp>,
+        updated_at -> Timestamp,
+        created_at -> Timestamp,
     }
 }
 
@@ -76,6 +127,24 @@ table! {
 table! {
     invitations (email) {
         email -> Text,
+        org_uuid -> Nullable<Text>,
+        invited_by_uuid -> Nullable<Text>,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    mail_queue (uuid) {
+        uuid -> Text,
+        created_at -> Timestamp,
+        recipient -> Text,
+        subject -> Text,
+        body -> Text,
+        html -> Bool,
+        attempts -> Integer,
+        next_attempt_at -> Timestamp,
+        last_error -> Nullable<Text>,
     }
 }
 
@@ -84,6 +153,46 @@ table! {
         uuid -> Text,
         name -> Text,
         billing_email -> Text,
+        revision_retention_days -> Nullable<Integer>,
+        private_key -> Nullable<Text>,
+        public_key -> Nullable<Text>,
+    }
+}
+
+table! {
+    sends (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        name -> Text,
+        notes -> Nullable<Text>,
+        #[sql_name = "type"]
+        type_ -> Integer,
+        data -> Text,
+        key -> Text,
+        password_hash -> Nullable<Binary>,
+        password_salt -> Nullable<Binary>,
+        password_iter -> Nullable<Integer>,
+        max_access_count -> Nullable<Integer>,
+        access_count -> Integer,
+        creation_date -> Timestamp,
+        revision_date -> Timestamp,
+        expiration_date -> Nullable<Timestamp>,
+        deletion_date -> Timestamp,
+        disabled -> Bool,
+    }
+}
+
+table! {
+    sso_auths (state) {
+        state -> Text,
+        created_at -> Timestamp,
+        nonce -> Text,
+        verifier -> Text,
+        redirect_uri -> Text,
+        client_state -> Nullable<Text>,
+        code_challenge -> Text,
+        auth_code -> Nullable<Text>,
+        user_uuid -> Nullable<Text>,
     }
 }
 
@@ -119,6 +228,11 @@ table! {
         excluded_globals -> Text,
         client_kdf_type -> Integer,
         client_kdf_iter -> Integer,
+        verified_at -> Nullable<Timestamp>,
+        last_verifying_at -> Nullable<Timestamp>,
+        password_algorithm -> Integer,
+        client_kdf_memory -> Nullable<Integer>,
+        client_kdf_parallelism -> Nullable<Integer>,
     }
 }
 
@@ -140,10 +254,14 @@ table! {
         status -> Integer,
         #[sql_name = "type"]
         type_ -> Integer,
+        reset_password_key -> Nullable<Text>,
     }
 }
 
+joinable!(api_keys -> organizations (org_uuid));
+joinable!(api_keys -> users (user_uuid));
 joinable!(attachments -> ciphers (cipher_uuid));
+joinable!(cipher_revisions -> ciphers (cipher_uuid));
 joinable!(ciphers -> organizations (organization_uuid));
 joinable!(ciphers -> users (user_uuid));
 joinable!(ciphers_collections -> ciphers (cipher_uuid));
@@ -153,6 +271,9 @@ joinable!(devices -> users (user_uuid));
 joinable!(folders -> users (user_uuid));
 joinable!(folders_ciphers -> ciphers (cipher_uuid));
 joinable!(folders_ciphers -> folders (folder_uuid));
+joinable!(invitations -> organizations (org_uuid));
+joinable!(sends -> users (user_uuid));
+joinable!(sso_auths -> users (user_uuid));
 joinable!(twofactor -> users (user_uuid));
 joinable!(users_collections -> collections (collection_uuid));
 joinable!(users_collections -> users (user_uuid));
@@ -160,15 +281,21 @@ joinable!(users_organizations -> organizations (org_uuid));
 joinable!(users_organizations -> users (user_uuid));
 
 allow_tables_to_appear_in_same_query!(
+    api_keys,
     attachments,
+    cipher_revisions,
     ciphers,
     ciphers_collections,
     collections,
     devices,
+    emergency_access,
     folders,
     folders_ciphers,
     invitations,
+    mail_queue,
     organizations,
+    sends,
+    sso_auths,
     twofactor,
     users,
     users_collections,
@@ -1,41 +1,87 @@
-use native_tls::{Protocol, TlsConnector};
-use lettre::{Transport, SmtpTransport, SmtpClient, ClientTlsParameters, ClientSecurity};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use native_tls::{Certificate, Protocol, TlsConnector};
+use lettre::{Transport, SmtpTransport, SmtpClient, ClientTlsParameters, ClientSecurity, SendableEmail};
 use lettre::smtp::ConnectionReuseParameters;
+use lettre::smtp::extension::ClientId;
 use lettre::smtp::authentication::Credentials;
 use lettre_email::EmailBuilder;
+use chrono::NaiveDateTime;
 
-use crate::MailConfig;
+use crate::db::{DbConn, Pool};
+use crate::db::models::QueuedMail;
+use crate::config::{MailConfig, MailTransport, SmtpSecurity};
+use crate::util::percent_encode;
 use crate::CONFIG;
 
-fn mailer(config: &MailConfig) -> SmtpTransport {
-    let client_security = if config.smtp_ssl {
-        let tls = TlsConnector::builder()
-            .min_protocol_version(Some(Protocol::Tlsv11))
-            .build()
-            .unwrap();
+/// Time between two runs of the mail queue worker
+const QUEUE_INTERVAL_SECONDS: u64 = 5;
+/// Maximum number of emails delivered in one run of the mail queue worker
+const QUEUE_BATCH_SIZE: i64 = 50;
 
-        ClientSecurity::Required(ClientTlsParameters::new(config.smtp_host.clone(), tls))
-    } else {
-        ClientSecurity::None
+fn tls_connector(config: &MailConfig) -> Result<TlsConnector, String> {
+    let mut tls_builder = TlsConnector::builder();
+    tls_builder.min_protocol_version(Some(Protocol::Tlsv12));
+
+    for pem in &config.smtp_ca_certs {
+        let cert = Certificate::from_pem(pem).map_err(|e| e.to_string())?;
+        tls_builder.add_root_certificate(cert);
+    }
+
+    tls_builder.build().map_err(|e| e.to_string())
+}
+
+fn mailer(config: &MailConfig) -> Result<SmtpTransport, String> {
+    let client_security = match config.smtp_security {
+        SmtpSecurity::Off => ClientSecurity::None,
+        security => {
+            let tls = tls_connector(config)?;
+            let tls_parameters = ClientTlsParameters::new(config.smtp_host.clone(), tls);
+
+            if security == SmtpSecurity::ForceTls {
+                ClientSecurity::Wrapper(tls_parameters)
+            } else {
+                ClientSecurity::Required(tls_parameters)
+            }
+        }
     };
 
-    let smtp_client = SmtpClient::new(
+    let mut smtp_client = SmtpClient::new(
         (config.smtp_host.as_str(), config.smtp_port),
         client_security,
-    ).unwrap();
+    ).map_err(|e| e.to_string())?
+        .smtp_utf8(true)
+        .timeout(Some(Duration::from_secs(config.smtp_timeout)))
+        .connection_reuse(ConnectionReuseParameters::ReuseUnlimited);
 
-    let smtp_client = match (&config.smtp_username, &config.smtp_password) {
-        (Some(user), Some(pass)) => smtp_client.credentials(Credentials::new(user.clone(), pass.clone())),
-        _ => smtp_client,
-    };
+    if let (Some(user), Some(pass)) = (&config.smtp_username, &config.smtp_password) {
+        smtp_client = smtp_client.credentials(Credentials::new(user.clone(), pass.clone()));
+    }
 
-    smtp_client
-        .smtp_utf8(true)
-        .connection_reuse(ConnectionReuseParameters::NoReuse)
-        .transport()
+    if let Some(mechanism) = config.smtp_auth_mechanism {
+        smtp_client = smtp_client.authentication_mechanism(mechanism);
+    }
+
+    if let Some(ref helo_name) = config.smtp_helo_name {
+        smtp_client = smtp_client.hello_name(ClientId::Domain(helo_name.clone()));
+    }
+
+    Ok(smtp_client.transport())
+}
+
+/// Checks the TLS settings of the configuration. The SMTP client isn't built, as that resolves
+/// the host, and a DNS failure would stop the server from starting even though the queue retries later
+pub fn check_config(config: &MailConfig) -> Result<(), String> {
+    match config.transport {
+        MailTransport::Smtp if config.smtp_security != SmtpSecurity::Off => tls_connector(config).and(Ok(())),
+        _ => Ok(()),
+    }
 }
 
-pub fn send_password_hint(address: &str, hint: Option<String>, config: &MailConfig) -> Result<(), String> {
+pub fn send_password_hint(address: &str, hint: Option<String>, conn: &DbConn) -> Result<(), String> {
     let (subject, body) = if let Some(hint) = hint {
         ("Your master password hint",
          format!(
@@ -48,44 +94,310 @@ pub fn send_password_hint(address: &str, hint: Option<String>, config: &MailConf
          "Sorry, you have not specified any password hint...\n".into())
     };
 
-    let email = EmailBuilder::new()
-        .to(address)
-        .from((config.smtp_from.clone(), "Bitwarden-rs"))
-        .subject(subject)
-        .body(body)
-        .build()
-        .map_err(|e| e.to_string())?;
+    queue_email(address, subject, body, false, conn)
+}
 
-    mailer(config)
-        .send(email.into())
-        .map_err(|e| e.to_string())
-        .and(Ok(()))
+/// Link to the web vault page where the invited user accepts the invitation
+pub fn invite_link(address: &str, org_id: &str, org_user_id: &str, token: &str, org_name: &str) -> String {
+    format!(
+        "{}/#/accept-organization/?organizationId={}&organizationUserId={}&email={}&organizationName={}&token={}",
+        CONFIG.domain,
+        percent_encode(org_id),
+        percent_encode(org_user_id),
+        percent_encode(address),
+        percent_encode(org_name),
+        percent_encode(token)
+    )
 }
 
-pub fn send_invite(address: &str, org_id: &str, org_user_id: &str, token: &str, org_name: &str, config: &MailConfig) -> Result<(), String> {
+pub fn send_invite(address: &str, org_id: &str, org_user_id: &str, token: &str, org_name: &str, conn: &DbConn) -> Result<(), String> {
     let (subject, body) =  {
         (format!("Join {}", &org_name),
         format!(
             "<html>
              <p>You have been invited to join the <b>{}</b> organization.<br><br>
-             <a href=\"{}/#/accept-organization/?organizationId={}&organizationUserId={}&email={}&organizationName={}&token={}\">Click here to join</a></p>
+             <a href=\"{}\">Click here to join</a></p>
              <p>If you do not wish to join this organization, you can safely ignore this email.</p>
              </html>",
-            org_name, CONFIG.domain, org_id, org_user_id, address, org_name, token
+            org_name, invite_link(address, org_id, org_user_id, token, org_name)
         ))
     };
 
-    let email = EmailBuilder::new()
-        .to(address)
+    queue_email(address, &subject, body, true, conn)
+}
+
+pub fn send_verify_email(address: &str, user_id: &str, token: &str, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "<html>
+         <p>Please verify the email address of your new Bitwarden account.<br><br>
+         <a href=\"{}/#/verify-email/?userId={}&token={}\">Click here to verify your email</a></p>
+         <p>Your account can't be used until its email is verified. If you did not create this account, you can safely ignore this email.</p>
+         </html>",
+        CONFIG.domain, user_id, token
+    );
+
+    queue_email(address, "Verify Your Email", body, true, conn)
+}
+
+pub fn send_new_device_logged_in(address: &str, ip: &str, dt: &NaiveDateTime, device: &str, conn: &DbConn) -> Result<(), String> {
+    let datetime = dt.format("%A, %B %_d, %Y %H:%M UTC").to_string();
+
+    let body = format!(
+        "Your account was just logged into from a new device or location.\n\n\
+         Date: {}\n\
+         IP Address: {}\n\
+         Device: {}\n\n\
+         If this was not you, change your master password and deauthorize all sessions as soon as possible.\n",
+        datetime, ip, device
+    );
+
+    queue_email(address, "New Device Logged In From Your Account", body, false, conn)
+}
+
+pub fn send_account_change(address: &str, change: &str, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "The following change was made to your account:\n\n\
+         {}\n\n\
+         If you did not make this change, change your master password and deauthorize all sessions as soon as possible.\n",
+        change
+    );
+
+    queue_email(address, "Your Account Has Been Changed", body, false, conn)
+}
+
+pub fn send_twofactor_recovered(address: &str, ip: &str, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "Two-step login was disabled on your account using the recovery code, from IP address {}.\n\n\
+         All your two-step login providers have been removed.\n\
+         If you did not do this, change your master password as soon as possible.\n",
+        ip
+    );
+
+    queue_email(address, "Two-step Login Recovered", body, false, conn)
+}
+
+pub fn send_emergency_access_invite(address: &str, emer_id: &str, token: &str, grantor_name: &str, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "<html>
+         <p><b>{}</b> has invited you to become an emergency contact.<br><br>
+         <a href=\"{}/#/accept-emergency/?id={}&name={}&email={}&token={}\">Click here to accept</a></p>
+         <p>If you do not wish to become an emergency contact, you can safely ignore this email.</p>
+         </html>",
+        grantor_name,
+        CONFIG.domain,
+        percent_encode(emer_id),
+        percent_encode(grantor_name),
+        percent_encode(address),
+        percent_encode(token)
+    );
+
+    queue_email(address, "Emergency Access Contact Invited", body, true, conn)
+}
+
+pub fn send_emergency_access_invite_accepted(address: &str, grantee_email: &str, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "{} has accepted your invitation to become an emergency contact.\n\n\
+         Confirm them from the Emergency Access page of your vault to give them access.\n",
+        grantee_email
+    );
+
+    queue_email(address, "Accepted Emergency Access", body, false, conn)
+}
+
+pub fn send_emergency_access_invite_confirmed(address: &str, grantor_name: &str, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "You have been confirmed as an emergency contact for {}.\n\n\
+         You can now request emergency access from the Emergency Access page of your vault.\n",
+        grantor_name
+    );
+
+    queue_email(address, "Emergency Access Contact Confirmed", body, false, conn)
+}
+
+pub fn send_emergency_access_recovery_initiated(address: &str, grantee_name: &str, access_type: &str, wait_time_days: i32, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "{} has requested {} emergency access to your account.\n\n\
+         Access will be granted automatically in {} day(s), unless you reject the request \
+         from the Emergency Access page of your vault.\n",
+        grantee_name, access_type, wait_time_days
+    );
+
+    queue_email(address, "Emergency Access Initiated", body, false, conn)
+}
+
+pub fn send_emergency_access_recovery_reminder(address: &str, grantee_name: &str, access_type: &str, days_left: i64, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "{} is still waiting for {} emergency access to your account.\n\n\
+         Access will be granted automatically in {} day(s), unless you reject the request \
+         from the Emergency Access page of your vault.\n",
+        grantee_name, access_type, days_left
+    );
+
+    queue_email(address, "Emergency Access Pending", body, false, conn)
+}
+
+pub fn send_emergency_access_recovery_approved(address: &str, grantor_name: &str, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "Your request for emergency access to the account of {} has been approved.\n\n\
+         You can now use it from the Emergency Access page of your vault.\n",
+        grantor_name
+    );
+
+    queue_email(address, "Emergency Access Approved", body, false, conn)
+}
+
+pub fn send_emergency_access_recovery_rejected(address: &str, grantor_name: &str, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "Your request for emergency access to the account of {} has been rejected.\n",
+        grantor_name
+    );
+
+    queue_email(address, "Emergency Access Rejected", body, false, conn)
+}
+
+pub fn send_emergency_access_recovery_timed_out(address: &str, grantee_name: &str, access_type: &str, conn: &DbConn) -> Result<(), String> {
+    let body = format!(
+        "The wait time for the emergency access request of {} has passed, \
+         and they have been granted {} access to your account.\n\n\
+         If this was not expected, remove them from the Emergency Access page of your vault \
+         and change your master password as soon as possible.\n",
+        grantee_name, access_type
+    );
+
+    queue_email(address, "Emergency Access Granted", body, false, conn)
+}
+
+fn queue_email(address: &str, subject: &str, body: String, html: bool, conn: &DbConn) -> Result<(), String> {
+    QueuedMail::new(address.to_string(), subject.to_string(), body, html)
+        .save(conn)
+        .map_err(|e| e.to_string())
+}
+
+/// Starts the background worker that delivers the queued emails, retrying failed ones later
+/// The worker always runs, as the mail service can be enabled later by reloading the config
+pub fn start_mail_queue(pool: Pool) {
+    thread::Builder::new()
+        .name("mail-queue".to_string())
+        .spawn(move || loop {
+            match pool.get() {
+                Ok(conn) => process_queue(&DbConn(conn)),
+                Err(e) => error!("Mail queue can't get a database connection: {}", e),
+            }
+
+            thread::sleep(Duration::from_secs(QUEUE_INTERVAL_SECONDS));
+        })
+        .expect("Error starting mail queue worker");
+}
+
+fn process_queue(conn: &DbConn) {
+    let config = match CONFIG.mail() {
+        Some(config) => config,
+        None => return,
+    };
+
+    let pending = match QueuedMail::find_pending(QUEUE_BATCH_SIZE, conn) {
+        Ok(pending) => pending,
+        Err(e) => {
+            // Usually a locked database, the next run tries again
+            error!("Error loading the queued mails: {:#?}", e);
+            return;
+        }
+    };
+    if pending.is_empty() {
+        return;
+    }
+
+    // The SMTP connection is shared by all the emails in this batch
+    let mut transport: Option<SmtpTransport> = None;
+
+    for mut mail in pending {
+        match deliver(&mail, &config, &mut transport) {
+            Ok(()) => {
+                if let Err(e) = mail.delete(conn) {
+                    error!("Error removing delivered mail from the queue: {:#?}", e);
+                }
+            }
+            Err(e) => {
+                mail.reschedule(e);
+
+                if mail.attempts >= QueuedMail::MAX_ATTEMPTS {
+                    error!(
+                        "Giving up on mail to {} after {} attempts: {}",
+                        mail.recipient,
+                        mail.attempts,
+                        mail.last_error.as_ref().map(String::as_str).unwrap_or_default()
+                    );
+                    if let Err(e) = mail.delete(conn) {
+                        error!("Error removing failed mail from the queue: {:#?}", e);
+                    }
+                } else {
+                    warn!("Error sending mail to {}, will retry: {:?}", mail.recipient, mail.last_error);
+                    if let Err(e) = mail.save(conn) {
+                        error!("Error rescheduling mail: {:#?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(mut transport) = transport {
+        transport.close();
+    }
+}
+
+fn deliver(mail: &QueuedMail, config: &MailConfig, transport: &mut Option<SmtpTransport>) -> Result<(), String> {
+    let email = build_email(mail, config)?;
+
+    match config.transport {
+        MailTransport::Smtp => {
+            if transport.is_none() {
+                *transport = Some(mailer(config)?);
+            }
+
+            let result = transport
+                .as_mut()
+                .unwrap()
+                .send(email)
+                .map_err(|e| e.to_string());
+
+            // Don't reuse a connection that failed, the next email will open a new one
+            if result.is_err() {
+                if let Some(mut transport) = transport.take() {
+                    transport.close();
+                }
+            }
+
+            result.and(Ok(()))
+        }
+        MailTransport::File(ref folder) => {
+            fs::create_dir_all(folder).map_err(|e| e.to_string())?;
+
+            let path = Path::new(folder).join(format!("{}.eml", mail.uuid));
+            let message = email.message_to_string().map_err(|e| e.to_string())?;
+
+            fs::write(path, message).map_err(|e| e.to_string())
+        }
+        MailTransport::Stdout => {
+            let message = email.message_to_string().map_err(|e| e.to_string())?;
+            println!("{}", message);
+            Ok(())
+        }
+    }
+}
+
+fn build_email(mail: &QueuedMail, config: &MailConfig) -> Result<SendableEmail, String> {
+    let mut builder = EmailBuilder::new()
+        .to(mail.recipient.as_str())
         .from((config.smtp_from.clone(), "Bitwarden-rs"))
-        .subject(subject)
-        .header(("Content-Type", "text/html"))
-        .body(body)
-        .build()
-        .map_err(|e| e.to_string())?;
+        .subject(mail.subject.as_str());
+
+    if mail.html {
+        builder = builder.header(("Content-Type", "text/html"));
+    }
 
-    mailer(config)
-        .send(email.into())
+    builder
+        .body(mail.body.as_str())
+        .build()
+        .map(Into::into)
         .map_err(|e| e.to_string())
-        .and(Ok(()))
-}
\ No newline at end of file
+}
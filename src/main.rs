@@ -11,7 +11,7 @@
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate num_derive;
 
-use std::{path::Path, process::{exit, Command}};
+use std::{path::Path, process::exit};
 use rocket::Rocket;
 
 #[macro_use]
@@ -22,8 +22,20 @@ mod db;
 mod crypto;
 mod auth;
 mod mail;
+mod config;
+mod sso;
+
+use crate::config::Config;
 
 fn init_rocket() -> Rocket {
+    let pool = db::init_pool();
+    let ws_users = api::start_notification_server();
+    mail::start_mail_queue(pool.clone());
+    api::start_invitation_sweep(pool.clone());
+    api::start_trash_purge(pool.clone(), ws_users.clone());
+    api::start_revision_purge(pool.clone());
+    api::start_send_purge(pool.clone());
+    api::start_emergency_access_job(pool.clone());
 
     // TODO: TO HIDE MOUNTING LOG, call ignite, set logging to disabled, call all the mounts, and then enable it again
     
@@ -33,8 +45,10 @@ fn init_rocket() -> Rocket {
         .mount("/identity", api::identity_routes())
         .mount("/icons", api::icons_routes())
         .mount("/notifications", api::notifications_routes())
-        .manage(db::init_pool())
-        .manage(api::start_notification_server())
+        .mount("/admin", api::admin_routes())
+        .register(api::catchers())
+        .manage(pool)
+        .manage(ws_users)
 }
 
 // Embed the migrations from the migrations folder into the application
@@ -54,6 +68,19 @@ mod migrations {
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => (),
+        ["config", "check"] => config_check(),
+        ["keys", "list"] => keys_list(),
+        ["keys", "rotate"] => keys_rotate(),
+        ["keys", "remove", kid] => keys_remove(kid),
+        _ => {
+            eprintln!("Usage: bitwarden_rs [config check | keys list | keys rotate | keys remove <kid>]");
+            exit(2);
+        }
+    }
+
     if CONFIG.extended_logging {
         init_logging().ok();
     }
@@ -61,6 +88,7 @@ fn main() {
     check_db();
     check_rsa_keys();
     check_web_vault();
+    check_mail();
     migrations::run_migrations();
 
     init_rocket().launch();
@@ -132,47 +160,9 @@ fn check_db() {
 }
 
 fn check_rsa_keys() {
-    // If the RSA keys don't exist, try to create them
-    if !util::file_exists(&CONFIG.private_rsa_key)
-        || !util::file_exists(&CONFIG.public_rsa_key) {
-        info!("JWT keys don't exist, checking if OpenSSL is available...");
-
-        Command::new("openssl")
-            .arg("version")
-            .output().unwrap_or_else(|_| {
-            info!("Can't create keys because OpenSSL is not available, make sure it's installed and available on the PATH");
-            exit(1);
-        });
-
-        info!("OpenSSL detected, creating keys...");
-
-        let mut success = Command::new("openssl").arg("genrsa")
-            .arg("-out").arg(&CONFIG.private_rsa_key_pem)
-            .output().expect("Failed to create private pem file")
-            .status.success();
-
-        success &= Command::new("openssl").arg("rsa")
-            .arg("-in").arg(&CONFIG.private_rsa_key_pem)
-            .arg("-outform").arg("DER")
-            .arg("-out").arg(&CONFIG.private_rsa_key)
-            .output().expect("Failed to create private der file")
-            .status.success();
-
-        success &= Command::new("openssl").arg("rsa")
-            .arg("-in").arg(&CONFIG.private_rsa_key)
-            .arg("-inform").arg("DER")
-            .arg("-RSAPublicKey_out")
-            .arg("-outform").arg("DER")
-            .arg("-out").arg(&CONFIG.public_rsa_key)
-            .output().expect("Failed to create public der file")
-            .status.success();
-
-        if success {
-            info!("Keys created correctly.");
-        } else {
-            error!("Error creating keys, exiting...");
-            exit(1);
-        }
+    if let Err(e) = auth::init_keys() {
+        error!("Error creating keys: {}", e);
+        exit(1);
     }
 }
 
@@ -189,158 +179,101 @@ fn check_web_vault() {
     }
 }
 
-lazy_static! {
-    // Load the config from .env or from environment variables
-    static ref CONFIG: Config = Config::load();
-}
-
-#[derive(Debug)]
-pub struct MailConfig {
-    smtp_host: String,
-    smtp_port: u16,
-    smtp_ssl: bool,
-    smtp_from: String,
-    smtp_username: Option<String>,
-    smtp_password: Option<String>,
-}
+/// Validates the configuration and exits, without starting the server
+fn config_check() {
+    let errors = match Config::load() {
+        Ok(config) => match config.mail() {
+            Some(mail_config) => mail::check_config(&mail_config)
+                .err()
+                .map(|e| vec![format!("Invalid SMTP configuration: {}", e)])
+                .unwrap_or_default(),
+            None => Vec::new(),
+        },
+        Err(errors) => errors,
+    };
 
-impl MailConfig {
-    fn load() -> Option<Self> {
-        use crate::util::{get_env, get_env_or};
+    if errors.is_empty() {
+        println!("The configuration is valid");
+        exit(0);
+    }
 
-        // When SMTP_HOST is absent, we assume the user does not want to enable it.
-        let smtp_host = match get_env("SMTP_HOST") {
-            Some(host) => host,
-            None => return None,
-        };
+    for e in errors {
+        eprintln!("Config error: {}", e);
+    }
+    exit(1);
+}
 
-        let smtp_from = get_env("SMTP_FROM").unwrap_or_else(|| {
-            error!("Please specify SMTP_FROM to enable SMTP support.");
-            exit(1);
-        });
-
-        let smtp_ssl = get_env_or("SMTP_SSL", true);
-        let smtp_port = get_env("SMTP_PORT").unwrap_or_else(|| 
-            if smtp_ssl { 
-                587u16 
-            } else { 
-                25u16 
-            }
-        );
-
-        let smtp_username = get_env("SMTP_USERNAME");
-        let smtp_password = get_env("SMTP_PASSWORD").or_else(|| {
-            if smtp_username.as_ref().is_some() {
-                error!("SMTP_PASSWORD is mandatory when specifying SMTP_USERNAME.");
-                exit(1);
-            } else {
-                None
+fn keys_list() {
+    match auth::list_key_ids() {
+        Ok(kids) => {
+            for (i, kid) in kids.iter().enumerate() {
+                let status = if i == kids.len() - 1 { "active" } else { "retired" };
+                println!("{} ({})", kid, status);
             }
-        });
-
-        Some(MailConfig {
-            smtp_host,
-            smtp_port,
-            smtp_ssl,
-            smtp_from,
-            smtp_username,
-            smtp_password,
-        })
+            exit(0);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct Config {
-    database_url: String,
-    icon_cache_folder: String,
-    attachments_folder: String,
-
-    icon_cache_ttl: u64,
-    icon_cache_negttl: u64,
-
-    private_rsa_key: String,
-    private_rsa_key_pem: String,
-    public_rsa_key: String,
-
-    web_vault_folder: String,
-    web_vault_enabled: bool,
-
-    websocket_enabled: bool,
-    websocket_url: String,
-
-    extended_logging: bool,
-    log_file: Option<String>,
-
-    local_icon_extractor: bool,
-    signups_allowed: bool,
-    invitations_allowed: bool,
-    server_admin_email: Option<String>,
-    password_iterations: i32,
-    show_password_hint: bool,
-
-    domain: String,
-    domain_set: bool,
-
-    yubico_cred_set: bool,
-    yubico_client_id: String,
-    yubico_secret_key: String,
-    yubico_server: Option<String>,
+/// Creates a new signing key, the previous keys are kept so the tokens they signed stay valid
+fn keys_rotate() {
+    match auth::init_keys() {
+        Ok(true) => {
+            println!("Created the first key, there was nothing to rotate");
+            exit(0);
+        }
+        Ok(false) => (),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
 
-    mail: Option<MailConfig>,
+    match auth::rotate_keys() {
+        Ok(kid) => {
+            println!("Created the key {}, restart the server to start using it", kid);
+            exit(0);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
 }
 
-impl Config {
-    fn load() -> Self {
-        use crate::util::{get_env, get_env_or};
-        dotenv::dotenv().ok();
-
-        let df = get_env_or("DATA_FOLDER", "data".to_string());
-        let key = get_env_or("RSA_KEY_FILENAME", format!("{}/{}", &df, "rsa_key"));
-
-        let domain = get_env("DOMAIN");
-
-        let yubico_client_id = get_env("YUBICO_CLIENT_ID");
-        let yubico_secret_key = get_env("YUBICO_SECRET_KEY");
-
-        Config {
-            database_url: get_env_or("DATABASE_URL", format!("{}/{}", &df, "db.sqlite3")),
-            icon_cache_folder: get_env_or("ICON_CACHE_FOLDER", format!("{}/{}", &df, "icon_cache")),
-            attachments_folder: get_env_or("ATTACHMENTS_FOLDER", format!("{}/{}", &df, "attachments")),
-
-            // icon_cache_ttl defaults to 30 days (30 * 24 * 60 * 60 seconds)
-            icon_cache_ttl: get_env_or("ICON_CACHE_TTL", 2592000u64),
-            // icon_cache_negttl defaults to 3 days (3 * 24 * 60 * 60 seconds)
-            icon_cache_negttl: get_env_or("ICON_CACHE_NEGTTL", 259200u64),
-
-            private_rsa_key: format!("{}.der", &key),
-            private_rsa_key_pem: format!("{}.pem", &key),
-            public_rsa_key: format!("{}.pub.der", &key),
-
-            web_vault_folder: get_env_or("WEB_VAULT_FOLDER", "web-vault/".into()),
-            web_vault_enabled: get_env_or("WEB_VAULT_ENABLED", true),
-
-            websocket_enabled: get_env_or("WEBSOCKET_ENABLED", false),
-            websocket_url: format!("{}:{}", get_env_or("WEBSOCKET_ADDRESS", "0.0.0.0".to_string()), get_env_or("WEBSOCKET_PORT", 3012)),
-            
-            extended_logging: get_env_or("EXTENDED_LOGGING", true),
-            log_file: get_env("LOG_FILE"),
-
-            local_icon_extractor: get_env_or("LOCAL_ICON_EXTRACTOR", false),
-            signups_allowed: get_env_or("SIGNUPS_ALLOWED", true),
-            server_admin_email: get_env("SERVER_ADMIN_EMAIL"),
-            invitations_allowed: get_env_or("INVITATIONS_ALLOWED", true),
-            password_iterations: get_env_or("PASSWORD_ITERATIONS", 100_000),
-            show_password_hint: get_env_or("SHOW_PASSWORD_HINT", true),
-
-            domain_set: domain.is_some(),
-            domain: domain.unwrap_or("http://localhost".into()),
-
-            yubico_cred_set: yubico_client_id.is_some() && yubico_secret_key.is_some(),
-            yubico_client_id: yubico_client_id.unwrap_or("00000".into()),
-            yubico_secret_key: yubico_secret_key.unwrap_or("AAAAAAA".into()),
-            yubico_server: get_env("YUBICO_SERVER"),
+fn keys_remove(kid: &str) {
+    match auth::remove_key(kid) {
+        Ok(()) => {
+            println!("Removed the key {}, restart the server to stop accepting its tokens", kid);
+            exit(0);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
 
-            mail: MailConfig::load(),
+fn check_mail() {
+    if let Some(mail_config) = CONFIG.mail() {
+        if let Err(e) = mail::check_config(&mail_config) {
+            error!("Invalid SMTP configuration: {}", e);
+            exit(1);
         }
     }
 }
+
+lazy_static! {
+    // Load the config from the config file, .env or from environment variables
+    static ref CONFIG: Config = Config::load().unwrap_or_else(|errors| {
+        // The logger is not initialized yet, so the errors are printed directly
+        for e in errors {
+            eprintln!("Config error: {}", e);
+        }
+        exit(1);
+    });
+}
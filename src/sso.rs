@@ -0,0 +1,245 @@
+//
+// OpenID Connect client, used to authenticate users against an external identity provider
+//
+use std::time::Duration;
+
+use chrono::Utc;
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{self, Algorithm, Validation};
+use reqwest::{self, Url};
+use ring::digest;
+
+use crate::config::SsoConfig;
+use crate::crypto;
+use crate::db::models::SsoAuth;
+use crate::CONFIG;
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct JsonWebKeySet {
+    keys: Vec<JsonWebKey>,
+}
+
+#[derive(Deserialize)]
+struct JsonWebKey {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+/// The address the identity provider sends the user back to, which must be registered with it
+pub fn redirect_uri() -> String {
+    format!("{}/identity/sso/callback", CONFIG.domain)
+}
+
+/// Computes the S256 PKCE challenge of a code verifier
+pub fn pkce_challenge(verifier: &str) -> String {
+    BASE64URL_NOPAD.encode(digest::digest(&digest::SHA256, verifier.as_bytes()).as_ref())
+}
+
+fn client() -> Result<reqwest::Client, String> {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+fn discover(config: &SsoConfig) -> Result<Discovery, String> {
+    let url = format!("{}/.well-known/openid-configuration", config.authority);
+
+    let discovery: Discovery = client()?
+        .get(&url)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|mut res| res.json())
+        .map_err(|e| format!("Error loading the OpenID configuration from {}: {}", url, e))?;
+
+    if discovery.issuer.trim_end_matches('/') != config.authority {
+        return Err(format!("The issuer '{}' doesn't match SSO_AUTHORITY", discovery.issuer));
+    }
+
+    Ok(discovery)
+}
+
+/// Builds the address of the identity provider's login page for this flow
+pub fn authorize_url(config: &SsoConfig, auth: &SsoAuth) -> Result<String, String> {
+    let discovery = discover(config)?;
+
+    let mut url = Url::parse(&discovery.authorization_endpoint).map_err(|e| e.to_string())?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", &redirect_uri())
+        .append_pair("scope", &config.scopes)
+        .append_pair("state", &auth.state)
+        .append_pair("nonce", &auth.nonce)
+        .append_pair("code_challenge", &pkce_challenge(&auth.verifier))
+        .append_pair("code_challenge_method", "S256");
+
+    Ok(url.into_string())
+}
+
+/// Exchanges the authorization code received from the identity provider and returns the email of the user
+pub fn verified_email(config: &SsoConfig, auth: &SsoAuth, code: &str) -> Result<String, String> {
+    let discovery = discover(config)?;
+    let client = client()?;
+
+    let redirect_uri = redirect_uri();
+    let mut params = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("client_id", config.client_id.as_str()),
+        ("code_verifier", auth.verifier.as_str()),
+    ];
+    if let Some(ref secret) = config.client_secret {
+        params.push(("client_secret", secret.as_str()));
+    }
+
+    let tokens: TokenResponse = client
+        .post(&discovery.token_endpoint)
+        .form(&params)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|mut res| res.json())
+        .map_err(|e| format!("Error exchanging the authorization code: {}", e))?;
+
+    let claims = verify_id_token(&tokens.id_token, &discovery, &client)?;
+
+    if claims.iss.trim_end_matches('/') != discovery.issuer.trim_end_matches('/') {
+        return Err("Invalid ID token issuer".into());
+    }
+
+    let audience_valid = match claims.aud {
+        Audience::Single(ref aud) => aud == &config.client_id,
+        Audience::Multiple(ref auds) => auds.contains(&config.client_id),
+    };
+    if !audience_valid {
+        return Err("Invalid ID token audience".into());
+    }
+
+    if claims.exp < Utc::now().timestamp() {
+        return Err("The ID token has expired".into());
+    }
+
+    if claims.nonce.as_ref() != Some(&auth.nonce) {
+        return Err("Invalid ID token nonce".into());
+    }
+
+    // Providers don't always include the email in the ID token, but it's available from the userinfo endpoint
+    let (email, email_verified) = match (claims.email, discovery.userinfo_endpoint) {
+        (Some(email), _) => (email, claims.email_verified),
+        (None, Some(userinfo_endpoint)) => {
+            let userinfo: UserInfo = client
+                .get(&userinfo_endpoint)
+                .bearer_auth(&tokens.access_token)
+                .send()
+                .and_then(|res| res.error_for_status())
+                .and_then(|mut res| res.json())
+                .map_err(|e| format!("Error loading the user info: {}", e))?;
+
+            match userinfo.email {
+                Some(email) => (email, userinfo.email_verified),
+                None => return Err("The identity provider didn't return an email".into()),
+            }
+        }
+        (None, None) => return Err("The identity provider didn't return an email".into()),
+    };
+
+    // Anyone able to set an unverified email at the provider could take over the matching account,
+    // so a missing claim is only accepted when the provider is trusted to never return one
+    match email_verified {
+        Some(true) => (),
+        None if config.allow_unverified_email => (),
+        _ => return Err(format!("The email {} is not verified by the identity provider", email)),
+    }
+
+    Ok(email)
+}
+
+/// Checks the signature of the ID token with the keys published by the identity provider, the claims are checked by the caller
+fn verify_id_token(token: &str, discovery: &Discovery, client: &reqwest::Client) -> Result<IdTokenClaims, String> {
+    let header = jsonwebtoken::decode_header(token).map_err(|e| format!("Invalid ID token: {}", e))?;
+
+    match header.alg {
+        Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => (),
+        alg => return Err(format!("Unsupported ID token algorithm {:?}", alg)),
+    }
+
+    let jwks: JsonWebKeySet = client
+        .get(&discovery.jwks_uri)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|mut res| res.json())
+        .map_err(|e| format!("Error loading the identity provider keys: {}", e))?;
+
+    let validation = Validation {
+        leeway: 30, // 30 seconds
+        validate_exp: false, // Checked with the other claims
+        validate_iat: false,
+        validate_nbf: false,
+        aud: None,
+        iss: None,
+        sub: None,
+        algorithms: vec![header.alg],
+    };
+
+    let keys = jwks.keys.iter().filter(|key| {
+        key.kty == "RSA" && (header.kid.is_none() || key.kid == header.kid)
+    });
+
+    for key in keys {
+        let der = match (&key.n, &key.e) {
+            (Some(n), Some(e)) => {
+                let n = BASE64URL_NOPAD.decode(n.trim_end_matches('=').as_bytes()).map_err(|e| e.to_string())?;
+                let e = BASE64URL_NOPAD.decode(e.trim_end_matches('=').as_bytes()).map_err(|e| e.to_string())?;
+                crypto::rsa_public_der(&n, &e)?
+            }
+            _ => continue,
+        };
+
+        if let Ok(decoded) = jsonwebtoken::decode::<IdTokenClaims>(token, &der, &validation) {
+            return Ok(decoded.claims);
+        }
+    }
+
+    Err("Invalid ID token signature".into())
+}
@@ -122,6 +122,17 @@ pub fn upcase_first(s: &str) -> String {
     }
 }
 
+/// Encodes a value for a query string, only the unreserved characters of RFC 3986 are kept
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
 pub fn try_parse_string<S, T, U>(string: impl Try<Ok = S, Error=U>) -> Option<T> where S: AsRef<str>, T: FromStr {
     if let Ok(Ok(value)) = string.into_result().map(|s| s.as_ref().parse::<T>()) {
         Some(value)
@@ -130,29 +141,6 @@ pub fn try_parse_string<S, T, U>(string: impl Try<Ok = S, Error=U>) -> Option<T>
     }
 }
 
-pub fn try_parse_string_or<S, T, U>(string: impl Try<Ok = S, Error=U>, default: T) -> T where S: AsRef<str>, T: FromStr {
-    if let Ok(Ok(value)) = string.into_result().map(|s| s.as_ref().parse::<T>()) {
-        value
-    } else {
-        default
-    }
-}
-
-
-///
-/// Env methods
-/// 
-
-use std::env;
-
-pub fn get_env<V>(key: &str) -> Option<V> where V: FromStr {
-    try_parse_string(env::var(key))
-}
-
-pub fn get_env_or<V>(key: &str, default: V) -> V where V: FromStr {
-    try_parse_string_or(env::var(key), default)
-}
-
 ///
 /// Date util methods
 ///
@@ -282,3 +270,88 @@ where
         }
     }
 }
+
+//
+// IP network methods
+//
+
+use std::net::IpAddr;
+
+/// An IP network in CIDR notation, like 10.0.0.0/8 or fd00::/8. A single address is a network of one address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (network, ip, bits) = match (self.addr, normalize_ip(*ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u128::from(u32::from(net)), u128::from(u32::from(ip)), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+
+        let shift = bits - u32::from(self.prefix);
+        shift >= bits || (network >> shift) == (ip >> shift)
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| format!("Invalid IP network '{}'", s))?;
+        let addr = normalize_ip(addr);
+
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().map_err(|_| format!("Invalid IP network '{}'", s))?,
+            None => max_prefix,
+        };
+
+        if prefix > max_prefix {
+            return Err(format!("Invalid prefix length in IP network '{}'", s));
+        }
+
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses (::ffff:10.0.0.1) to IPv4, so they match IPv4 networks
+pub fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(v6.to_ipv4().unwrap()),
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+//
+// Background job methods
+//
+
+use crate::db::{DbConn, Pool};
+
+/// Runs the job on its own thread every hour, with a new database connection each time
+pub fn spawn_hourly_job<F>(name: &'static str, pool: Pool, job: F)
+where
+    F: Fn(&DbConn) + Send + 'static,
+{
+    use std::{thread, time::Duration};
+
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || loop {
+            match pool.get() {
+                Ok(conn) => job(&DbConn(conn)),
+                Err(e) => error!("The {} job can't get a database connection: {}", name, e),
+            }
+
+            thread::sleep(Duration::from_secs(60 * 60));
+        })
+        .unwrap_or_else(|e| panic!("Error starting the {} job: {}", name, e));
+}
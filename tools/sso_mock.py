@@ -0,0 +1,316 @@
+#!/usr/bin/env python3
+"""
+Mock OpenID Connect provider to test single sign-on locally, using only the Python standard library.
+
+    python3 tools/sso_mock.py serve --port 8090 --email user@example.com
+    python3 tools/sso_mock.py flow --server http://localhost:8000
+
+`serve` publishes a discovery document, an authorization endpoint that logs in as --email
+without asking anything, a token endpoint returning RS256 ID tokens, a userinfo endpoint and
+the key set used to sign the tokens. Start bitwarden_rs with SSO_ENABLED=true,
+SSO_AUTHORITY=http://localhost:8090, SSO_CLIENT_ID=bitwarden and a DOMAIN that points to
+the server, as the provider redirects the browser to DOMAIN/identity/sso/callback.
+
+`flow` plays the client: it opens /identity/sso/authorize, follows the redirects through the
+mock provider and /identity/sso/callback, and redeems the code at /identity/connect/token.
+It exits with an error if any step fails.
+"""
+import argparse
+import base64
+import hashlib
+import json
+import secrets
+import sys
+import time
+import urllib.error
+import urllib.parse
+import urllib.request
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+from socketserver import ThreadingMixIn
+
+#
+# RSA key and RS256 signatures
+#
+
+SMALL_PRIMES = [p for p in range(3, 2000) if all(p % d for d in range(2, int(p ** 0.5) + 1))]
+SHA256_DIGEST_INFO = bytes.fromhex("3031300d060960864801650304020105000420")
+
+
+def is_probable_prime(n, rounds=40):
+    if any(n % p == 0 for p in SMALL_PRIMES):
+        return False
+    d, r = n - 1, 0
+    while d % 2 == 0:
+        d, r = d // 2, r + 1
+    for _ in range(rounds):
+        x = pow(secrets.randbelow(n - 3) + 2, d, n)
+        if x in (1, n - 1):
+            continue
+        for _ in range(r - 1):
+            x = pow(x, 2, n)
+            if x == n - 1:
+                break
+        else:
+            return False
+    return True
+
+
+def random_prime(bits):
+    while True:
+        candidate = secrets.randbits(bits) | (1 << (bits - 1)) | (1 << (bits - 2)) | 1
+        if is_probable_prime(candidate):
+            return candidate
+
+
+def generate_key(bits=2048):
+    e = 65537
+    while True:
+        p, q = random_prime(bits // 2), random_prime(bits // 2)
+        phi = (p - 1) * (q - 1)
+        if p != q and phi % e != 0:
+            n = p * q
+            return {"n": n, "e": e, "d": pow(e, -1, phi), "size": (n.bit_length() + 7) // 8}
+
+
+def b64url(data):
+    return base64.urlsafe_b64encode(data).rstrip(b"=").decode()
+
+
+def int_bytes(value):
+    return value.to_bytes((value.bit_length() + 7) // 8, "big")
+
+
+def sign_jwt(key, kid, claims):
+    header = {"alg": "RS256", "typ": "JWT", "kid": kid}
+    signing_input = b64url(json.dumps(header).encode()) + "." + b64url(json.dumps(claims).encode())
+
+    # EMSA-PKCS1-v1_5 encoding (RFC 8017, 9.2)
+    t = SHA256_DIGEST_INFO + hashlib.sha256(signing_input.encode()).digest()
+    em = b"\x00\x01" + b"\xff" * (key["size"] - len(t) - 3) + b"\x00" + t
+    signature = pow(int.from_bytes(em, "big"), key["d"], key["n"]).to_bytes(key["size"], "big")
+
+    return signing_input + "." + b64url(signature)
+
+
+def pkce_challenge(verifier):
+    return b64url(hashlib.sha256(verifier.encode()).digest())
+
+
+#
+# Provider
+#
+
+class ThreadingHTTPServer(ThreadingMixIn, HTTPServer):
+    daemon_threads = True
+
+
+def make_handler(args, key, kid):
+    codes = {}
+    access_tokens = {}
+
+    class Handler(BaseHTTPRequestHandler):
+        def send_json(self, status, body):
+            data = json.dumps(body).encode()
+            self.send_response(status)
+            self.send_header("Content-Type", "application/json")
+            self.send_header("Content-Length", str(len(data)))
+            self.end_headers()
+            self.wfile.write(data)
+
+        def send_error_json(self, error, description):
+            self.send_json(400, {"error": error, "error_description": description})
+
+        def do_GET(self):
+            url = urllib.parse.urlsplit(self.path)
+            query = dict(urllib.parse.parse_qsl(url.query))
+
+            if url.path == "/.well-known/openid-configuration":
+                self.send_json(200, {
+                    "issuer": args.issuer,
+                    "authorization_endpoint": args.issuer + "/authorize",
+                    "token_endpoint": args.issuer + "/token",
+                    "userinfo_endpoint": args.issuer + "/userinfo",
+                    "jwks_uri": args.issuer + "/jwks",
+                    "response_types_supported": ["code"],
+                    "subject_types_supported": ["public"],
+                    "id_token_signing_alg_values_supported": ["RS256"],
+                    "code_challenge_methods_supported": ["S256"],
+                })
+            elif url.path == "/jwks":
+                self.send_json(200, {"keys": [{
+                    "kty": "RSA", "use": "sig", "alg": "RS256", "kid": kid,
+                    "n": b64url(int_bytes(key["n"])), "e": b64url(int_bytes(key["e"])),
+                }]})
+            elif url.path == "/authorize":
+                if query.get("client_id") != args.client_id:
+                    return self.send_error_json("unauthorized_client", "Unknown client_id")
+                if query.get("code_challenge_method") != "S256" or "code_challenge" not in query:
+                    return self.send_error_json("invalid_request", "PKCE with S256 is required")
+
+                # No login page, the user is always --email
+                code = secrets.token_urlsafe(24)
+                codes[code] = query
+                location = query["redirect_uri"] + "?" + urllib.parse.urlencode({"code": code, "state": query.get("state", "")})
+
+                self.send_response(302)
+                self.send_header("Location", location)
+                self.end_headers()
+            elif url.path == "/userinfo":
+                token = self.headers.get("Authorization", "")[len("Bearer "):]
+                if token not in access_tokens:
+                    return self.send_json(401, {"error": "invalid_token"})
+                self.send_json(200, {"sub": args.subject, "email": args.email, "email_verified": True})
+            else:
+                self.send_json(404, {"error": "not_found"})
+
+        def do_POST(self):
+            if urllib.parse.urlsplit(self.path).path != "/token":
+                return self.send_json(404, {"error": "not_found"})
+
+            length = int(self.headers.get("Content-Length", 0))
+            form = dict(urllib.parse.parse_qsl(self.rfile.read(length).decode()))
+
+            request = codes.pop(form.get("code"), None)
+            if form.get("grant_type") != "authorization_code" or request is None:
+                return self.send_error_json("invalid_grant", "Unknown or already used code")
+            if form.get("client_id") != args.client_id or form.get("redirect_uri") != request["redirect_uri"]:
+                return self.send_error_json("invalid_grant", "client_id or redirect_uri don't match")
+            if args.client_secret and form.get("client_secret") != args.client_secret:
+                return self.send_error_json("invalid_client", "Invalid client_secret")
+            if pkce_challenge(form.get("code_verifier", "")) != request["code_challenge"]:
+                return self.send_error_json("invalid_grant", "Invalid code_verifier")
+
+            now = int(time.time())
+            claims = {
+                "iss": args.issuer, "sub": args.subject, "aud": args.client_id,
+                "iat": now, "exp": now + 300, "nonce": request.get("nonce"),
+            }
+            # Without it, the server has to load the email from the userinfo endpoint
+            if not args.userinfo_only:
+                claims.update({"email": args.email, "email_verified": True})
+
+            access_token = secrets.token_urlsafe(24)
+            access_tokens[access_token] = True
+            self.send_json(200, {
+                "access_token": access_token,
+                "token_type": "Bearer",
+                "expires_in": 300,
+                "id_token": sign_jwt(key, kid, claims),
+            })
+
+    return Handler
+
+
+def serve(args):
+    args.issuer = (args.issuer or "http://localhost:%d" % args.port).rstrip("/")
+
+    print("Generating the signing key...")
+    key = generate_key()
+    kid = secrets.token_hex(8)
+
+    server = ThreadingHTTPServer(("127.0.0.1", args.port), make_handler(args, key, kid))
+    print("Mock provider listening on %s, logging in as %s" % (args.issuer, args.email))
+    server.serve_forever()
+
+
+#
+# Client
+#
+
+class NoRedirect(urllib.request.HTTPRedirectHandler):
+    def redirect_request(self, req, fp, code, msg, headers, newurl):
+        return None
+
+
+OPENER = urllib.request.build_opener(NoRedirect)
+
+
+def redirect_location(url):
+    try:
+        response = OPENER.open(url)
+        fail("Expected a redirect from %s, got %d: %s" % (url, response.status, response.read().decode()))
+    except urllib.error.HTTPError as e:
+        if e.code not in (301, 302, 303, 307):
+            fail("Error from %s, %d: %s" % (url, e.code, e.read().decode()))
+        return e.headers["Location"]
+
+
+def fail(message):
+    print(message, file=sys.stderr)
+    sys.exit(1)
+
+
+def flow(args):
+    server = args.server.rstrip("/")
+    verifier = secrets.token_urlsafe(48)
+    state = secrets.token_urlsafe(12)
+
+    authorize = server + "/identity/sso/authorize?" + urllib.parse.urlencode({
+        "response_type": "code",
+        "redirect_uri": args.redirect_uri,
+        "state": state,
+        "code_challenge": pkce_challenge(verifier),
+        "code_challenge_method": "S256",
+    })
+
+    provider_url = redirect_location(authorize)
+    print("1. /identity/sso/authorize redirected to", provider_url)
+
+    callback_url = redirect_location(provider_url)
+    print("2. The provider redirected to", callback_url)
+
+    client_url = redirect_location(callback_url)
+    print("3. /identity/sso/callback redirected to", client_url)
+
+    query = dict(urllib.parse.parse_qsl(urllib.parse.urlsplit(client_url).query))
+    if query.get("state") != state or "code" not in query:
+        fail("The callback didn't return the code and the original state")
+
+    body = urllib.parse.urlencode({
+        "grant_type": "authorization_code",
+        "code": query["code"],
+        "code_verifier": verifier,
+        "redirect_uri": args.redirect_uri,
+        "device_identifier": str(uuid.uuid4()),
+        "device_name": "sso-mock",
+        "device_type": "8",
+    }).encode()
+
+    try:
+        response = OPENER.open(urllib.request.Request(server + "/identity/connect/token", data=body))
+    except urllib.error.HTTPError as e:
+        fail("4. /identity/connect/token failed, %d: %s" % (e.code, e.read().decode()))
+
+    print("4. /identity/connect/token logged in:")
+    print(json.dumps(json.loads(response.read().decode()), indent=2))
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Mock OpenID Connect provider to test single sign-on locally")
+    commands = parser.add_subparsers(dest="command")
+    commands.required = True
+
+    serve_parser = commands.add_parser("serve", help="run the mock identity provider")
+    serve_parser.add_argument("--port", type=int, default=8090)
+    serve_parser.add_argument("--issuer", help="defaults to http://localhost:PORT, must match SSO_AUTHORITY")
+    serve_parser.add_argument("--client-id", default="bitwarden")
+    serve_parser.add_argument("--client-secret", help="required from the server when set")
+    serve_parser.add_argument("--email", default="user@example.com", help="the email of the account that logs in")
+    serve_parser.add_argument("--subject", default="mock-user")
+    serve_parser.add_argument("--userinfo-only", action="store_true",
+                              help="leave the email out of the ID token, so it's loaded from the userinfo endpoint")
+    serve_parser.set_defaults(run=serve)
+
+    flow_parser = commands.add_parser("flow", help="run a single sign-on login against bitwarden_rs")
+    flow_parser.add_argument("--server", default="http://localhost:8000", help="the address of bitwarden_rs")
+    flow_parser.add_argument("--redirect-uri", default="http://localhost/sso-connector.html",
+                             help="the client redirect URI, it's never opened")
+    flow_parser.set_defaults(run=flow)
+
+    args = parser.parse_args()
+    args.run(args)
+
+
+if __name__ == "__main__":
+    main()